@@ -0,0 +1,107 @@
+/*
+Throughput of the decode loop on a small random llama, run with `cargo bench --bench decode`:
+- concat: no kv cache, each step concatenates the new embedding and runs the whole context,
+  what the generation loop used to do with --no-kv-cache;
+- buffer: no kv cache, the embeddings are written into a buffer allocated once;
+- decode_step: a prefill, then one token per step against the kv cache.
+*/
+use std::time::Instant;
+
+use candle_core::{DType, Device, Result, Tensor, D};
+use candle_llava::cache::Cache;
+use candle_llava::language_model::LanguageModel;
+use candle_llava::llama::Llama;
+use candle_llava::quantized::Quantization;
+use candle_nn::VarBuilder;
+use candle_transformers::models::llama::LlamaConfig;
+
+const PROMPT_LEN: usize = 128;
+const NEW_TOKENS: usize = 64;
+const MAX_SEQ_LEN: usize = 512;
+
+fn model(device: &Device) -> Result<Llama> {
+    let config: LlamaConfig = serde_json::from_str(
+        r#"{"hidden_size": 512, "intermediate_size": 1376, "vocab_size": 1000,
+            "num_hidden_layers": 4, "num_attention_heads": 8, "num_key_value_heads": 8,
+            "rms_norm_eps": 1e-5, "max_position_embeddings": 512}"#,
+    )
+    .map_err(candle_core::Error::wrap)?;
+    let vb = VarBuilder::zeros(DType::F32, device);
+    Llama::load(
+        vb,
+        &config.into_config(false),
+        MAX_SEQ_LEN,
+        &Quantization::default(),
+    )
+}
+
+fn prompt(model: &Llama, device: &Device) -> Result<Tensor> {
+    let ids = (0..PROMPT_LEN as u32).map(|i| i % 1000).collect::<Vec<_>>();
+    model.embed(&Tensor::new(ids, device)?)?.unsqueeze(0)
+}
+
+fn argmax(logits: &Tensor) -> Result<u32> {
+    logits.squeeze(0)?.argmax(D::Minus1)?.to_scalar::<u32>()
+}
+
+fn embed(model: &Llama, token: u32, device: &Device) -> Result<Tensor> {
+    model.embed(&Tensor::new(&[token], device)?)?.unsqueeze(0)
+}
+
+fn concat(model: &Llama, cache: &mut Cache, device: &Device) -> Result<()> {
+    let mut input_embeds = prompt(model, device)?;
+    for _ in 0..NEW_TOKENS {
+        let token = argmax(&model.forward_input_embed(&input_embeds, 0, cache)?)?;
+        input_embeds = Tensor::cat(&[input_embeds, embed(model, token, device)?], 1)?;
+    }
+    Ok(())
+}
+
+fn buffer(model: &Llama, cache: &mut Cache, device: &Device) -> Result<()> {
+    let input_embeds = prompt(model, device)?;
+    let (b_sz, mut len, hidden_size) = input_embeds.dims3()?;
+    let buffer = Tensor::zeros((b_sz, len + NEW_TOKENS, hidden_size), DType::F32, device)?;
+    buffer.slice_set(&input_embeds, 1, 0)?;
+    for _ in 0..NEW_TOKENS {
+        let token = argmax(&model.forward_input_embed(&buffer.narrow(1, 0, len)?, 0, cache)?)?;
+        buffer.slice_set(&embed(model, token, device)?, 1, len)?;
+        len += 1;
+    }
+    Ok(())
+}
+
+fn decode_step(model: &Llama, cache: &mut Cache, device: &Device) -> Result<()> {
+    let mut token = argmax(&model.forward_input_embed(&prompt(model, device)?, 0, cache)?)?;
+    for pos in PROMPT_LEN..PROMPT_LEN + NEW_TOKENS - 1 {
+        token = argmax(&model.decode_step(token, pos, cache)?)?;
+    }
+    Ok(())
+}
+
+type Loop = fn(&Llama, &mut Cache, &Device) -> Result<()>;
+
+fn main() -> Result<()> {
+    let device = Device::Cpu;
+    let model = model(&device)?;
+    let loops: [(&str, bool, Loop); 3] = [
+        ("concat", false, concat),
+        ("buffer", false, buffer),
+        ("decode_step", true, decode_step),
+    ];
+    println!("{PROMPT_LEN} prompt tokens, {NEW_TOKENS} new tokens");
+    for (name, use_kv_cache, decode_loop) in loops {
+        let mut best = f64::INFINITY;
+        for _ in 0..3 {
+            let mut cache = model.create_cache(use_kv_cache)?;
+            let start = Instant::now();
+            decode_loop(&model, &mut cache, &device)?;
+            best = best.min(start.elapsed().as_secs_f64());
+        }
+        println!(
+            "{name:>12}: {:8.1} tokens/s ({:.3}s)",
+            NEW_TOKENS as f64 / best,
+            best
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,314 @@
+/*
+Beam search, following `BeamSearchScorer` of the python transformers. Every beam has its own
+kv cache, a new beam starts from a clone of the cache of its parent, the tensors already in the
+cache are shared.
+*/
+use std::sync::Arc;
+
+use anyhow::{bail, Error as E, Result};
+use candle_core::{DType, Tensor, D};
+use image::DynamicImage;
+
+use crate::cache::Cache;
+use crate::logits_processor::LogitsProcessor;
+use crate::pipeline::LlavaPipeline;
+
+#[derive(Debug, Clone)]
+pub struct BeamSearchOptions {
+    pub num_beams: usize,
+    /// The sum of the log probabilities is divided by `length ^ length_penalty`, a value above 0
+    /// favors longer answers.
+    pub length_penalty: f64,
+    /// Stop as soon as `num_beams` answers are finished, instead of when no running beam can do
+    /// better.
+    pub early_stopping: bool,
+    /// The size of the n-best list, from 1 to `num_beams`.
+    pub num_return_sequences: usize,
+    pub max_new_tokens: usize,
+    /// Run on the logits of each beam before the log softmax, see `sampling::logits_processors`.
+    pub logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+}
+
+impl Default for BeamSearchOptions {
+    fn default() -> Self {
+        Self {
+            num_beams: 4,
+            length_penalty: 1.,
+            early_stopping: false,
+            num_return_sequences: 1,
+            max_new_tokens: 512,
+            logits_processors: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hypothesis {
+    pub text: String,
+    pub tokens: Vec<u32>,
+    /// The sum of the log probabilities of the tokens, the eos token included when it was reached.
+    pub log_prob: f64,
+    /// `log_prob` with the length penalty, the n-best list is sorted by it.
+    pub score: f64,
+}
+
+struct Beam {
+    tokens: Vec<u32>,
+    log_prob: f64,
+    cache: Cache,
+    /// The embeddings to feed next, the whole context without kv cache.
+    input: Tensor,
+    index_pos: usize,
+}
+
+fn score(log_prob: f64, len: usize, length_penalty: f64) -> f64 {
+    log_prob / (len.max(1) as f64).powf(length_penalty)
+}
+
+impl LlavaPipeline {
+    /// The `num_return_sequences` best answers to `prompt`, best first.
+    pub fn beam_search(
+        &self,
+        prompt: &str,
+        images: &[DynamicImage],
+        options: &BeamSearchOptions,
+    ) -> Result<Vec<Hypothesis>> {
+        let conv = self.prompt_conversation(prompt, images.len())?;
+        let input_embeds = self.conversation_embeds(&conv, images)?;
+        // every beam copies the cache it forks, it only needs room for the answer
+        let (_, prompt_len, _) = input_embeds.dims3()?;
+        let cache = self
+            .cache
+            .with_max_seq_len(prompt_len + options.max_new_tokens)?;
+        self.beam_search_from_embeds(&cache, input_embeds, options)
+    }
+
+    /// Beam search from `input_embeds` (1, seq_len, hidden_size), `cache` should be empty. The
+    /// buffers of each beam hold `cache.max_seq_len()` positions, see `Cache::with_max_seq_len`.
+    pub fn beam_search_from_embeds(
+        &self,
+        cache: &Cache,
+        input_embeds: Tensor,
+        options: &BeamSearchOptions,
+    ) -> Result<Vec<Hypothesis>> {
+        let eos_token_id = self.llava_config.eos_token_id as u32;
+        let num_beams = options.num_beams.max(1);
+        if options.num_return_sequences == 0 || options.num_return_sequences > num_beams {
+            bail!(
+                "num_return_sequences is {}, it has to be between 1 and num_beams {num_beams}",
+                options.num_return_sequences
+            )
+        }
+        let (_, prompt_len, _) = input_embeds.dims3()?;
+        let max_seq_len = cache.max_seq_len();
+        if prompt_len > max_seq_len {
+            bail!("the prompt takes {prompt_len} positions, the model handles {max_seq_len}")
+        }
+        let max_new_tokens = options.max_new_tokens.min(max_seq_len - prompt_len + 1);
+        let mut beams = vec![Beam {
+            tokens: Vec::new(),
+            log_prob: 0.,
+            cache: cache.clone(),
+            input: input_embeds,
+            index_pos: 0,
+        }];
+        // (tokens, log_prob, score) of the best finished answers
+        let mut finished: Vec<(Vec<u32>, f64, f64)> = Vec::new();
+        for step in 0..max_new_tokens {
+            // two candidates per beam, so that num_beams are left when some of them end
+            let mut candidates = Vec::new();
+            for (i, beam) in beams.iter_mut().enumerate() {
+                let logits = if beam.cache.use_kv_cache {
+                    let logits =
+                        self.llava
+                            .forward(&beam.input, beam.index_pos, &mut beam.cache)?;
+                    beam.index_pos += beam.input.dim(1)?;
+                    logits
+                } else {
+                    self.llava.forward(&beam.input, 0, &mut beam.cache)?
+                };
+                let mut logits: Vec<f32> = logits.squeeze(0)?.to_dtype(DType::F32)?.to_vec1()?;
+                for processor in &options.logits_processors {
+                    processor.process(&mut logits, &beam.tokens)?;
+                }
+                let log_probs: Vec<f32> = candle_nn::ops::log_softmax(
+                    &Tensor::new(logits.as_slice(), &candle_core::Device::Cpu)?,
+                    D::Minus1,
+                )?
+                .to_vec1()?;
+                let mut order: Vec<usize> = (0..log_probs.len()).collect();
+                let k = (2 * num_beams).min(order.len());
+                order.select_nth_unstable_by(k - 1, |&a, &b| log_probs[b].total_cmp(&log_probs[a]));
+                // tokens ruled out by a logits processor are never candidates
+                for &token in order[..k].iter().filter(|&&t| log_probs[t].is_finite()) {
+                    candidates.push((beam.log_prob + log_probs[token] as f64, i, token as u32));
+                }
+            }
+            candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            let mut next_beams = Vec::with_capacity(num_beams);
+            for (rank, (log_prob, i, token)) in candidates.into_iter().enumerate() {
+                let parent = &beams[i];
+                if token == eos_token_id {
+                    // an eos out of the top num_beams does not end a beam
+                    if rank < num_beams {
+                        let score = score(log_prob, parent.tokens.len(), options.length_penalty);
+                        finished.push((parent.tokens.clone(), log_prob, score));
+                        finished.sort_by(|a, b| b.2.total_cmp(&a.2));
+                        finished.truncate(num_beams);
+                    }
+                    continue;
+                }
+                let next_embeds = self
+                    .llava
+                    .language_model
+                    .embed(&Tensor::new(&[token], self.device())?)?
+                    .unsqueeze(0)?;
+                let input = if parent.cache.use_kv_cache {
+                    next_embeds
+                } else {
+                    Tensor::cat(&[&parent.input, &next_embeds], 1)?
+                };
+                let mut tokens = parent.tokens.clone();
+                tokens.push(token);
+                next_beams.push(Beam {
+                    tokens,
+                    log_prob,
+                    cache: parent.cache.clone(),
+                    input,
+                    index_pos: parent.index_pos,
+                });
+                if next_beams.len() == num_beams {
+                    break;
+                }
+            }
+            beams = next_beams;
+
+            let done = finished.len() >= num_beams
+                && (options.early_stopping
+                    || beams.first().map_or(true, |best| {
+                        // as in transformers, with the length before the token just added
+                        let best_possible = score(best.log_prob, step, options.length_penalty);
+                        finished[finished.len() - 1].2 >= best_possible
+                    }));
+            if done || beams.is_empty() {
+                beams.clear();
+                break;
+            }
+        }
+        // the beams still running at max_new_tokens are answers too
+        for beam in beams {
+            let score = score(beam.log_prob, beam.tokens.len(), options.length_penalty);
+            finished.push((beam.tokens, beam.log_prob, score));
+        }
+        finished.sort_by(|a, b| b.2.total_cmp(&a.2));
+        finished.truncate(options.num_return_sequences);
+        finished
+            .into_iter()
+            .map(|(tokens, log_prob, score)| {
+                Ok(Hypothesis {
+                    text: self.tokenizer.decode(&tokens, true).map_err(E::msg)?,
+                    tokens,
+                    log_prob,
+                    score,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logits_processor::ForcedTokens;
+    use crate::pipeline::tests::{tiny_image, tiny_pipeline};
+    use crate::GenerationOptions;
+
+    #[test]
+    fn test_one_beam_is_greedy() {
+        let mut pipeline = tiny_pipeline(true);
+        let images = [tiny_image()];
+        let greedy = pipeline
+            .generate(
+                "what color is the cat?",
+                &images,
+                &GenerationOptions {
+                    temperature: 0.,
+                    max_new_tokens: 6,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let options = BeamSearchOptions {
+            num_beams: 1,
+            early_stopping: true,
+            max_new_tokens: 6,
+            ..Default::default()
+        };
+        let beams = pipeline
+            .beam_search("what color is the cat?", &images, &options)
+            .unwrap();
+        assert_eq!(beams.len(), 1);
+        assert_eq!(beams[0].tokens, greedy.tokens);
+    }
+
+    #[test]
+    fn test_beam_search_with_and_without_kv_cache() {
+        let options = BeamSearchOptions {
+            num_beams: 3,
+            num_return_sequences: 3,
+            max_new_tokens: 5,
+            ..Default::default()
+        };
+        let images = [tiny_image()];
+        let mut pipeline = tiny_pipeline(true);
+        let with_cache = pipeline
+            .beam_search("what color is the dog?", &images, &options)
+            .unwrap();
+        pipeline.cache = pipeline.llava.language_model.create_cache(false).unwrap();
+        let without_cache = pipeline
+            .beam_search("what color is the dog?", &images, &options)
+            .unwrap();
+        assert_eq!(with_cache.len(), 3);
+        assert!(with_cache.windows(2).all(|w| w[0].score >= w[1].score));
+        for (a, b) in with_cache.iter().zip(&without_cache) {
+            assert_eq!(a.tokens, b.tokens);
+            assert!((a.score - b.score).abs() < 1e-4);
+            assert!((a.score - a.log_prob / (a.tokens.len().max(1) as f64)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_more_sequences_than_beams() {
+        let options = BeamSearchOptions {
+            num_beams: 2,
+            num_return_sequences: 3,
+            max_new_tokens: 2,
+            ..Default::default()
+        };
+        let pipeline = tiny_pipeline(true);
+        let err = pipeline
+            .beam_search("what color is the dog?", &[tiny_image()], &options)
+            .unwrap_err();
+        assert!(err.to_string().contains("num_return_sequences"), "{err}");
+    }
+
+    #[test]
+    fn test_beam_search_runs_the_logits_processors() {
+        let options = BeamSearchOptions {
+            num_beams: 3,
+            num_return_sequences: 3,
+            max_new_tokens: 4,
+            logits_processors: vec![Arc::new(ForcedTokens(vec![5, 7]))],
+            ..Default::default()
+        };
+        let pipeline = tiny_pipeline(true);
+        let beams = pipeline
+            .beam_search("what color is the dog?", &[tiny_image()], &options)
+            .unwrap();
+        assert!(!beams.is_empty());
+        for beam in beams {
+            assert_eq!(&beam.tokens[..2], [5, 7]);
+        }
+    }
+}
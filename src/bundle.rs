@@ -0,0 +1,311 @@
+/*
+A single gguf file holding a whole LLaVA checkpoint: the weights under their checkpoint names, with
+the language model quantized, and the configs, tokenizer and image processor as metadata. It loads
+with `ModelSource::Gguf` without reading the safetensors or the config files again.
+*/
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error as E, Result};
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{Device, Tensor};
+use tokenizers::Tokenizer;
+
+use crate::clip::clip_vit_large_patch14_336;
+use crate::config::HFLLaVAVisionConfig;
+use crate::model_source::ModelSource;
+use crate::pipeline::ModelConfigs;
+
+const ARCHITECTURE: &str = "llava";
+const KEY_CONFIG: &str = "llava.config";
+const KEY_VISION_CONFIG: &str = "llava.vision_config";
+const KEY_IMAGE_PROCESSOR: &str = "llava.image_processor";
+const KEY_GENERATION_CONFIG: &str = "llava.generation_config";
+const KEY_TOKENIZER: &str = "tokenizer.huggingface.json";
+
+/// The vision tower, the projector and the image newline of both checkpoint formats.
+const VISION_PREFIXES: [&str; 6] = [
+    "vision_tower.",
+    "multi_modal_projector.",
+    "image_newline",
+    "model.vision_tower.",
+    "model.mm_projector.",
+    "model.image_newline",
+];
+
+#[derive(Debug, Clone)]
+pub struct QuantizeOptions {
+    /// The type of the linear layers of the language model.
+    pub dtype: GgmlDType,
+    /// The type of the matrices of the vision tower and the projector.
+    pub vision_dtype: GgmlDType,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> Self {
+        Self {
+            dtype: GgmlDType::Q4K,
+            vision_dtype: GgmlDType::F16,
+        }
+    }
+}
+
+/// The llama.cpp names, e.g. "q4_k", "q8_0" or "f16", case and '_' do not matter.
+pub fn parse_ggml_dtype(s: &str) -> Result<GgmlDType> {
+    let dtype = match s.to_lowercase().replace('_', "").as_str() {
+        "f32" => GgmlDType::F32,
+        "f16" => GgmlDType::F16,
+        "q40" => GgmlDType::Q4_0,
+        "q41" => GgmlDType::Q4_1,
+        "q50" => GgmlDType::Q5_0,
+        "q51" => GgmlDType::Q5_1,
+        "q80" => GgmlDType::Q8_0,
+        "q2k" => GgmlDType::Q2K,
+        "q3k" => GgmlDType::Q3K,
+        "q4k" => GgmlDType::Q4K,
+        "q5k" => GgmlDType::Q5K,
+        "q6k" => GgmlDType::Q6K,
+        _ => bail!("unsupported gguf type {s}"),
+    };
+    Ok(dtype)
+}
+
+fn is_vision_tensor(name: &str) -> bool {
+    VISION_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// Matrices get `dtype` when their rows split into its blocks, embeddings and the rows that do not
+/// split stay in F16. Vectors, e.g. norms and biases, stay in F32.
+fn tensor_dtype(name: &str, tensor: &Tensor, options: &QuantizeOptions) -> GgmlDType {
+    let dtype = if is_vision_tensor(name) {
+        options.vision_dtype
+    } else {
+        options.dtype
+    };
+    match tensor.dims() {
+        [_] => GgmlDType::F32,
+        [_, columns] if !name.contains("embed") && columns % dtype.block_size() == 0 => dtype,
+        _ => GgmlDType::F16,
+    }
+}
+
+fn metadata_string<'a>(
+    metadata: &'a HashMap<String, gguf_file::Value>,
+    key: &str,
+) -> Result<Option<&'a str>> {
+    match metadata.get(key) {
+        Some(value) => Ok(Some(value.to_string()?.as_str())),
+        None => Ok(None),
+    }
+}
+
+impl ModelConfigs {
+    pub fn to_gguf_metadata(&self) -> Result<Vec<(String, gguf_file::Value)>> {
+        let vision_config = HFLLaVAVisionConfig::from_clip_vision_config(
+            &self
+                .clip_vision_config
+                .clone()
+                .unwrap_or_else(clip_vit_large_patch14_336),
+        );
+        let mut metadata = vec![
+            ("general.architecture".to_string(), ARCHITECTURE.to_string()),
+            ("general.name".to_string(), self.name.clone()),
+            (
+                KEY_CONFIG.to_string(),
+                serde_json::to_string(&self.llava_config)?,
+            ),
+            (
+                KEY_VISION_CONFIG.to_string(),
+                serde_json::to_string(&vision_config)?,
+            ),
+            (
+                KEY_IMAGE_PROCESSOR.to_string(),
+                serde_json::to_string(&self.image_processor)?,
+            ),
+            (
+                KEY_TOKENIZER.to_string(),
+                self.tokenizer.to_string(false).map_err(E::msg)?,
+            ),
+        ];
+        if let Some(generation_config) = &self.generation_config {
+            metadata.push((
+                KEY_GENERATION_CONFIG.to_string(),
+                serde_json::to_string(generation_config)?,
+            ));
+        }
+        Ok(metadata
+            .into_iter()
+            .map(|(key, value)| (key, gguf_file::Value::String(value)))
+            .collect())
+    }
+
+    pub fn from_gguf_metadata(metadata: &HashMap<String, gguf_file::Value>) -> Result<Self> {
+        let architecture = metadata_string(metadata, "general.architecture")?;
+        if architecture != Some(ARCHITECTURE) {
+            bail!("not a llava bundle, the architecture is {architecture:?}")
+        }
+        let get = |key: &str| {
+            metadata_string(metadata, key)?
+                .with_context(|| format!("{key} is missing in the bundle"))
+        };
+        let vision_config: HFLLaVAVisionConfig = serde_json::from_str(get(KEY_VISION_CONFIG)?)?;
+        let generation_config = match metadata_string(metadata, KEY_GENERATION_CONFIG)? {
+            Some(generation_config) => Some(serde_json::from_str(generation_config)?),
+            None => None,
+        };
+        Ok(Self {
+            name: get("general.name")?.to_string(),
+            llava_config: serde_json::from_str(get(KEY_CONFIG)?)?,
+            clip_vision_config: Some(vision_config.to_clip_vision_config()),
+            tokenizer: Tokenizer::from_str(get(KEY_TOKENIZER)?).map_err(E::msg)?,
+            image_processor: serde_json::from_str(get(KEY_IMAGE_PROCESSOR)?)?,
+            generation_config,
+        })
+    }
+}
+
+/// Convert the checkpoint tensor `name` to the type `options` give it.
+pub fn quantize_tensor(name: &str, tensor: &Tensor, options: &QuantizeOptions) -> Result<QTensor> {
+    let dtype = tensor_dtype(name, tensor, options);
+    QTensor::quantize(tensor, dtype).with_context(|| format!("cannot convert {name} to {dtype:?}"))
+}
+
+/// Write `tensors`, named as in the checkpoint, and `configs` to a gguf file at `path`.
+pub fn write_bundle<P: AsRef<Path>>(
+    path: P,
+    configs: &ModelConfigs,
+    tensors: &[(String, QTensor)],
+) -> Result<()> {
+    let metadata = configs.to_gguf_metadata()?;
+    let metadata = metadata
+        .iter()
+        .map(|(key, value)| (key.as_str(), value))
+        .collect::<Vec<_>>();
+    let mut tensors = tensors
+        .iter()
+        .map(|(name, qtensor)| (name.as_str(), qtensor))
+        .collect::<Vec<_>>();
+    tensors.sort_by_key(|(name, _)| *name);
+    let path = path.as_ref();
+    let mut file = std::io::BufWriter::new(
+        std::fs::File::create(path).with_context(|| format!("cannot create {}", path.display()))?,
+    );
+    gguf_file::write(&mut file, &metadata, &tensors)?;
+    Ok(())
+}
+
+/// Read the checkpoint of `source`, either format, and write it as a bundle to `output`. The
+/// tensors are read from the memory mapped safetensors and converted one at a time, but the
+/// converted ones are all kept until the file is written: the quantized model has to fit in
+/// memory, the original one does not.
+pub fn quantize_checkpoint<P: AsRef<Path>>(
+    source: &ModelSource,
+    output: P,
+    options: &QuantizeOptions,
+) -> Result<()> {
+    let weight_filenames = source.weight_files()?;
+    let safetensors = unsafe { MmapedSafetensors::multi(&weight_filenames)? };
+    let names = safetensors
+        .tensors()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    let configs = ModelConfigs::from_source(source, &names)?;
+    eprintln!(
+        "quantizing {} tensors, the language model to {:?}, the vision tower and projector to {:?}",
+        names.len(),
+        options.dtype,
+        options.vision_dtype
+    );
+    let mut tensors = Vec::with_capacity(names.len());
+    for name in names {
+        let tensor = safetensors.load(&name, &Device::Cpu)?;
+        tensors.push((name.clone(), quantize_tensor(&name, &tensor, options)?));
+    }
+    eprintln!("writing {}", output.as_ref().display());
+    write_bundle(output, &configs, &tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::tests::{tiny_clip_vision_config, tiny_image, tiny_pipeline_with_weights};
+    use crate::pipeline::LlavaPipeline;
+    use crate::quantized::GgufWeights;
+    use crate::GenerationOptions;
+
+    #[test]
+    fn test_parse_ggml_dtype() {
+        assert_eq!(parse_ggml_dtype("Q4_K").unwrap(), GgmlDType::Q4K);
+        assert_eq!(parse_ggml_dtype("q8_0").unwrap(), GgmlDType::Q8_0);
+        assert!(parse_ggml_dtype("q9").is_err());
+    }
+
+    #[test]
+    fn test_bundle_roundtrip() {
+        let (mut pipeline, varmap) = tiny_pipeline_with_weights(true);
+        let configs = ModelConfigs {
+            name: "tiny-llava-v1.6".to_string(),
+            llava_config: pipeline.llava_config.clone(),
+            clip_vision_config: Some(tiny_clip_vision_config()),
+            tokenizer: pipeline.tokenizer.clone(),
+            image_processor: pipeline.image_processor.clone(),
+            generation_config: None,
+        };
+        let options = QuantizeOptions {
+            dtype: GgmlDType::Q8_0,
+            vision_dtype: GgmlDType::F16,
+        };
+        let tensors = varmap
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, var)| (name.clone(), quantize_tensor(name, var, &options).unwrap()))
+            .collect::<Vec<_>>();
+        let path =
+            std::env::temp_dir().join(format!("candle-llava-bundle-{}.gguf", std::process::id()));
+        write_bundle(&path, &configs, &tensors).unwrap();
+
+        let gguf = GgufWeights::load(&path, &Device::Cpu).unwrap();
+        assert!(gguf
+            .quantized("model.layers.0.self_attn.q_proj.weight")
+            .is_some());
+        assert!(gguf.quantized("model.embed_tokens.weight").is_none());
+        assert!(gguf.quantized("model.mm_projector.0.weight").is_none());
+        let loaded = LlavaPipeline::load(&ModelSource::Gguf(path.clone()), &Device::Cpu, true);
+        std::fs::remove_file(&path).unwrap();
+        let mut loaded = loaded.unwrap();
+        assert_eq!(loaded.conv_mode, "llava_v1");
+        assert_eq!(
+            loaded
+                .tokenizer
+                .encode("is this a cat?", true)
+                .unwrap()
+                .get_ids(),
+            pipeline
+                .tokenizer
+                .encode("is this a cat?", true)
+                .unwrap()
+                .get_ids()
+        );
+
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 4,
+            ..Default::default()
+        };
+        let images = [tiny_image()];
+        let expected = pipeline
+            .generate("is this a cat?", &images, &options)
+            .unwrap();
+        let output = loaded
+            .generate("is this a cat?", &images, &options)
+            .unwrap();
+        assert_eq!(output.tokens, expected.tokens);
+    }
+}
@@ -0,0 +1,511 @@
+/*
+The kv cache and rotary tables shared by all the language models, moved out of llama.rs
+so that it does not depend on the llama config.
+
+The keys and values of a layer live in a buffer of `max_seq_len` positions, allocated on the first
+write and then written in place, also after `clear` or `truncate`. Clones of a cache share their
+buffers until one of them writes, then it copies its positions into a buffer of its own.
+*/
+use candle_core::{bail, DType, Device, Result, Tensor};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The keys and values of one layer, (b_sz, num_key_value_heads, capacity, head_dim) each, valid
+/// up to `len`.
+#[derive(Debug, Clone)]
+pub(crate) struct LayerKv {
+    buffers: Arc<(Tensor, Tensor)>,
+    len: usize,
+}
+
+impl LayerKv {
+    fn capacity(&self) -> usize {
+        self.buffers.0.dims()[2]
+    }
+
+    /// The cached keys and values, (b_sz, num_key_value_heads, len, head_dim).
+    pub(crate) fn kv(&self) -> Result<(Tensor, Tensor)> {
+        let (k, v) = &*self.buffers;
+        Ok((k.narrow(2, 0, self.len)?, v.narrow(2, 0, self.len)?))
+    }
+
+    /// The memory held by the buffers.
+    pub(crate) fn size_in_bytes(&self) -> usize {
+        let (k, v) = &*self.buffers;
+        (k.elem_count() + v.elem_count()) * k.dtype().size_in_bytes()
+    }
+
+    /// A copy of the cached positions in buffers of just their size.
+    pub(crate) fn compact(&self) -> Result<Self> {
+        // a contiguous view is the whole buffer, it still has to be copied
+        let copy = |t: Tensor| {
+            if t.is_contiguous() {
+                t.copy()
+            } else {
+                t.contiguous()
+            }
+        };
+        let (k, v) = self.kv()?;
+        Ok(Self {
+            buffers: Arc::new((copy(k)?, copy(v)?)),
+            len: self.len,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    masks: HashMap<usize, Tensor>,
+    pub use_kv_cache: bool,
+    pub(crate) kvs: Vec<Option<LayerKv>>,
+    cos: Tensor,
+    sin: Tensor,
+    max_seq_len: usize,
+    device: Device,
+}
+
+impl Cache {
+    /// `max_seq_len` bounds the positions, cached or not, usually `max_position_embeddings`.
+    pub fn new(
+        use_kv_cache: bool,
+        dtype: DType,
+        num_hidden_layers: usize,
+        head_dim: usize,
+        rope_theta: f32,
+        max_seq_len: usize,
+        device: &Device,
+    ) -> Result<Self> {
+        // precompute freqs_cis
+        let theta: Vec<_> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / rope_theta.powf(i as f32 / head_dim as f32))
+            .collect();
+        let theta = Tensor::new(theta.as_slice(), device)?;
+        let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?
+            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+        // This is different from the paper, see:
+        // https://github.com/huggingface/transformers/blob/6112b1c6442aaf7affd2b0676a1cd4eee30c45cf/src/transformers/models/llama/modeling_llama.py#L112
+        let cos = idx_theta.cos()?.to_dtype(dtype)?;
+        let sin = idx_theta.sin()?.to_dtype(dtype)?;
+        Ok(Self {
+            masks: HashMap::new(),
+            use_kv_cache,
+            kvs: vec![None; num_hidden_layers],
+            device: device.clone(),
+            cos,
+            sin,
+            max_seq_len,
+        })
+    }
+
+    /// Forget the cached keys and values, e.g. before starting a new conversation. The buffers
+    /// that are not shared with a clone are kept for the next writes.
+    pub fn clear(&mut self) {
+        for kv in self.kvs.iter_mut() {
+            match kv {
+                Some(layer) if Arc::strong_count(&layer.buffers) == 1 => layer.len = 0,
+                _ => *kv = None,
+            }
+        }
+    }
+
+    /// Number of positions whose keys and values are cached.
+    pub fn seq_len(&self) -> usize {
+        match self.kvs.first() {
+            Some(Some(kv)) => kv.len,
+            _ => 0,
+        }
+    }
+
+    /// The most positions the model can see, cached or not.
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// An empty cache like this one for at most `max_seq_len` positions, its buffers are allocated
+    /// for that many, e.g. for the forks of beam search.
+    pub fn with_max_seq_len(&self, max_seq_len: usize) -> Result<Self> {
+        let max_seq_len = max_seq_len.min(self.max_seq_len);
+        Ok(Self {
+            masks: HashMap::new(),
+            use_kv_cache: self.use_kv_cache,
+            kvs: vec![None; self.kvs.len()],
+            cos: self.cos.narrow(0, 0, max_seq_len)?,
+            sin: self.sin.narrow(0, 0, max_seq_len)?,
+            max_seq_len,
+            device: self.device.clone(),
+        })
+    }
+
+    /// Keep only the first `len` positions, the rest is computed again on the next forward.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        for layer in self.kvs.iter_mut().flatten() {
+            layer.len = layer.len.min(len);
+        }
+        Ok(())
+    }
+
+    /// Take the first `len` positions of `kvs`, e.g. of a prefix cache entry. They are copied into
+    /// the buffers of this cache when it owns them, else the buffers of `kvs` are shared.
+    pub(crate) fn load(&mut self, kvs: &[Option<LayerKv>], len: usize) -> Result<()> {
+        for (kv, other) in self.kvs.iter_mut().zip(kvs) {
+            let Some(other) = other else {
+                *kv = None;
+                continue;
+            };
+            let len = len.min(other.len);
+            match kv {
+                Some(layer)
+                    if Arc::strong_count(&layer.buffers) == 1 && len <= layer.capacity() =>
+                {
+                    let (k, v) = other.kv()?;
+                    let (buffer_k, buffer_v) = &*layer.buffers;
+                    buffer_k.slice_set(&k.narrow(2, 0, len)?.contiguous()?, 2, 0)?;
+                    buffer_v.slice_set(&v.narrow(2, 0, len)?.contiguous()?, 2, 0)?;
+                    layer.len = len;
+                }
+                _ => {
+                    *kv = Some(LayerKv {
+                        buffers: other.buffers.clone(),
+                        len,
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the cached positions `start..start + len`, the later ones move back by `len`. Their
+    /// keys are rotated back by `len` positions, as if they were computed at their new positions,
+    /// so nothing is prefilled again. The values do not depend on the position.
+    pub fn evict(&mut self, start: usize, len: usize) -> Result<()> {
+        let seq_len = self.seq_len();
+        if start + len > seq_len {
+            bail!(
+                "cannot evict positions {start}..{}, only {seq_len} positions are cached",
+                start + len
+            )
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let rest = seq_len - start - len;
+        // a rotation by -len: cos(-x) = cos(x) and sin(-x) = -sin(x)
+        let (cos, sin) = self.cos_sin(len, 1)?;
+        let half_dim = cos.dim(1)?;
+        let cos = cos.broadcast_as((rest, half_dim))?.contiguous()?;
+        let sin = sin.neg()?.broadcast_as((rest, half_dim))?.contiguous()?;
+        for kv in self.kvs.iter_mut() {
+            let Some(layer) = kv.take().filter(|_| start + rest > 0) else {
+                continue;
+            };
+            let (k, v) = layer.kv()?;
+            let (mut ks, mut vs) = (Vec::new(), Vec::new());
+            if start > 0 {
+                ks.push(k.narrow(2, 0, start)?);
+                vs.push(v.narrow(2, 0, start)?);
+            }
+            if rest > 0 {
+                let k_rest = k.narrow(2, start + len, rest)?.contiguous()?;
+                ks.push(candle_nn::rotary_emb::rope(&k_rest, &cos, &sin)?);
+                vs.push(v.narrow(2, start + len, rest)?);
+            }
+            let (k, v) = (
+                Tensor::cat(&ks, 2)?.contiguous()?,
+                Tensor::cat(&vs, 2)?.contiguous()?,
+            );
+            // a new buffer, a clone may still use the previous one
+            let (b_sz, num_heads, _, head_dim) = k.dims4()?;
+            let shape = (b_sz, num_heads, self.max_seq_len, head_dim);
+            let buffers = (
+                Tensor::zeros(shape, k.dtype(), k.device())?,
+                Tensor::zeros(shape, v.dtype(), v.device())?,
+            );
+            buffers.0.slice_set(&k, 2, 0)?;
+            buffers.1.slice_set(&v, 2, 0)?;
+            *kv = Some(LayerKv {
+                buffers: Arc::new(buffers),
+                len: start + rest,
+            });
+        }
+        Ok(())
+    }
+
+    /// The rotary tables for `seq_len` positions from `index_pos`.
+    pub(crate) fn cos_sin(&self, index_pos: usize, seq_len: usize) -> Result<(Tensor, Tensor)> {
+        if index_pos + seq_len > self.max_seq_len {
+            bail!(
+                "the sequence is too long: {} positions, the model handles {}",
+                index_pos + seq_len,
+                self.max_seq_len
+            )
+        }
+        Ok((
+            self.cos.narrow(0, index_pos, seq_len)?,
+            self.sin.narrow(0, index_pos, seq_len)?,
+        ))
+    }
+
+    /// Write the keys and values of `block_idx` for the positions from `index_pos`, and return all
+    /// the cached ones up to them. `k` and `v` are (b_sz, num_key_value_heads, seq_len, head_dim).
+    pub(crate) fn append(
+        &mut self,
+        block_idx: usize,
+        index_pos: usize,
+        k: &Tensor,
+        v: &Tensor,
+    ) -> Result<(Tensor, Tensor)> {
+        let (b_sz, num_heads, seq_len, head_dim) = k.dims4()?;
+        let len = index_pos + seq_len;
+        if len > self.max_seq_len {
+            bail!(
+                "the kv cache is full: {len} positions, it holds {}",
+                self.max_seq_len
+            )
+        }
+        let cached = self.kvs[block_idx].as_ref().map_or(0, |kv| kv.len);
+        if index_pos > cached {
+            bail!("cannot write position {index_pos}, only {cached} positions are cached")
+        }
+        let buffers = match self.kvs[block_idx].take() {
+            // in place when the buffers are not shared with a clone, and not a compact copy
+            Some(layer) if Arc::strong_count(&layer.buffers) == 1 && len <= layer.capacity() => {
+                layer.buffers
+            }
+            previous => {
+                let shape = (b_sz, num_heads, self.max_seq_len, head_dim);
+                let buffers = (
+                    Tensor::zeros(shape, k.dtype(), k.device())?,
+                    Tensor::zeros(shape, v.dtype(), v.device())?,
+                );
+                if let Some(previous) = previous.filter(|_| index_pos > 0) {
+                    let (previous_k, previous_v) = previous.kv()?;
+                    let previous_k = previous_k.narrow(2, 0, index_pos)?.contiguous()?;
+                    let previous_v = previous_v.narrow(2, 0, index_pos)?.contiguous()?;
+                    buffers.0.slice_set(&previous_k, 2, 0)?;
+                    buffers.1.slice_set(&previous_v, 2, 0)?;
+                }
+                Arc::new(buffers)
+            }
+        };
+        buffers.0.slice_set(&k.contiguous()?, 2, index_pos)?;
+        buffers.1.slice_set(&v.contiguous()?, 2, index_pos)?;
+        let layer = LayerKv { buffers, len };
+        let kv = layer.kv()?;
+        self.kvs[block_idx] = Some(layer);
+        Ok(kv)
+    }
+
+    /// Causal mask for `t` queries following `index_pos` cached positions, (t, index_pos + t).
+    pub(crate) fn mask(&mut self, t: usize, index_pos: usize) -> Result<Tensor> {
+        if index_pos > 0 {
+            let mask: Vec<_> = (0..t)
+                .flat_map(|i| (0..index_pos + t).map(move |j| u8::from(j > i + index_pos)))
+                .collect();
+            return Tensor::from_slice(&mask, (t, index_pos + t), &self.device);
+        }
+        if let Some(mask) = self.masks.get(&t) {
+            Ok(mask.clone())
+        } else {
+            let mask: Vec<_> = (0..t)
+                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .collect();
+            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
+            self.masks.insert(t, mask.clone());
+            Ok(mask)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(start: usize, len: usize) -> Tensor {
+        Tensor::arange(start as f32, (start + len) as f32, &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, len, 1))
+            .unwrap()
+            .broadcast_as((1, 2, len, 4))
+            .unwrap()
+            .contiguous()
+            .unwrap()
+    }
+
+    fn cached(cache: &Cache) -> Vec<f32> {
+        let (k, _) = cache.kvs[0].as_ref().unwrap().kv().unwrap();
+        k.narrow(1, 0, 1)
+            .unwrap()
+            .narrow(3, 0, 1)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_append_in_place() {
+        let mut cache = Cache::new(true, DType::F32, 1, 4, 10000., 300, &Device::Cpu).unwrap();
+        let (k, v) = cache
+            .append(0, 0, &positions(0, 200), &positions(0, 200))
+            .unwrap();
+        assert_eq!(k.dims(), &[1, 2, 200, 4]);
+        assert_eq!(v.dims(), &[1, 2, 200, 4]);
+        // allocated once for max_seq_len positions
+        assert_eq!(cache.kvs[0].as_ref().unwrap().capacity(), 300);
+        let buffers = Arc::as_ptr(&cache.kvs[0].as_ref().unwrap().buffers);
+        cache
+            .append(0, 200, &positions(200, 99), &positions(200, 99))
+            .unwrap();
+        assert_eq!(cache.seq_len(), 299);
+        assert_eq!(
+            Arc::as_ptr(&cache.kvs[0].as_ref().unwrap().buffers),
+            buffers
+        );
+        assert_eq!(
+            cached(&cache),
+            (0..299).map(|i| i as f32).collect::<Vec<_>>()
+        );
+        cache
+            .append(0, 299, &positions(299, 1), &positions(299, 1))
+            .unwrap();
+        let err = cache.append(0, 300, &positions(300, 1), &positions(300, 1));
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("the kv cache is full"));
+        assert!(cache.cos_sin(299, 2).is_err());
+        assert!(cache
+            .append(0, 301, &positions(0, 1), &positions(0, 1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_clones_do_not_share_writes() {
+        let mut cache = Cache::new(true, DType::F32, 1, 4, 10000., 64, &Device::Cpu).unwrap();
+        cache
+            .append(0, 0, &positions(0, 4), &positions(0, 4))
+            .unwrap();
+        let clone = cache.clone();
+        // rewrite the last two positions of the original only
+        cache.truncate(2).unwrap();
+        cache
+            .append(0, 2, &positions(10, 3), &positions(10, 3))
+            .unwrap();
+        assert_eq!(cached(&cache), [0., 1., 10., 11., 12.]);
+        assert_eq!(cached(&clone), [0., 1., 2., 3.]);
+        assert_eq!(cache.kvs[0].as_ref().unwrap().capacity(), 64);
+        // the original owns its buffer again, the next write is in place
+        let buffer = cache.kvs[0].as_ref().unwrap().buffers.clone();
+        drop(clone);
+        drop(buffer);
+        cache
+            .append(0, 5, &positions(13, 1), &positions(13, 1))
+            .unwrap();
+        assert_eq!(cached(&cache), [0., 1., 10., 11., 12., 13.]);
+    }
+
+    #[test]
+    fn test_clear_keeps_owned_buffers() {
+        let mut cache = Cache::new(true, DType::F32, 1, 4, 10000., 64, &Device::Cpu).unwrap();
+        cache
+            .append(0, 0, &positions(0, 4), &positions(0, 4))
+            .unwrap();
+        let buffers = Arc::as_ptr(&cache.kvs[0].as_ref().unwrap().buffers);
+        cache.clear();
+        assert_eq!(cache.seq_len(), 0);
+        cache
+            .append(0, 0, &positions(5, 2), &positions(5, 2))
+            .unwrap();
+        assert_eq!(cached(&cache), [5., 6.]);
+        assert_eq!(
+            Arc::as_ptr(&cache.kvs[0].as_ref().unwrap().buffers),
+            buffers
+        );
+
+        // a prefix is copied into them too
+        let mut prefix = cache.with_max_seq_len(64).unwrap();
+        prefix
+            .append(0, 0, &positions(0, 3), &positions(0, 3))
+            .unwrap();
+        cache.load(&prefix.kvs, 2).unwrap();
+        assert_eq!(cached(&cache), [0., 1.]);
+        assert_eq!(
+            Arc::as_ptr(&cache.kvs[0].as_ref().unwrap().buffers),
+            buffers
+        );
+
+        // shared ones are left to the clone
+        let clone = cache.clone();
+        cache.clear();
+        assert!(cache.kvs[0].is_none());
+        assert_eq!(cached(&clone), [0., 1.]);
+    }
+
+    #[test]
+    fn test_with_max_seq_len() {
+        let mut cache = Cache::new(true, DType::F32, 2, 4, 10000., 64, &Device::Cpu).unwrap();
+        cache
+            .append(0, 0, &positions(0, 4), &positions(0, 4))
+            .unwrap();
+        let mut small = cache.with_max_seq_len(6).unwrap();
+        assert_eq!((small.max_seq_len(), small.seq_len()), (6, 0));
+        small
+            .append(0, 0, &positions(0, 6), &positions(0, 6))
+            .unwrap();
+        assert_eq!(small.kvs[0].as_ref().unwrap().capacity(), 6);
+        assert!(small.cos_sin(6, 1).is_err());
+        let (cos, _) = small.cos_sin(2, 4).unwrap();
+        let (expected, _) = cache.cos_sin(2, 4).unwrap();
+        assert_eq!(
+            cos.to_vec2::<f32>().unwrap(),
+            expected.to_vec2::<f32>().unwrap()
+        );
+        // never more than the model handles
+        assert_eq!(cache.with_max_seq_len(100).unwrap().max_seq_len(), 64);
+    }
+
+    #[test]
+    fn test_evict_rotates_the_keys() {
+        let device = Device::Cpu;
+        let mut cache = Cache::new(true, DType::F32, 1, 4, 10000., 64, &device).unwrap();
+        let x = Tensor::arange(0f32, 48., &device)
+            .unwrap()
+            .sin()
+            .unwrap()
+            .reshape((1, 2, 6, 4))
+            .unwrap();
+        let rope = |x: &Tensor, cache: &Cache, index_pos: usize| {
+            let (cos, sin) = cache.cos_sin(index_pos, x.dim(2).unwrap()).unwrap();
+            candle_nn::rotary_emb::rope(&x.contiguous().unwrap(), &cos, &sin).unwrap()
+        };
+        let k = rope(&x, &cache, 0);
+        cache.append(0, 0, &k, &x).unwrap();
+        cache.evict(1, 2).unwrap();
+        assert_eq!(cache.seq_len(), 4);
+
+        let kept =
+            Tensor::cat(&[x.narrow(2, 0, 1).unwrap(), x.narrow(2, 3, 3).unwrap()], 2).unwrap();
+        let expected = rope(&kept, &cache, 0);
+        let (k, v) = cache.kvs[0].as_ref().unwrap().kv().unwrap();
+        let diff = (k - expected)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-5, "{diff}");
+        assert_eq!(
+            v.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            kept.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        );
+        assert!(cache.evict(2, 3).is_err());
+        cache.evict(0, 4).unwrap();
+        assert_eq!(cache.seq_len(), 0);
+    }
+}
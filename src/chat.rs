@@ -0,0 +1,798 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use candle_core::{Device, Tensor};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::Cache;
+use crate::conversation::Conversation;
+use crate::pipeline::{insert_image_tokens, Generated, GenerationOptions, LlavaPipeline};
+use crate::utils::tokenizer_image_token;
+
+/// Bumped when the layout of the session files changes.
+const SESSION_VERSION: u32 = 2;
+/// The safetensors metadata key of the `SessionState` json.
+const SESSION_KEY: &str = "llava.session";
+/// The config keys that only say where the model was loaded from or how it runs, a session
+/// resumes whatever they are. `use_flash_attn` and `max_seq_len` are not even serialized.
+const SESSION_IGNORED_CONFIG_KEYS: &[&str] = &["_name_or_path", "use_flash_attn", "max_seq_len"];
+
+/// What a session file holds besides the tensors.
+#[derive(Serialize, Deserialize)]
+struct SessionState {
+    version: u32,
+    /// The config of the model that wrote the session, the one loading it has to have the same
+    /// but for `SESSION_IGNORED_CONFIG_KEYS`.
+    llava_config: serde_json::Value,
+    /// `LlavaPipeline::weights_fingerprint` of the model that wrote the session, the cache only
+    /// holds for the same weights.
+    weights_fingerprint: String,
+    dtype: String,
+    conversation: Conversation,
+    num_image_features: usize,
+    num_pending_image_features: usize,
+    cached_ids: Vec<i64>,
+    cached_positions: Vec<usize>,
+}
+
+/// A multi-turn conversation that keeps its kv cache between turns, so that a new turn only
+/// prefills what comes after the part of the prompt that is already cached.
+///
+/// When a new turn does not leave room for its answer in the context, the oldest turns are evicted
+/// with their images until it does, the system prompt stays. Their positions are dropped from the
+/// cache and the keys after them rotated back, see `Cache::evict`, so the chat goes on for as long
+/// as the last turn fits.
+pub struct ChatSession {
+    pub conv: Conversation,
+    cache: Cache,
+    /// Features of the images in the conversation, in the order of their image tokens.
+    image_features: Vec<Tensor>,
+    /// Features of the images attached for the next user message.
+    pending_image_features: Vec<Tensor>,
+    /// The input ids whose keys and values are in `cache`, image tokens included.
+    cached_ids: Vec<i64>,
+    /// The cache length right after each of `cached_ids`, an image token spans all its features.
+    cached_positions: Vec<usize>,
+}
+
+impl ChatSession {
+    pub fn new(pipeline: &LlavaPipeline) -> Result<Self> {
+        let cache = pipeline
+            .llava
+            .language_model
+            .create_cache(pipeline.cache.use_kv_cache)?;
+        Ok(Self {
+            conv: pipeline.conversation()?,
+            cache,
+            image_features: Vec::new(),
+            pending_image_features: Vec::new(),
+            cached_ids: Vec::new(),
+            cached_positions: Vec::new(),
+        })
+    }
+
+    /// Encode `image` now, it goes with the next user message.
+    pub fn attach_image(&mut self, pipeline: &LlavaPipeline, image: &DynamicImage) -> Result<()> {
+        let (image_size, image_tensor) = pipeline.process_image(image)?;
+        let mut features = pipeline
+            .llava
+            .encode_image_features(&[image_tensor], &[image_size])?;
+        self.pending_image_features.append(&mut features);
+        Ok(())
+    }
+
+    pub fn num_pending_images(&self) -> usize {
+        self.pending_image_features.len()
+    }
+
+    /// Changes the start of the prompt, so the whole conversation is prefilled again on the next turn.
+    pub fn set_system(&mut self, system: &str) {
+        self.conv.system = system.to_string();
+    }
+
+    /// Forget the messages, the images and the cache. The system prompt and the example turns of
+    /// the conversation template are kept.
+    pub fn reset(&mut self) {
+        let offset = self.conv.offset.max(0) as usize;
+        self.conv.messages.truncate(offset);
+        self.image_features.clear();
+        self.pending_image_features.clear();
+        self.clear_cache();
+    }
+
+    /// Write the conversation, the features of its images and the kv cache to a safetensors file
+    /// at `path`, the rest of the state goes as json into its metadata. `load` resumes from it
+    /// without encoding the images or prefilling again.
+    pub fn save<P: AsRef<Path>>(&self, pipeline: &LlavaPipeline, path: P) -> Result<()> {
+        let mut tensors = Vec::new();
+        for (i, features) in self.image_features.iter().enumerate() {
+            tensors.push((format!("image_features.{i}"), features.contiguous()?));
+        }
+        for (i, features) in self.pending_image_features.iter().enumerate() {
+            tensors.push((
+                format!("pending_image_features.{i}"),
+                features.contiguous()?,
+            ));
+        }
+        for (i, kv) in self.cache.kvs.iter().enumerate() {
+            if let Some(kv) = kv {
+                let (k, v) = kv.kv()?;
+                tensors.push((format!("cache.{i}.k"), k.contiguous()?));
+                tensors.push((format!("cache.{i}.v"), v.contiguous()?));
+            }
+        }
+        let state = SessionState {
+            version: SESSION_VERSION,
+            llava_config: serde_json::to_value(&pipeline.llava_config)?,
+            weights_fingerprint: pipeline.weights_fingerprint().to_string(),
+            dtype: pipeline.dtype().as_str().to_string(),
+            conversation: self.conv.clone(),
+            num_image_features: self.image_features.len(),
+            num_pending_image_features: self.pending_image_features.len(),
+            cached_ids: self.cached_ids.clone(),
+            cached_positions: self.cached_positions.clone(),
+        };
+        let metadata = HashMap::from([(SESSION_KEY.to_string(), serde_json::to_string(&state)?)]);
+        let path = path.as_ref();
+        safetensors::serialize_to_file(tensors, &Some(metadata), path)
+            .with_context(|| format!("cannot write the session to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Resume a session written by `save`, with the same config but for its location, see
+    /// `SESSION_IGNORED_CONFIG_KEYS`, the same weights and the same dtype. Without kv
+    /// cache the saved one is left out and the next turn prefills the whole conversation.
+    pub fn load<P: AsRef<Path>>(pipeline: &LlavaPipeline, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let buffer =
+            std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let (_, metadata) = safetensors::SafeTensors::read_metadata(&buffer)
+            .with_context(|| format!("{} is not a safetensors file", path.display()))?;
+        let state = metadata
+            .metadata()
+            .as_ref()
+            .and_then(|metadata| metadata.get(SESSION_KEY))
+            .with_context(|| format!("{} is not a chat session", path.display()))?;
+        let state: SessionState = serde_json::from_str(state)?;
+        if state.version != SESSION_VERSION {
+            bail!(
+                "the session has version {}, this build reads version {SESSION_VERSION}",
+                state.version
+            )
+        }
+        let llava_config = serde_json::to_value(&pipeline.llava_config)?;
+        let (Some(saved), Some(loaded)) =
+            (state.llava_config.as_object(), llava_config.as_object())
+        else {
+            bail!("the session has no model config")
+        };
+        let mut differences = saved
+            .keys()
+            .chain(loaded.keys())
+            .map(|key| key.as_str())
+            .filter(|key| !SESSION_IGNORED_CONFIG_KEYS.contains(key))
+            .filter(|key| saved.get(*key) != loaded.get(*key))
+            .collect::<Vec<_>>();
+        differences.sort();
+        differences.dedup();
+        if !differences.is_empty() {
+            bail!(
+                "the session was saved with another model, the config differs in {}",
+                differences.join(", ")
+            )
+        }
+        if state.weights_fingerprint != pipeline.weights_fingerprint() {
+            bail!(
+                "the session was saved with other weights, fingerprint {} instead of {}",
+                state.weights_fingerprint,
+                pipeline.weights_fingerprint()
+            )
+        }
+        let dtype = pipeline.dtype().as_str();
+        if state.dtype != dtype {
+            bail!(
+                "the session was saved in {}, the model runs in {dtype}",
+                state.dtype
+            )
+        }
+
+        let mut tensors = candle_core::safetensors::load_buffer(&buffer, pipeline.device())?;
+        let mut take = |name: String| {
+            tensors
+                .remove(&name)
+                .with_context(|| format!("{name} is missing in the session"))
+        };
+        let hidden_size = pipeline.llava_config.hidden_size;
+        let mut features = |prefix: &str, len: usize| {
+            (0..len)
+                .map(|i| {
+                    let features = take(format!("{prefix}.{i}"))?;
+                    match features.dims() {
+                        [_, size] if *size == hidden_size => Ok(features),
+                        dims => bail!("{prefix}.{i} is {dims:?}, expected (_, {hidden_size})"),
+                    }
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let image_features = features("image_features", state.num_image_features)?;
+        let pending_image_features =
+            features("pending_image_features", state.num_pending_image_features)?;
+
+        let mut session = Self::new(pipeline)?;
+        session.conv = state.conversation;
+        let image_token_index = pipeline.llava_config.image_token_index as i64;
+        let num_image_tokens = session
+            .input_ids(pipeline)?
+            .iter()
+            .filter(|id| **id == image_token_index)
+            .count();
+        if num_image_tokens != image_features.len() {
+            bail!(
+                "the conversation has {num_image_tokens} images but the session {}",
+                image_features.len()
+            )
+        }
+        session.image_features = image_features;
+        session.pending_image_features = pending_image_features;
+
+        let len = state.cached_positions.last().copied().unwrap_or(0);
+        if !session.cache.use_kv_cache || len == 0 {
+            return Ok(session);
+        }
+        if state.cached_ids.len() != state.cached_positions.len() {
+            bail!("the cached ids and positions of the session do not match")
+        }
+        if len > session.cache.max_seq_len() {
+            bail!(
+                "the session holds {len} positions, the kv cache {}",
+                session.cache.max_seq_len()
+            )
+        }
+        let config = &pipeline.llava_config;
+        let shape = [
+            1,
+            config.num_key_value_heads,
+            len,
+            config.hidden_size / config.num_attention_heads,
+        ];
+        for block_idx in 0..session.cache.kvs.len() {
+            let k = take(format!("cache.{block_idx}.k"))?;
+            let v = take(format!("cache.{block_idx}.v"))?;
+            if k.dims() != shape || v.dims() != shape {
+                bail!("cache.{block_idx} is {:?}, expected {shape:?}", k.dims())
+            }
+            session.cache.append(block_idx, 0, &k, &v)?;
+        }
+        session.cached_ids = state.cached_ids;
+        session.cached_positions = state.cached_positions;
+        Ok(session)
+    }
+
+    fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.cached_ids.clear();
+        self.cached_positions.clear();
+    }
+
+    pub fn send(
+        &mut self,
+        pipeline: &LlavaPipeline,
+        message: &str,
+        options: &GenerationOptions,
+    ) -> Result<Generated> {
+        self.send_with_callback(pipeline, message, options, |_| Ok(()))
+    }
+
+    /// Append `message` and the attached images as a user turn and generate the answer,
+    /// `on_token` is called with each piece of decoded text.
+    pub fn send_with_callback<F>(
+        &mut self,
+        pipeline: &LlavaPipeline,
+        message: &str,
+        options: &GenerationOptions,
+        on_token: F,
+    ) -> Result<Generated>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let qs = insert_image_tokens(
+            message,
+            self.pending_image_features.len(),
+            pipeline.llava_config.mm_use_im_start_end,
+        )?;
+        let num_new_images = self.pending_image_features.len();
+        self.conv.append_user_message(Some(&qs));
+        self.conv.append_assistant_message(None);
+        self.image_features.append(&mut self.pending_image_features);
+        match self.generate(pipeline, options, on_token) {
+            Ok(generated) => {
+                if let Some((_, answer)) = self.conv.messages.last_mut() {
+                    *answer = Some(generated.text.trim().to_string());
+                }
+                Ok(generated)
+            }
+            Err(e) => {
+                // leave the session as it was before the message
+                self.conv.messages.truncate(self.conv.messages.len() - 2);
+                self.pending_image_features = self
+                    .image_features
+                    .split_off(self.image_features.len() - num_new_images);
+                // `generate` left in the cache only what it holds for sure, the earlier turns
+                // stay cached
+                Err(e)
+            }
+        }
+    }
+
+    fn generate<F>(
+        &mut self,
+        pipeline: &LlavaPipeline,
+        options: &GenerationOptions,
+        on_token: F,
+    ) -> Result<Generated>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let image_token_index = pipeline.llava_config.image_token_index as i64;
+        // room for the answer, a longer one stops when the context is full
+        let reserve = options.max_new_tokens.min(self.cache.max_seq_len() / 2);
+        let input_ids = self.fit_context(pipeline, reserve)?;
+
+        // reuse the cached prefix, at least one position is left to get the next token logits
+        let reused = if self.cache.use_kv_cache {
+            common_prefix_len(&self.cached_ids, &input_ids).min(input_ids.len() - 1)
+        } else {
+            0
+        };
+        self.truncate_cached(reused)?;
+        let mut index_pos = self.cached_positions.last().copied().unwrap_or(0);
+
+        let new_ids = &input_ids[reused..];
+        let reused_images = self
+            .cached_ids
+            .iter()
+            .filter(|id| **id == image_token_index)
+            .count();
+        let image_features = &self.image_features[reused_images..];
+        let input_embeds = pipeline.llava.embed_with_image_features(
+            &Tensor::new(new_ids, &Device::Cpu)?.unsqueeze(0)?,
+            image_features,
+        )?;
+        let prompt_end = index_pos;
+        let mut image_features = image_features.iter();
+        for id in new_ids {
+            index_pos += if *id == image_token_index {
+                match image_features.next() {
+                    Some(features) => features.dim(0)?,
+                    None => 1,
+                }
+            } else {
+                1
+            };
+            self.cached_ids.push(*id);
+            self.cached_positions.push(index_pos);
+        }
+
+        let generated = match pipeline.generate_from_embeds(
+            &mut self.cache,
+            input_embeds,
+            prompt_end,
+            &pipeline.text_tokens(&input_ids),
+            options,
+            on_token,
+        ) {
+            Ok(generated) => generated,
+            Err(e) => {
+                // the new ids may be partly prefilled, only the reused prefix is known good
+                self.truncate_cached(reused)?;
+                return Err(e);
+            }
+        };
+        if self.cache.use_kv_cache {
+            let fed = generated.index_pos - index_pos;
+            for token in &generated.tokens[..fed] {
+                index_pos += 1;
+                self.cached_ids.push(*token as i64);
+                self.cached_positions.push(index_pos);
+            }
+        } else {
+            self.clear_cache();
+        }
+        Ok(generated)
+    }
+
+    fn input_ids(&self, pipeline: &LlavaPipeline) -> Result<Vec<i64>> {
+        let input_ids = tokenizer_image_token(
+            &self.conv.get_prompt(),
+            &pipeline.tokenizer,
+            pipeline.llava_config.image_token_index as i64,
+            &pipeline.llava_config,
+        )?
+        .squeeze(0)?
+        .to_vec1::<i64>()?;
+        Ok(input_ids)
+    }
+
+    /// Positions taken by `ids`, an image token spans all the features of its image.
+    fn num_positions(&self, ids: &[i64], image_token_index: i64) -> Result<usize> {
+        let mut image_features = self.image_features.iter();
+        let mut num_positions = 0;
+        for id in ids {
+            num_positions += if *id == image_token_index {
+                match image_features.next() {
+                    Some(features) => features.dim(0)?,
+                    None => 1,
+                }
+            } else {
+                1
+            };
+        }
+        Ok(num_positions)
+    }
+
+    /// The input ids of the prompt, once the oldest turns are evicted until it fits in the
+    /// context with `reserve` more positions.
+    fn fit_context(&mut self, pipeline: &LlavaPipeline, reserve: usize) -> Result<Vec<i64>> {
+        let image_token_index = pipeline.llava_config.image_token_index as i64;
+        let max_seq_len = self.cache.max_seq_len();
+        // the example turns of the template, e.g. in vicuna_v0, are never evicted
+        let offset = self.conv.offset.max(0) as usize;
+        let mut input_ids = self.input_ids(pipeline)?;
+        // to put the evicted turns back when the last one does not fit anyway
+        let mut before_eviction = None;
+        loop {
+            let num_positions = self.num_positions(&input_ids, image_token_index)?;
+            if num_positions + reserve <= max_seq_len {
+                return Ok(input_ids);
+            }
+            // the last turn is the one to answer
+            if self.conv.messages.len() <= offset + 2 {
+                if let Some((messages, image_features, cache, cached_ids, cached_positions)) =
+                    before_eviction
+                {
+                    self.conv.messages = messages;
+                    self.image_features = image_features;
+                    self.cache = cache;
+                    self.cached_ids = cached_ids;
+                    self.cached_positions = cached_positions;
+                }
+                bail!(
+                    "the message does not fit: the prompt takes {num_positions} positions and \
+                     {reserve} are kept for the answer, the context holds {max_seq_len}"
+                )
+            }
+            before_eviction.get_or_insert_with(|| {
+                (
+                    self.conv.messages.clone(),
+                    self.image_features.clone(),
+                    self.cache.clone(),
+                    self.cached_ids.clone(),
+                    self.cached_positions.clone(),
+                )
+            });
+            self.conv.messages.drain(offset..offset + 2);
+            let kept_ids = self.input_ids(pipeline)?;
+            self.evict_cached(&input_ids, &kept_ids, image_token_index)?;
+            input_ids = kept_ids;
+        }
+    }
+
+    /// Drop the images and the cached positions of the ids that `before` has and `after` has not.
+    /// When they are not a single run, e.g. the system prompt is part of the first message, the
+    /// cache is cut before them and the rest is prefilled again.
+    fn evict_cached(
+        &mut self,
+        before: &[i64],
+        after: &[i64],
+        image_token_index: i64,
+    ) -> Result<()> {
+        let num_images = |ids: &[i64]| ids.iter().filter(|id| **id == image_token_index).count();
+        self.image_features
+            .drain(..num_images(before) - num_images(after));
+        // the kept prefix has no image token, the same token stands for another image once the
+        // first ones are dropped
+        let first_image = before
+            .iter()
+            .position(|id| *id == image_token_index)
+            .unwrap_or(before.len());
+        let start = common_prefix_len(before, after).min(first_image);
+        let end = (start + before.len()).saturating_sub(after.len());
+        let cached = common_prefix_len(&self.cached_ids, before);
+        if end <= start || end > cached || before[end..] != after[start..] {
+            return self.truncate_cached(start.min(cached));
+        }
+        let start_pos = match start {
+            0 => 0,
+            start => self.cached_positions[start - 1],
+        };
+        let len = self.cached_positions[end - 1] - start_pos;
+        self.cache.evict(start_pos, len)?;
+        self.cached_ids.drain(start..end);
+        self.cached_positions.drain(start..end);
+        for position in &mut self.cached_positions[start..] {
+            *position -= len;
+        }
+        Ok(())
+    }
+
+    /// Keep the keys and values of the first `len` cached ids only.
+    fn truncate_cached(&mut self, len: usize) -> Result<()> {
+        self.cached_ids.truncate(len);
+        self.cached_positions.truncate(len);
+        self.cache
+            .truncate(self.cached_positions.last().copied().unwrap_or(0))?;
+        Ok(())
+    }
+}
+
+fn common_prefix_len(a: &[i64], b: &[i64]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::tests::{tiny_image, tiny_pipeline};
+    use candle_core::DType;
+
+    #[test]
+    fn test_chat_reuses_the_cache() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 6,
+            ..Default::default()
+        };
+        let pipeline = tiny_pipeline(true);
+        let mut chat = ChatSession::new(&pipeline).unwrap();
+        chat.attach_image(&pipeline, &tiny_image()).unwrap();
+        chat.send(&pipeline, "is this a cat?", &options).unwrap();
+        let cached_after_first_turn = chat.cache.seq_len();
+        assert_eq!(
+            *chat.cached_positions.last().unwrap(),
+            cached_after_first_turn
+        );
+        chat.attach_image(&pipeline, &tiny_image()).unwrap();
+        let second = chat
+            .send(&pipeline, "what color is the dog?", &options)
+            .unwrap();
+        assert_eq!(chat.conv.messages.len(), 4);
+        assert_eq!(chat.image_features.len(), 2);
+
+        // the same conversation recomputed from scratch
+        let pipeline = tiny_pipeline(false);
+        let mut fresh = ChatSession::new(&pipeline).unwrap();
+        fresh.conv = chat.conv.clone();
+        fresh.conv.messages.truncate(3);
+        fresh.conv.append_assistant_message(None);
+        fresh.image_features = chat.image_features.clone();
+        let expected = fresh.generate(&pipeline, &options, |_| Ok(())).unwrap();
+        assert_eq!(second.tokens, expected.tokens);
+    }
+
+    #[test]
+    fn test_chat_evicts_the_oldest_turns() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 4,
+            ..Default::default()
+        };
+        let pipeline = tiny_pipeline(true);
+        let turns = |chat: &mut ChatSession| {
+            chat.attach_image(&pipeline, &tiny_image()).unwrap();
+            chat.send(&pipeline, "is this a cat?", &options).unwrap();
+            chat.send(&pipeline, "what color is it?", &options).unwrap();
+        };
+        let mut unbounded = ChatSession::new(&pipeline).unwrap();
+        turns(&mut unbounded);
+        let max_seq_len = unbounded.cache.seq_len() + options.max_new_tokens + 2;
+
+        let mut chat = ChatSession::new(&pipeline).unwrap();
+        chat.cache = Cache::new(true, DType::F32, 2, 8, 10000., max_seq_len, &Device::Cpu).unwrap();
+        turns(&mut chat);
+        assert_eq!(chat.cached_ids, unbounded.cached_ids);
+        chat.send(&pipeline, "how many legs does it have?", &options)
+            .unwrap();
+        // the first turn went with its image, the system prompt stayed
+        assert_eq!(chat.conv.messages.len(), 4);
+        assert_eq!(
+            chat.conv.messages[0].1.as_deref(),
+            Some("what color is it?")
+        );
+        assert!(chat.image_features.is_empty());
+        assert!(chat.conv.get_prompt().starts_with(&chat.conv.system));
+        assert_eq!(chat.cache.seq_len(), *chat.cached_positions.last().unwrap());
+        assert!(chat.cache.seq_len() <= max_seq_len);
+        let input_ids = chat.input_ids(&pipeline).unwrap();
+        let reused = common_prefix_len(&chat.cached_ids, &input_ids);
+        assert!(
+            reused > input_ids.len() - 8,
+            "{reused} of {}",
+            input_ids.len()
+        );
+
+        // a message that cannot fit even alone is refused
+        let long_message = ["cat"; 200].join(" ");
+        assert!(chat.send(&pipeline, &long_message, &options).is_err());
+        assert_eq!(chat.conv.messages.len(), 4);
+        chat.send(&pipeline, "is it a dog?", &options).unwrap();
+    }
+
+    #[test]
+    fn test_chat_keeps_the_example_turns() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 4,
+            ..Default::default()
+        };
+        let pipeline = tiny_pipeline(true);
+        let new_chat = || {
+            let mut chat = ChatSession::new(&pipeline).unwrap();
+            chat.conv = Conversation::conv_vicuna_v0();
+            chat
+        };
+        let examples = new_chat().conv.messages;
+        assert_eq!(examples.len(), 2);
+        let turns = |chat: &mut ChatSession| {
+            chat.send(&pipeline, "is this a cat?", &options).unwrap();
+            chat.send(&pipeline, "what color is it?", &options).unwrap();
+        };
+        let mut unbounded = new_chat();
+        turns(&mut unbounded);
+        let max_seq_len = unbounded.cache.seq_len() + options.max_new_tokens + 2;
+
+        let mut chat = new_chat();
+        chat.cache = Cache::new(true, DType::F32, 2, 8, 10000., max_seq_len, &Device::Cpu).unwrap();
+        turns(&mut chat);
+        chat.send(&pipeline, "how many legs does it have?", &options)
+            .unwrap();
+        // the first turn went, the example before it stayed
+        assert_eq!(chat.conv.messages.len(), 6);
+        assert_eq!(chat.conv.messages[..2], examples);
+        assert_eq!(
+            chat.conv.messages[2].1.as_deref(),
+            Some("what color is it?")
+        );
+        assert_eq!(chat.cache.seq_len(), *chat.cached_positions.last().unwrap());
+
+        chat.reset();
+        assert_eq!(chat.conv.messages, examples);
+        assert_eq!(chat.cache.seq_len(), 0);
+    }
+
+    #[test]
+    fn test_chat_session_save_and_load() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 4,
+            ..Default::default()
+        };
+        let mut pipeline = tiny_pipeline(true);
+        let mut chat = ChatSession::new(&pipeline).unwrap();
+        chat.attach_image(&pipeline, &tiny_image()).unwrap();
+        chat.send(&pipeline, "is this a cat?", &options).unwrap();
+        chat.attach_image(&pipeline, &tiny_image()).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "candle-llava-session-{}.safetensors",
+            std::process::id()
+        ));
+        chat.save(&pipeline, &path).unwrap();
+        let expected = chat.send(&pipeline, "what color is it?", &options).unwrap();
+
+        let mut loaded = ChatSession::load(&pipeline, &path).unwrap();
+        assert_eq!(loaded.conv.messages.len(), 2);
+        assert_eq!(loaded.num_pending_images(), 1);
+        assert_eq!(loaded.cached_ids.len(), loaded.cached_positions.len());
+        assert_eq!(
+            loaded.cache.seq_len(),
+            *loaded.cached_positions.last().unwrap()
+        );
+        let output = loaded
+            .send(&pipeline, "what color is it?", &options)
+            .unwrap();
+        assert_eq!(output.tokens, expected.tokens);
+
+        // another model cannot resume it, whether it differs in shape, in another part of its
+        // config or only in its weights
+        let load_err = |pipeline: &LlavaPipeline| {
+            ChatSession::load(pipeline, &path)
+                .map(|_| ())
+                .unwrap_err()
+                .to_string()
+        };
+        let llava_config = pipeline.llava_config.clone();
+        pipeline.llava_config.num_hidden_layers = 3;
+        let shape_err = load_err(&pipeline);
+        pipeline.llava_config = llava_config.clone();
+        pipeline.llava_config.mm_vision_tower = Some("another-clip".to_string());
+        let config_err = load_err(&pipeline);
+        pipeline.llava_config = llava_config;
+        pipeline.weights_fingerprint = "0123456789abcdef".to_string();
+        let weights_err = load_err(&pipeline);
+        std::fs::remove_file(&path).unwrap();
+        assert!(shape_err.contains("num_hidden_layers"), "{shape_err}");
+        assert!(config_err.contains("mm_vision_tower"), "{config_err}");
+        assert!(weights_err.contains("other weights"), "{weights_err}");
+    }
+
+    #[test]
+    fn test_chat_session_load_from_another_path() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 4,
+            ..Default::default()
+        };
+        let mut pipeline = tiny_pipeline(true);
+        let mut chat = ChatSession::new(&pipeline).unwrap();
+        chat.send(&pipeline, "is this a cat?", &options).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "candle-llava-session-{}-path.safetensors",
+            std::process::id()
+        ));
+        chat.save(&pipeline, &path).unwrap();
+        let expected = chat.send(&pipeline, "what color is it?", &options).unwrap();
+
+        // the same checkpoint, copied somewhere else
+        pipeline.llava_config._name_or_path = "/data/tiny-llava-copy".to_string();
+        let loaded = ChatSession::load(&pipeline, &path);
+        std::fs::remove_file(&path).unwrap();
+        let output = loaded
+            .unwrap()
+            .send(&pipeline, "what color is it?", &options)
+            .unwrap();
+        assert_eq!(output.tokens, expected.tokens);
+    }
+
+    #[test]
+    fn test_chat_keeps_the_cache_on_error() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 4,
+            ..Default::default()
+        };
+        let pipeline = tiny_pipeline(true);
+        let mut expected = ChatSession::new(&pipeline).unwrap();
+        expected
+            .send(&pipeline, "is this a cat?", &options)
+            .unwrap();
+        let expected_output = expected
+            .send(&pipeline, "what color is it?", &options)
+            .unwrap();
+
+        let mut chat = ChatSession::new(&pipeline).unwrap();
+        chat.send(&pipeline, "is this a cat?", &options).unwrap();
+        let cached_ids = chat.cached_ids.clone();
+        let err = chat
+            .send_with_callback(&pipeline, "how many legs?", &options, |_| {
+                bail!("the client went away")
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("went away"), "{err}");
+        assert_eq!(chat.conv.messages.len(), 2);
+        // the first turn is still cached, up to where the failed message starts
+        assert!(!chat.cached_ids.is_empty());
+        assert_eq!(chat.cached_ids[..], cached_ids[..chat.cached_ids.len()]);
+        assert_eq!(chat.cache.seq_len(), *chat.cached_positions.last().unwrap());
+        let output = chat.send(&pipeline, "what color is it?", &options).unwrap();
+        assert_eq!(output.tokens, expected_output.tokens);
+    }
+
+    #[test]
+    fn test_chat_image_count_mismatch() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 2,
+            ..Default::default()
+        };
+        let pipeline = tiny_pipeline(true);
+        let mut chat = ChatSession::new(&pipeline).unwrap();
+        assert!(chat
+            .send(&pipeline, "<image-placeholder> is this a cat?", &options)
+            .is_err());
+        assert!(chat.conv.messages.is_empty());
+        chat.reset();
+        chat.send(&pipeline, "is this a cat?", &options).unwrap();
+        assert_eq!(chat.conv.messages.len(), 2);
+    }
+}
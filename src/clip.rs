@@ -202,7 +202,7 @@ impl ClipVisionEmbeddings {
         let class_embedding = if vs.contains_tensor("class_embedding") {
             vs.get(c.embed_dim, "class_embedding")?
         } else {
-            Tensor::randn(0f32, 1f32, c.embed_dim, vs.device())?
+            Tensor::randn(0f32, 1f32, c.embed_dim, vs.device())?.to_dtype(vs.dtype())?
         };
 
         let num_patches = (c.image_size / c.patch_size).pow(2);
@@ -1,12 +1,14 @@
 use candle_core::Result;
 use candle_core::{Device, Tensor};
-use hf_hub::api::sync::Api;
 use image::{DynamicImage, GenericImageView};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::model_source::ModelSource;
 
 //This struct is mainly for LLaVA aplications, hence it's not completely compatible with python transformer CLIPImageProcessor  few several preprocess that LLaVA used, including "openai/clip-vit-large-patch14-336" and "openai/clip-vit-large-patch14".
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CLIPImageProcessor {
     #[serde(default = "default_size")]
     pub size: u32, // this is not the same as python transformer
@@ -83,10 +85,37 @@ pub fn calculate_middle(image_size: (u32, u32), center_size: (u32, u32)) -> (u32
 }
 
 impl CLIPImageProcessor {
+    /// `clip_id` is either a hub repo id or a local directory.
     pub fn from_pretrained(clip_id: &str) -> anyhow::Result<Self> {
-        let api = Api::new()?;
-        let api = api.model(clip_id.to_string());
-        let config_filename = api.get("preprocessor_config.json")?;
+        Self::from_source(&ModelSource::from_model_path(clip_id)?)
+    }
+
+    /// The preprocessing of the vision towers LLaVA is trained with, known without a download.
+    /// `vision_tower` is `mm_vision_tower` of the config, e.g. "openai/clip-vit-large-patch14-336".
+    pub fn for_vision_tower(vision_tower: &str) -> Option<Self> {
+        let size = match vision_tower.trim_end_matches('/').rsplit('/').next()? {
+            "clip-vit-large-patch14-336" => 336,
+            "clip-vit-large-patch14" => 224,
+            _ => return None,
+        };
+        Some(Self {
+            size,
+            do_resize: default_do_resize(),
+            do_center_crop: default_do_center_crop(),
+            crop_size: size,
+            do_rescale: default_do_rescale(),
+            rescale_factor: default_rescale_factor(),
+            do_normalize: default_do_normalize(),
+            image_mean: default_image_mean(),
+            image_std: default_image_std(),
+        })
+    }
+
+    pub fn from_source(source: &ModelSource) -> anyhow::Result<Self> {
+        Self::from_file(source.get("preprocessor_config.json")?)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(config_filename: P) -> anyhow::Result<Self> {
         let image_processor = serde_json::from_slice(&std::fs::read(config_filename)?)?;
         Ok(image_processor)
     }
@@ -177,6 +206,23 @@ mod tests {
     use std::path::Path;
     const CLIP_ID: &str = "openai/clip-vit-large-patch14-336";
 
+    #[test]
+    fn test_for_vision_tower() {
+        let image_processor = CLIPImageProcessor::for_vision_tower(CLIP_ID).unwrap();
+        assert_eq!(
+            (image_processor.size, image_processor.crop_size),
+            (336, 336)
+        );
+        let image_processor =
+            CLIPImageProcessor::for_vision_tower("/models/clip-vit-large-patch14/").unwrap();
+        assert_eq!(
+            (image_processor.size, image_processor.crop_size),
+            (224, 224)
+        );
+        assert_eq!(image_processor.image_mean, default_image_mean());
+        assert!(CLIPImageProcessor::for_vision_tower("google/siglip-so400m-patch14-384").is_none());
+    }
+
     #[test]
     fn test_resize() {
         let image_path = Path::new("images/Rectangle-1.png");
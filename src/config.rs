@@ -8,6 +8,65 @@ use serde::{Deserialize, Serialize};
 
 use crate::clip_image_processor::CLIPImageProcessor;
 
+/// Which layout a checkpoint uses. The two layouts name both the config keys and the tensors differently.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckpointFormat {
+    /// liuhaotian/LLaVA: flat `mm_*` config keys, tensors under `model.mm_projector`/`model.vision_tower`
+    #[default]
+    Original,
+    /// llava-hf: nested `text_config`/`vision_config`, tensors under `language_model`/`multi_modal_projector`
+    Hf,
+}
+
+impl CheckpointFormat {
+    pub fn from_config_json(config: &serde_json::Value) -> Option<Self> {
+        let config = config.as_object()?;
+        let is_hf = config.contains_key("text_config") || config.contains_key("vision_config");
+        let is_original = config.keys().any(|key| key.starts_with("mm_"));
+        match (is_hf, is_original) {
+            (true, false) => Some(CheckpointFormat::Hf),
+            (false, true) => Some(CheckpointFormat::Original),
+            _ => None,
+        }
+    }
+
+    pub fn from_tensor_names<S: AsRef<str>>(tensor_names: &[S]) -> Option<Self> {
+        let has_prefix = |prefixes: &[&str]| {
+            tensor_names
+                .iter()
+                .any(|name| prefixes.iter().any(|p| name.as_ref().starts_with(p)))
+        };
+        let is_hf = has_prefix(&["multi_modal_projector.", "language_model.", "vision_tower."]);
+        let is_original = has_prefix(&["model.mm_projector.", "model.vision_tower."]);
+        match (is_hf, is_original) {
+            (true, false) => Some(CheckpointFormat::Hf),
+            (false, true) => Some(CheckpointFormat::Original),
+            _ => None,
+        }
+    }
+
+    /// The tensor names decide how weights are loaded, the config shape decides how config.json is parsed,
+    /// so they have to agree.
+    pub fn detect<S: AsRef<str>>(
+        config: &serde_json::Value,
+        tensor_names: &[S],
+    ) -> anyhow::Result<Self> {
+        match (
+            Self::from_config_json(config),
+            Self::from_tensor_names(tensor_names),
+        ) {
+            (Some(from_config), Some(from_tensors)) if from_config != from_tensors => {
+                anyhow::bail!(
+                    "config.json looks like a {from_config:?} checkpoint but the weights look like a {from_tensors:?} checkpoint"
+                )
+            }
+            (Some(format), _) | (None, Some(format)) => Ok(format),
+            (None, None) => anyhow::bail!("cannot detect the checkpoint format"),
+        }
+    }
+}
+
 // original config from liuhaotian/llava
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LLaVAConfig {
@@ -50,6 +109,8 @@ pub struct LLaVAConfig {
     pub rms_norm_eps: f32,
     //pub rope_scaling: Option<f32>,
     pub rope_theta: f32,
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
     //pub tie_word_embeddings: bool,
     pub tokenizer_model_max_length: Option<usize>,
     //pub tokenizer_padding_side: String,
@@ -63,6 +124,15 @@ pub struct LLaVAConfig {
     pub vocab_size: usize,
     #[serde(default = "default_image_token_index")]
     pub image_token_index: isize,
+    #[serde(default)]
+    pub checkpoint_format: CheckpointFormat,
+    /// Set at load time, not part of config.json. Needs the `flash-attn` feature and a CUDA device.
+    #[serde(skip)]
+    pub use_flash_attn: bool,
+    /// Set at load time, the length of the kv cache and rotary tables when it is not
+    /// `max_position_embeddings`.
+    #[serde(skip)]
+    pub max_seq_len: Option<usize>,
 }
 
 fn default_image_token_index() -> isize {
@@ -90,9 +160,23 @@ impl LLaVAConfig {
             rope_theta: self.rope_theta,
             bos_token_id: Some(self.bos_token_id as u32),
             eos_token_id: Some(self.eos_token_id as u32),
-            use_flash_attn: false,
+            use_flash_attn: self.use_flash_attn,
         }
     }
+
+    /// The most positions the language model sees, prompt and answer together.
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len.unwrap_or(self.max_position_embeddings)
+    }
+
+    /// liuhaotian configs say `LlavaMistralForCausalLM`/`llava_mistral`, llava-hf text configs say `MistralForCausalLM`/`mistral`
+    pub fn is_mistral(&self) -> bool {
+        self.model_type.contains("mistral")
+            || self
+                .architectures
+                .iter()
+                .any(|architecture| architecture.contains("Mistral"))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -112,10 +196,13 @@ pub struct HFLLaVATextConfig {
     pub num_hidden_layers: usize,
     #[serde(default = "default_num_key_value_heads")]
     pub num_key_value_heads: usize,
+    #[serde(default)]
     pub pad_token_id: usize,
     pub rms_norm_eps: f32,
     #[serde(default = "default_rope_theta")]
     pub rope_theta: f32,
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
     pub torch_dtype: String,
     #[serde(default = "default_use_cache")]
     pub use_cache: bool,
@@ -191,7 +278,15 @@ pub struct HFGenerationConfig {
     pub eos_token_id: usize,
     #[serde(default = "default_max_length")]
     pub max_length: usize,
-    pub pad_token_id: usize,
+    /// Left out by e.g. llava-hf/llava-v1.6-mistral-7b-hf.
+    pub pad_token_id: Option<usize>,
+    // sampling defaults, most checkpoints leave them out
+    pub do_sample: Option<bool>,
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub min_p: Option<f64>,
+    pub repetition_penalty: Option<f32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -226,20 +321,41 @@ impl HFPreProcessorConfig {
     }
 }
 
-impl HFLLaVAConfig {
+impl HFLLaVAVisionConfig {
     pub fn to_clip_vision_config(&self) -> ClipVisionConfig {
         ClipVisionConfig {
-            embed_dim: self.vision_config.hidden_size,
+            embed_dim: self.hidden_size,
             activation: Activation::QuickGelu,
-            intermediate_size: self.vision_config.intermediate_size,
-            num_hidden_layers: self.vision_config.num_hidden_layers,
-            num_attention_heads: self.vision_config.num_attention_heads,
-            projection_dim: self.vision_config.projection_dim,
+            intermediate_size: self.intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            projection_dim: self.projection_dim,
             num_channels: 3,
-            image_size: self.vision_config.image_size,
-            patch_size: self.vision_config.patch_size,
+            image_size: self.image_size,
+            patch_size: self.patch_size,
+        }
+    }
+
+    /// The activation is always QuickGelu for the LLaVA vision towers.
+    pub fn from_clip_vision_config(config: &ClipVisionConfig) -> Self {
+        Self {
+            hidden_size: config.embed_dim,
+            image_size: config.image_size,
+            intermediate_size: config.intermediate_size,
+            model_type: "clip_vision_model".to_string(),
+            num_attention_heads: config.num_attention_heads,
+            num_hidden_layers: config.num_hidden_layers,
+            patch_size: config.patch_size,
+            projection_dim: config.projection_dim,
+            vocab_size: 32000,
         }
     }
+}
+
+impl HFLLaVAConfig {
+    pub fn to_clip_vision_config(&self) -> ClipVisionConfig {
+        self.vision_config.to_clip_vision_config()
+    }
     fn map_projector_type(s: &str) -> String {
         if s == "gelu" {
             "mlp2x_gelu".to_string()
@@ -264,7 +380,8 @@ impl HFLLaVAConfig {
     ) -> LLaVAConfig {
         LLaVAConfig {
             _name_or_path: name.to_string(),
-            architectures: self.architectures.clone(),
+            // the flattened config describes the language model, like the original LLaVA config
+            architectures: self.text_config.architectures.clone(),
             bos_token_id: generation_config.bos_token_id,
             eos_token_id: generation_config.eos_token_id,
             hidden_size: self.text_config.hidden_size,
@@ -283,18 +400,92 @@ impl HFLLaVAConfig {
             ),
             mm_vision_select_layer: self.vision_feature_layer,
             mm_vision_tower: None,
-            model_type: self.model_type.clone(),
+            model_type: self.text_config.model_type.clone(),
             num_attention_heads: self.text_config.num_attention_heads,
             num_hidden_layers: self.text_config.num_hidden_layers,
             num_key_value_heads: self.text_config.num_key_value_heads,
             pad_token_id: self.text_config.pad_token_id,
             rms_norm_eps: self.text_config.rms_norm_eps,
             rope_theta: self.text_config.rope_theta,
+            sliding_window: self.text_config.sliding_window,
             tokenizer_model_max_length: Some(4096),
             torch_dtype: self.torch_dtype.clone(),
             use_cache: self.text_config.use_cache,
             vocab_size: self.vocab_size,
             image_token_index: self.image_token_index,
+            checkpoint_format: CheckpointFormat::Hf,
+            use_flash_attn: false,
+            max_seq_len: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_format_from_config() {
+        let original: serde_json::Value = serde_json::from_str(
+            r#"{"_name_or_path": "my-finetune", "mm_projector_type": "mlp2x_gelu", "mm_vision_tower": "openai/clip-vit-large-patch14-336"}"#,
+        )
+        .unwrap();
+        let hf: serde_json::Value = serde_json::from_str(
+            r#"{"text_config": {"model_type": "llama"}, "vision_config": {"model_type": "clip_vision_model"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            CheckpointFormat::from_config_json(&original),
+            Some(CheckpointFormat::Original)
+        );
+        assert_eq!(
+            CheckpointFormat::from_config_json(&hf),
+            Some(CheckpointFormat::Hf)
+        );
+        assert_eq!(
+            CheckpointFormat::from_config_json(&serde_json::json!({})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_format_detect() {
+        let original_tensors = [
+            "model.layers.0.self_attn.q_proj.weight",
+            "model.mm_projector.0.weight",
+        ];
+        let hf_tensors = [
+            "language_model.model.layers.0.self_attn.q_proj.weight",
+            "multi_modal_projector.linear_1.weight",
+        ];
+        let original_config = serde_json::json!({"mm_hidden_size": 1024});
+        let hf_config = serde_json::json!({"text_config": {}});
+        assert_eq!(
+            CheckpointFormat::detect(&original_config, &original_tensors).unwrap(),
+            CheckpointFormat::Original
+        );
+        assert_eq!(
+            CheckpointFormat::detect(&hf_config, &hf_tensors).unwrap(),
+            CheckpointFormat::Hf
+        );
+        // the weights win when the config alone is inconclusive
+        assert_eq!(
+            CheckpointFormat::detect(&serde_json::json!({}), &hf_tensors).unwrap(),
+            CheckpointFormat::Hf
+        );
+        assert!(CheckpointFormat::detect(&original_config, &hf_tensors).is_err());
+    }
+
+    #[test]
+    fn test_generation_config_without_pad_token_id() {
+        let generation_config: HFGenerationConfig = serde_json::from_value(serde_json::json!({
+            "_from_model_config": true,
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+            "transformers_version": "4.39.0.dev0"
+        }))
+        .unwrap();
+        assert_eq!(generation_config.pad_token_id, None);
+        assert_eq!(generation_config.eos_token_id, 2);
+    }
+}
@@ -0,0 +1,337 @@
+/*
+A logits processor that only lets through the tokens that keep the output matching a Grammar.
+The token texts are put in a byte trie, so the tokens sharing a prefix are checked together, and
+byte-fallback tokens (`<0x0A>`) may stop in the middle of an utf-8 char.
+*/
+use std::sync::Mutex;
+
+use anyhow::Result;
+use tokenizers::{DecoderWrapper, Tokenizer};
+
+use crate::grammar::{Grammar, Stack};
+use crate::logits_processor::LogitsProcessor;
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    tokens: Vec<u32>,
+}
+
+/// Where the output is in the grammar, `partial` is the start of an incomplete utf-8 char.
+#[derive(Debug, Clone)]
+struct MatchState {
+    stacks: Vec<Stack>,
+    partial: Vec<u8>,
+}
+
+impl MatchState {
+    fn feed(&self, grammar: &Grammar, byte: u8) -> Option<MatchState> {
+        let mut partial = self.partial.clone();
+        partial.push(byte);
+        match std::str::from_utf8(&partial) {
+            Ok(s) => {
+                let c = s.chars().next()?;
+                let stacks = grammar.advance(&self.stacks, c);
+                (!stacks.is_empty()).then_some(MatchState {
+                    stacks,
+                    partial: Vec::new(),
+                })
+            }
+            // the char is not complete yet, one of the chars it can become has to match
+            Err(e) if e.error_len().is_none() => {
+                let may_advance = match utf8_prefix_bounds(&partial) {
+                    Some((lo, hi)) => grammar.may_advance(&self.stacks, lo, hi),
+                    None => self.stacks.iter().any(|stack| !stack.is_empty()),
+                };
+                may_advance.then(|| MatchState {
+                    stacks: self.stacks.clone(),
+                    partial,
+                })
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn is_accepting(&self) -> bool {
+        self.partial.is_empty() && Grammar::is_accepting(&self.stacks)
+    }
+}
+
+#[derive(Debug)]
+pub struct GrammarConstraint {
+    grammar: Grammar,
+    /// The bytes of each token, None for the special tokens which are never allowed.
+    token_bytes: Vec<Option<Vec<u8>>>,
+    trie: Vec<TrieNode>,
+    eos_token_id: u32,
+    /// The decoder drops the space in front of the first token, e.g. the llama tokenizers.
+    strip_first_space: bool,
+    /// The tokens matched so far and where they lead, reused while the generation goes on.
+    state: Mutex<(Vec<u32>, MatchState)>,
+}
+
+impl GrammarConstraint {
+    pub fn new(grammar: Grammar, tokenizer: &Tokenizer, eos_token_id: u32) -> Result<Self> {
+        let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)))
+            .then(byte_level_chars_to_bytes);
+        let added_tokens = tokenizer.get_added_tokens_decoder();
+        let vocab = tokenizer.get_vocab(false);
+        let mut token_bytes = vec![None; tokenizer.get_vocab_size(true)];
+        for (piece, id) in &vocab {
+            if added_tokens.contains_key(id) {
+                continue;
+            }
+            if let Some(bytes) = token_bytes.get_mut(*id as usize) {
+                *bytes = Some(piece_bytes(piece, byte_level.as_ref()));
+            }
+        }
+        let strip_first_space = match vocab
+            .iter()
+            .find(|(piece, _)| piece.starts_with('▁') && piece.len() > '▁'.len_utf8())
+        {
+            Some((piece, id)) => {
+                tokenizer
+                    .decode(&[*id], false)
+                    .map_err(anyhow::Error::msg)?
+                    == piece['▁'.len_utf8()..]
+            }
+            None => false,
+        };
+
+        let mut trie = vec![TrieNode::default()];
+        for (id, bytes) in token_bytes.iter().enumerate() {
+            let Some(bytes) = bytes else {
+                continue;
+            };
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for byte in bytes {
+                node = match trie[node].children.iter().find(|(b, _)| b == byte) {
+                    Some((_, child)) => *child,
+                    None => {
+                        trie.push(TrieNode::default());
+                        let child = trie.len() - 1;
+                        trie[node].children.push((*byte, child));
+                        child
+                    }
+                };
+            }
+            trie[node].tokens.push(id as u32);
+        }
+
+        let initial = MatchState {
+            stacks: grammar.initial_stacks(),
+            partial: Vec::new(),
+        };
+        Ok(Self {
+            grammar,
+            token_bytes,
+            trie,
+            eos_token_id,
+            strip_first_space,
+            state: Mutex::new((Vec::new(), initial)),
+        })
+    }
+
+    fn initial_state(&self) -> MatchState {
+        MatchState {
+            stacks: self.grammar.initial_stacks(),
+            partial: Vec::new(),
+        }
+    }
+
+    fn feed_token(&self, state: &MatchState, token: u32, first: bool) -> Option<MatchState> {
+        let bytes = self.token_bytes.get(token as usize)?.as_ref()?;
+        let bytes = match bytes.split_first() {
+            Some((b' ', rest)) if first && self.strip_first_space => rest,
+            _ => bytes,
+        };
+        let mut state = state.clone();
+        for byte in bytes {
+            state = state.feed(&self.grammar, *byte)?;
+        }
+        Some(state)
+    }
+
+    /// The tokens that can follow `state`.
+    fn allowed(&self, state: &MatchState, first: bool, allowed: &mut [bool]) {
+        self.visit(0, state, first, allowed)
+    }
+
+    fn visit(&self, node: usize, state: &MatchState, first: bool, allowed: &mut [bool]) {
+        for (byte, child) in &self.trie[node].children {
+            let next = if first && self.strip_first_space && *byte == b' ' {
+                Some(state.clone())
+            } else {
+                state.feed(&self.grammar, *byte)
+            };
+            if let Some(next) = next {
+                for token in &self.trie[*child].tokens {
+                    if let Some(allowed) = allowed.get_mut(*token as usize) {
+                        *allowed = true;
+                    }
+                }
+                self.visit(*child, &next, false, allowed);
+            }
+        }
+    }
+}
+
+impl LogitsProcessor for GrammarConstraint {
+    fn process(&self, logits: &mut [f32], tokens: &[u32]) -> candle_core::Result<()> {
+        let mut guard = self.state.lock().unwrap();
+        let (matched, state) = &mut *guard;
+        if !tokens.starts_with(matched) {
+            matched.clear();
+            *state = self.initial_state();
+        }
+        for token in &tokens[matched.len()..] {
+            *state = self
+                .feed_token(state, *token, matched.is_empty())
+                .ok_or_else(|| {
+                    candle_core::Error::Msg(format!("token {token} does not match the grammar"))
+                })?;
+            matched.push(*token);
+        }
+
+        let mut allowed = vec![false; logits.len()];
+        self.allowed(state, matched.is_empty(), &mut allowed);
+        let eos = self.eos_token_id as usize;
+        if eos < allowed.len() && state.is_accepting() {
+            allowed[eos] = true;
+        }
+        // e.g. the grammar needs a char that no token has
+        if !allowed.contains(&true) {
+            return Err(candle_core::Error::Msg(
+                "no token can continue the output in the grammar".to_string(),
+            ));
+        }
+        for (logit, allowed) in logits.iter_mut().zip(allowed) {
+            if !allowed {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The smallest and the largest char starting with the incomplete utf-8 `prefix`.
+fn utf8_prefix_bounds(prefix: &[u8]) -> Option<(char, char)> {
+    let len = match prefix.first()? {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return None,
+    };
+    let bound = |fill: u8| {
+        let mut bytes = prefix.to_vec();
+        bytes.resize(len, fill);
+        std::str::from_utf8(&bytes).ok()?.chars().next()
+    };
+    Some((bound(0x80)?, bound(0xbf)?))
+}
+
+/// The inverse of the gpt2 `bytes_to_unicode`: printable bytes are kept, the others are moved
+/// to 256 and after.
+fn byte_level_chars_to_bytes() -> [u8; 324] {
+    let mut chars_to_bytes = [0u8; 324];
+    let mut n = 0;
+    for b in 0..=255u8 {
+        let printable = matches!(b, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff);
+        let c = if printable {
+            b as usize
+        } else {
+            n += 1;
+            255 + n
+        };
+        chars_to_bytes[c] = b;
+    }
+    chars_to_bytes
+}
+
+/// The bytes a vocab piece stands for: `<0xNN>` byte-fallback tokens, `▁` for the sentencepiece
+/// spaces, or the byte-level mapping when it is given.
+fn piece_bytes(piece: &str, byte_level: Option<&[u8; 324]>) -> Vec<u8> {
+    if piece.len() == 6 && piece.starts_with("<0x") && piece.ends_with('>') {
+        if let Ok(byte) = u8::from_str_radix(&piece[3..5], 16) {
+            return vec![byte];
+        }
+    }
+    match byte_level {
+        Some(chars_to_bytes) => piece
+            .chars()
+            .map(|c| chars_to_bytes.get(c as usize).copied().unwrap_or(b'?'))
+            .collect(),
+        None => piece.replace('▁', " ").into_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::AddedToken;
+
+    const PIECES: [&str; 9] = [
+        "<unk>", "</s>", "a", "ab", "1", "▁a", "<0xC3>", "<0xA9>", "é",
+    ];
+
+    fn tokenizer() -> Tokenizer {
+        let vocab: HashMap<String, u32> = PIECES
+            .iter()
+            .enumerate()
+            .map(|(i, piece)| (piece.to_string(), i as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.add_special_tokens(&[AddedToken::from("</s>", true)]);
+        tokenizer
+    }
+
+    fn allowed(constraint: &GrammarConstraint, tokens: &[u32]) -> Vec<&'static str> {
+        let mut logits = vec![0f32; PIECES.len()];
+        constraint.process(&mut logits, tokens).unwrap();
+        (0..PIECES.len())
+            .filter(|&i| logits[i] == 0.)
+            .map(|i| PIECES[i])
+            .collect()
+    }
+
+    #[test]
+    fn test_piece_bytes() {
+        let byte_level = byte_level_chars_to_bytes();
+        assert_eq!(piece_bytes("▁the", None), b" the");
+        assert_eq!(piece_bytes("<0x0A>", None), b"\n");
+        assert_eq!(piece_bytes("Ġthe", Some(&byte_level)), b" the");
+        assert_eq!(piece_bytes("Ċ", Some(&byte_level)), b"\n");
+    }
+
+    #[test]
+    fn test_grammar_constraint() {
+        let grammar = Grammar::from_regex("(ab|a)1é").unwrap();
+        let constraint = GrammarConstraint::new(grammar, &tokenizer(), 1).unwrap();
+        assert!(!constraint.strip_first_space);
+        assert_eq!(allowed(&constraint, &[]), ["a", "ab"]);
+        assert_eq!(allowed(&constraint, &[2]), ["1"]);
+        assert_eq!(allowed(&constraint, &[2, 4]), ["<0xC3>", "é"]);
+        assert_eq!(allowed(&constraint, &[2, 4, 6]), ["<0xA9>"]);
+        assert_eq!(allowed(&constraint, &[2, 4, 6, 7]), ["</s>"]);
+        // another generation starts over
+        assert_eq!(allowed(&constraint, &[3]), ["1"]);
+        let mut logits = vec![0f32; PIECES.len()];
+        assert!(constraint.process(&mut logits, &[4]).is_err());
+
+        // a dead end is an error, not an early end of the output
+        let grammar = Grammar::from_regex("a2").unwrap();
+        let constraint = GrammarConstraint::new(grammar, &tokenizer(), 1).unwrap();
+        assert_eq!(allowed(&constraint, &[]), ["a"]);
+        assert!(constraint.process(&mut logits, &[2]).is_err());
+    }
+}
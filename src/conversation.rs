@@ -1,10 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+// ported from https://github.com/haotian-liu/LLaVA/blob/main/llava/conversation.py
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SeparatorStyle {
-    //SINGLE,
+    Single,
     Two,
     Mpt,
-    //PLAIN,
-    //LLAMA2,
+    Plain,
+    Llama2,
 }
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Conversation {
     pub system: String,
     pub roles: Vec<String>,
@@ -16,6 +22,54 @@ pub struct Conversation {
     pub version: String,
 }
 
+/// The names of `conv_templates` in python LLaVA.
+pub const CONV_TEMPLATE_NAMES: &[&str] = &[
+    "default",
+    "v0",
+    "v1",
+    "vicuna_v1",
+    "llama_2",
+    "mistral_instruct",
+    "chatml_direct",
+    "mistral_direct",
+    "plain",
+    "v0_plain",
+    "llava_v0",
+    "v0_mmtag",
+    "llava_v1",
+    "v1_mmtag",
+    "llava_llama_2",
+    "mpt",
+];
+
+const VICUNA_V0_ANSWER: &str =
+    "Renewable energy sources are those that can be replenished naturally in a relatively \
+short amount of time, such as solar, wind, hydro, geothermal, and biomass. \
+Non-renewable energy sources, on the other hand, are finite and will eventually be \
+depleted, such as coal, oil, and natural gas. Here are some key differences between \
+renewable and non-renewable energy sources:\n\
+1. Availability: Renewable energy sources are virtually inexhaustible, while non-renewable \
+energy sources are finite and will eventually run out.\n\
+2. Environmental impact: Renewable energy sources have a much lower environmental impact \
+than non-renewable sources, which can lead to air and water pollution, greenhouse gas emissions, \
+and other negative effects.\n\
+3. Cost: Renewable energy sources can be more expensive to initially set up, but they typically \
+have lower operational costs than non-renewable sources.\n\
+4. Reliability: Renewable energy sources are often more reliable and can be used in more remote \
+locations than non-renewable sources.\n\
+5. Flexibility: Renewable energy sources are often more flexible and can be adapted to different \
+situations and needs, while non-renewable sources are more rigid and inflexible.\n\
+6. Sustainability: Renewable energy sources are more sustainable over the long term, while \
+non-renewable sources are not, and their depletion can lead to economic and social instability.\n";
+
+const LLAMA_2_SYSTEM: &str = "You are a helpful, respectful and honest assistant. Always answer as helpfully as possible, while being safe.  Your answers should not include any harmful, unethical, racist, sexist, toxic, dangerous, or illegal content. Please ensure that your responses are socially unbiased and positive in nature.
+
+If a question does not make any sense, or is not factually coherent, explain why instead of answering something not correct. If you don't know the answer to a question, please don't share false information.";
+
+const MMTAG_SYSTEM: &str = "A chat between a curious user and an artificial intelligence assistant. \
+The assistant is able to understand the visual content that the user provides, and assist the user with a variety of tasks using natural language.\
+The visual content will be provided with the following format: <Image>visual content</Image>.";
+
 impl Conversation {
     pub fn new(
         system: &str,
@@ -38,6 +92,130 @@ impl Conversation {
         }
     }
 
+    /// Look up a template by its name in python `conv_templates`.
+    pub fn from_template(name: &str) -> Option<Self> {
+        let conv = match name {
+            "default" | "v0" => Self::conv_vicuna_v0(),
+            "v1" | "vicuna_v1" => Self::conv_vicuna_v1(),
+            "llama_2" => Self::conv_llama_2(),
+            "mistral_instruct" => Self::conv_mistral_instruct(),
+            "chatml_direct" | "mistral_direct" => Self::conv_chatml_direct(),
+            "plain" | "v0_plain" => Self::conv_llava_plain(),
+            "llava_v0" => Self::conv_llava_v0(),
+            "v0_mmtag" => Self::conv_llava_v0_mmtag(),
+            "llava_v1" => Self::conv_llava_v1(),
+            "v1_mmtag" => Self::conv_llava_v1_mmtag(),
+            "llava_llama_2" => Self::conv_llava_llama_2(),
+            "mpt" => Self::conv_mpt(),
+            _ => return None,
+        };
+        Some(conv)
+    }
+
+    pub fn conv_vicuna_v0() -> Self {
+        let mut conv = Conversation::new(
+            "A chat between a curious human and an artificial intelligence assistant. The assistant gives helpful, detailed, and polite answers to the human's questions.",
+            &["Human".to_string(), "Assistant".to_string()],
+            2,
+            SeparatorStyle::Single,
+            "###",
+            None,
+            "Unknown",
+        );
+        conv.append_user_message(Some(
+            "What are the key differences between renewable and non-renewable energy sources?",
+        ));
+        conv.append_assistant_message(Some(VICUNA_V0_ANSWER));
+        conv
+    }
+
+    pub fn conv_vicuna_v1() -> Self {
+        Conversation::new(
+            "A chat between a curious user and an artificial intelligence assistant. The assistant gives helpful, detailed, and polite answers to the user's questions.",
+            &["USER".to_string(), "ASSISTANT".to_string()],
+            0,
+            SeparatorStyle::Two,
+            " ",
+            Some("</s>"),
+            "v1",
+        )
+    }
+
+    pub fn conv_llama_2() -> Self {
+        Conversation::new(
+            LLAMA_2_SYSTEM,
+            &["USER".to_string(), "ASSISTANT".to_string()],
+            0,
+            SeparatorStyle::Llama2,
+            "<s>",
+            Some("</s>"),
+            "llama_v2",
+        )
+    }
+
+    pub fn conv_llava_llama_2() -> Self {
+        Conversation::new(
+            "You are a helpful language and vision assistant. You are able to understand the visual content that the user provides, and assist the user with a variety of tasks using natural language.",
+            &["USER".to_string(), "ASSISTANT".to_string()],
+            0,
+            SeparatorStyle::Llama2,
+            "<s>",
+            Some("</s>"),
+            "llama_v2",
+        )
+    }
+
+    pub fn conv_mpt() -> Self {
+        Conversation::new(
+            "<|im_start|>system\nA conversation between a user and an LLM-based AI assistant. The assistant gives helpful and honest answers.",
+            &[
+                "<|im_start|>user\n".to_string(),
+                "<|im_start|>assistant\n".to_string(),
+            ],
+            0,
+            SeparatorStyle::Mpt,
+            "<|im_end|>",
+            None,
+            "mpt",
+        )
+    }
+
+    pub fn conv_llava_plain() -> Self {
+        Conversation::new(
+            "",
+            &["".to_string(), "".to_string()],
+            0,
+            SeparatorStyle::Plain,
+            "\n",
+            None,
+            "Unknown",
+        )
+    }
+
+    pub fn conv_llava_v0() -> Self {
+        Conversation::new(
+            "A chat between a curious human and an artificial intelligence assistant. The assistant gives helpful, detailed, and polite answers to the human's questions.",
+            &["Human".to_string(), "Assistant".to_string()],
+            0,
+            SeparatorStyle::Single,
+            "###",
+            None,
+            "Unknown",
+        )
+    }
+
+    pub fn conv_llava_v0_mmtag() -> Self {
+        Conversation::new(
+            MMTAG_SYSTEM,
+            &["Human".to_string(), "Assistant".to_string()],
+            0,
+            SeparatorStyle::Single,
+            "###",
+            None,
+            "v0_mmtag",
+        )
+    }
+
     pub fn conv_chatml_direct() -> Self {
         Conversation::new(
             "<|im_start|>system\nAnswer the questions.",
@@ -68,6 +246,30 @@ impl Conversation {
         )
     }
 
+    pub fn conv_llava_v1_mmtag() -> Self {
+        Conversation::new(
+            MMTAG_SYSTEM,
+            &["USER".to_string(), "ASSISTANT".to_string()],
+            0,
+            SeparatorStyle::Two,
+            " ",
+            Some("</s>"),
+            "v1_mmtag",
+        )
+    }
+
+    pub fn conv_mistral_instruct() -> Self {
+        Conversation::new(
+            "",
+            &["USER".to_string(), "ASSISTANT".to_string()],
+            0,
+            SeparatorStyle::Llama2,
+            "",
+            Some("</s>"),
+            "llama_v2",
+        )
+    }
+
     pub fn append_message(&mut self, role: String, message: Option<&str>) {
         self.messages.push((role, message.map(|s| s.to_string())))
     }
@@ -80,18 +282,39 @@ impl Conversation {
         self.append_message(self.roles[1].clone(), message);
     }
 
+    /// Same as python `get_prompt`, where an empty message counts as no message.
     pub fn get_prompt(&self) -> String {
+        let messages = self
+            .messages
+            .iter()
+            .map(|(role, message)| (role, message.as_deref().filter(|m| !m.is_empty())));
         match self.sep_style {
+            SeparatorStyle::Single => {
+                let mut ret = String::new();
+                ret.push_str(&self.system);
+                ret.push_str(&self.sep);
+                for (role, message) in messages {
+                    ret.push_str(role);
+                    if let Some(message) = message {
+                        ret.push_str(": ");
+                        ret.push_str(message);
+                        ret.push_str(&self.sep);
+                    } else {
+                        ret.push(':')
+                    }
+                }
+                ret
+            }
             SeparatorStyle::Mpt => {
                 let mut ret = String::new();
                 ret.push_str(&self.system);
                 ret.push_str(&self.sep);
-                for (role, message) in &self.messages {
+                for (role, message) in messages {
                     ret.push_str(role);
                     if let Some(message) = message {
                         ret.push_str(message);
-                    };
-                    ret.push_str(&self.sep);
+                        ret.push_str(&self.sep);
+                    }
                 }
                 ret
             }
@@ -100,7 +323,7 @@ impl Conversation {
                 let mut ret = String::new();
                 ret.push_str(&self.system);
                 ret.push_str(&seps[0]);
-                for (i, (role, message)) in self.messages.iter().enumerate() {
+                for (i, (role, message)) in messages.enumerate() {
                     ret.push_str(role);
                     if let Some(message) = message {
                         ret.push_str(": "); // strictly follow the python implementation, otherwise it will cause some minor difference between tokens ^_^
@@ -112,12 +335,63 @@ impl Conversation {
                 }
                 ret
             }
+            SeparatorStyle::Plain => {
+                let seps = [self.sep.clone(), self.sep2.clone().unwrap_or_default()];
+                let mut ret = String::new();
+                ret.push_str(&self.system);
+                for (i, (_, message)) in messages.enumerate() {
+                    if let Some(message) = message {
+                        ret.push_str(message);
+                        ret.push_str(&seps[i % 2]);
+                    }
+                }
+                ret
+            }
+            SeparatorStyle::Llama2 => {
+                let wrap_sys = |msg: &str| {
+                    if msg.is_empty() {
+                        msg.to_string()
+                    } else {
+                        format!("<<SYS>>\n{msg}\n<</SYS>>\n\n")
+                    }
+                };
+                let sep2 = self.sep2.clone().unwrap();
+                let mut ret = String::new();
+                for (i, (_, message)) in messages.enumerate() {
+                    if let Some(message) = message {
+                        let message = if i == 0 {
+                            wrap_sys(&self.system) + message
+                        } else {
+                            message.to_string()
+                        };
+                        if i % 2 == 0 {
+                            ret.push_str(&self.sep);
+                            ret.push_str(&format!("[INST] {message} [/INST]"));
+                        } else {
+                            ret.push_str(&format!(" {message} {sep2}"));
+                        }
+                    }
+                }
+                // python lstrip strips any of the characters of sep
+                ret.trim_start_matches(|c| self.sep.contains(c)).to_string()
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    const LLAVA_V1_SYSTEM: &str = "A chat between a curious human and an artificial intelligence assistant. The assistant gives helpful, detailed, and polite answers to the human's questions.";
+
+    fn prompt(mut conv: Conversation, turns: &[(&str, Option<&str>)]) -> String {
+        for (user, assistant) in turns {
+            conv.append_user_message(Some(user));
+            conv.append_assistant_message(*assistant);
+        }
+        conv.get_prompt()
+    }
 
     #[test]
     fn test_get_prompt() {
@@ -125,6 +399,108 @@ mod tests {
         conv.append_user_message(Some("<image>\nis this a cat?"));
         conv.append_assistant_message(None);
         let prompt = conv.get_prompt();
-        println!("{}", prompt);
+        assert_eq!(
+            prompt,
+            format!("{LLAVA_V1_SYSTEM} USER: <image>\nis this a cat? ASSISTANT:")
+        );
+    }
+
+    #[test]
+    fn test_mistral_instruct_prompt() {
+        let mut conv = super::Conversation::conv_mistral_instruct();
+        conv.append_user_message(Some("<image>\nis this a cat?"));
+        conv.append_assistant_message(Some("Yes."));
+        conv.append_user_message(Some("what color is it?"));
+        conv.append_assistant_message(None);
+        assert_eq!(
+            conv.get_prompt(),
+            "[INST] <image>\nis this a cat? [/INST] Yes. </s>[INST] what color is it? [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_two_prompt() {
+        assert_eq!(
+            prompt(
+                Conversation::conv_vicuna_v1(),
+                &[("hi", Some("Hello.")), ("bye", None)]
+            ),
+            "A chat between a curious user and an artificial intelligence assistant. The assistant gives helpful, detailed, and polite answers to the user's questions. USER: hi ASSISTANT: Hello.</s>USER: bye ASSISTANT:"
+        );
+        // an empty message is falsy in python
+        assert_eq!(
+            prompt(Conversation::conv_llava_v1(), &[("hi", Some(""))]),
+            format!("{LLAVA_V1_SYSTEM} USER: hi ASSISTANT:")
+        );
+        assert_eq!(
+            prompt(Conversation::conv_llava_v1_mmtag(), &[("<image>\nhi", None)]),
+            "A chat between a curious user and an artificial intelligence assistant. The assistant is able to understand the visual content that the user provides, and assist the user with a variety of tasks using natural language.The visual content will be provided with the following format: <Image>visual content</Image>. USER: <image>\nhi ASSISTANT:"
+        );
+    }
+
+    #[test]
+    fn test_single_prompt() {
+        assert_eq!(
+            prompt(
+                Conversation::conv_llava_v0(),
+                &[("<image>\nis this a cat?", Some("Yes.")), ("why?", None)]
+            ),
+            format!("{LLAVA_V1_SYSTEM}###Human: <image>\nis this a cat?###Assistant: Yes.###Human: why?###Assistant:")
+        );
+        let vicuna_v0 = prompt(Conversation::conv_vicuna_v0(), &[("is this a cat?", None)]);
+        assert!(vicuna_v0.starts_with(&format!("{LLAVA_V1_SYSTEM}###Human: What are the key differences between renewable and non-renewable energy sources?###Assistant: Renewable energy sources are those that can be replenished naturally in a relatively short amount of time, such as solar, wind, hydro, geothermal, and biomass. Non-renewable energy sources")));
+        assert!(vicuna_v0.contains("run out.\n2. Environmental impact: Renewable energy sources have a much lower environmental impact than non-renewable sources, which can lead to air and water pollution, greenhouse gas emissions, and other negative effects.\n3. Cost:"));
+        assert!(vicuna_v0.ends_with("their depletion can lead to economic and social instability.\n###Human: is this a cat?###Assistant:"));
+    }
+
+    #[test]
+    fn test_llama_2_prompt() {
+        assert_eq!(
+            prompt(
+                Conversation::conv_llava_llama_2(),
+                &[("<image>\nis this a cat?", Some("Yes.")), ("why?", None)]
+            ),
+            "[INST] <<SYS>>\nYou are a helpful language and vision assistant. You are able to understand the visual content that the user provides, and assist the user with a variety of tasks using natural language.\n<</SYS>>\n\n<image>\nis this a cat? [/INST] Yes. </s><s>[INST] why? [/INST]"
+        );
+        let llama_2 = prompt(Conversation::conv_llama_2(), &[("hi", None)]);
+        assert!(llama_2.starts_with("[INST] <<SYS>>\nYou are a helpful, respectful and honest assistant. Always answer as helpfully as possible, while being safe.  Your answers should not include"));
+        assert!(llama_2.ends_with("please don't share false information.\n<</SYS>>\n\nhi [/INST]"));
+    }
+
+    #[test]
+    fn test_mpt_prompt() {
+        assert_eq!(
+            prompt(
+                Conversation::conv_mpt(),
+                &[("<image>\nis this a cat?", Some("Yes.")), ("why?", None)]
+            ),
+            "<|im_start|>system\nA conversation between a user and an LLM-based AI assistant. The assistant gives helpful and honest answers.<|im_end|><|im_start|>user\n<image>\nis this a cat?<|im_end|><|im_start|>assistant\nYes.<|im_end|><|im_start|>user\nwhy?<|im_end|><|im_start|>assistant\n"
+        );
+        assert_eq!(
+            prompt(Conversation::conv_chatml_direct(), &[("<image>\nhi", None)]),
+            "<|im_start|>system\nAnswer the questions.<|im_end|><|im_start|>user\n<image>\nhi<|im_end|><|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn test_plain_prompt() {
+        assert_eq!(
+            prompt(Conversation::conv_llava_plain(), &[("<image>", None)]),
+            "<image>\n"
+        );
+    }
+
+    #[test]
+    fn test_from_template() {
+        for name in CONV_TEMPLATE_NAMES {
+            assert!(Conversation::from_template(name).is_some(), "{name}");
+        }
+        assert!(Conversation::from_template("llava_v2").is_none());
+        assert_eq!(
+            Conversation::from_template("mistral_direct")
+                .unwrap()
+                .system,
+            Conversation::conv_chatml_direct().system
+        );
     }
 }
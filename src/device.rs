@@ -0,0 +1,56 @@
+/*
+The device to run on, given as "cpu", "cuda", "cuda:N" or "metal". CUDA and Metal are only there
+when the crate is built with the `cuda` or `metal` feature.
+*/
+use anyhow::{bail, Context, Result};
+use candle_core::utils::{cuda_is_available, metal_is_available};
+use candle_core::Device;
+
+/// Without `spec` the first CUDA device, else Metal, else the CPU.
+pub fn device(spec: Option<&str>) -> Result<Device> {
+    let spec = match spec {
+        Some(spec) => spec.trim().to_lowercase(),
+        None if cuda_is_available() => "cuda:0".to_string(),
+        None if metal_is_available() => "metal".to_string(),
+        None => "cpu".to_string(),
+    };
+    let (kind, ordinal) = match spec.split_once(':') {
+        Some((kind, ordinal)) => (
+            kind,
+            ordinal
+                .parse::<usize>()
+                .with_context(|| format!("invalid device ordinal in {spec}"))?,
+        ),
+        None => (spec.as_str(), 0),
+    };
+    let device = match kind {
+        "cpu" if ordinal == 0 => Device::Cpu,
+        "cuda" if !cuda_is_available() => {
+            bail!("{spec} is not available, build with `--features cuda`")
+        }
+        "cuda" => Device::new_cuda(ordinal)?,
+        "metal" if !metal_is_available() => {
+            bail!("{spec} is not available, build with `--features metal`")
+        }
+        "metal" => Device::new_metal(ordinal)?,
+        _ => bail!("unknown device {spec}, expected cpu, cuda, cuda:N or metal"),
+    };
+    Ok(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device() {
+        assert!(device(Some("cpu")).unwrap().is_cpu());
+        assert!(device(Some("CPU")).unwrap().is_cpu());
+        assert!(device(Some("cpu:1")).is_err());
+        assert!(device(Some("cuda:x")).is_err());
+        assert!(device(Some("tpu")).is_err());
+        if !cuda_is_available() {
+            assert!(device(Some("cuda:0")).is_err());
+        }
+    }
+}
@@ -0,0 +1,960 @@
+/*
+Context free grammars over characters for constrained decoding, written in the GBNF format of
+llama.cpp (https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md), or converted
+from a regex or a JSON schema. The matcher follows llama.cpp: a set of stacks of positions in the
+rules, advanced one character at a time.
+*/
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use regex_syntax::hir::{Class, Hir, HirKind, Look};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Element {
+    /// One char in one of the sorted, disjoint ranges, or in none of them when negated.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn chars(mut ranges: Vec<(char, char)>, negated: bool) -> Self {
+        ranges.sort();
+        let mut merged: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last)
+                    if start <= last.1 || char::from_u32(last.1 as u32 + 1) == Some(start) =>
+                {
+                    last.1 = last.1.max(end)
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+        Element::Chars {
+            ranges: merged,
+            negated,
+        }
+    }
+
+    fn char(c: char) -> Self {
+        Element::Chars {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Chars { ranges, negated } => {
+                let i = ranges.partition_point(|(start, _)| *start <= c);
+                let inside = i > 0 && c <= ranges[i - 1].1;
+                inside != *negated
+            }
+            Element::Rule(_) => false,
+        }
+    }
+
+    /// Whether a char between `lo` and `hi` may match.
+    fn may_match(&self, lo: char, hi: char) -> bool {
+        match self {
+            Element::Chars { ranges, negated } => {
+                if *negated {
+                    !ranges.iter().any(|(start, end)| *start <= lo && hi <= *end)
+                } else {
+                    ranges.iter().any(|(start, end)| *start <= hi && lo <= *end)
+                }
+            }
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+/// A position in a grammar: rule, alternative and index of the next element.
+pub type Position = (usize, usize, usize);
+/// The top is the position of the next char, below are the positions to return to.
+pub type Stack = Vec<Position>;
+
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    /// Each rule is a list of alternatives, each alternative a sequence of elements.
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    root: usize,
+}
+
+impl Grammar {
+    /// Parse a GBNF grammar, it starts at the `root` rule.
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut builder = Builder::default();
+        Parser {
+            src: src.chars().collect(),
+            pos: 0,
+            builder: &mut builder,
+        }
+        .parse()?;
+        builder.build("root")
+    }
+
+    /// The whole output has to match `pattern`. `^` and `$` (or `\A` and `\z`) are only allowed
+    /// at its ends, the other assertions are not supported.
+    pub fn from_regex(pattern: &str) -> Result<Self> {
+        let hir = regex_syntax::Parser::new()
+            .parse(pattern)
+            .with_context(|| format!("invalid regex {pattern}"))?;
+        let mut hirs = match hir.kind() {
+            HirKind::Concat(hirs) => hirs.as_slice(),
+            _ => std::slice::from_ref(&hir),
+        };
+        // they hold anyway, the whole output is matched
+        while let Some((HirKind::Look(Look::Start), rest)) =
+            hirs.split_first().map(|(first, rest)| (first.kind(), rest))
+        {
+            hirs = rest;
+        }
+        while let Some((HirKind::Look(Look::End), rest)) =
+            hirs.split_last().map(|(last, rest)| (last.kind(), rest))
+        {
+            hirs = rest;
+        }
+        let mut builder = Builder::default();
+        let root = builder.id("root");
+        let mut seq = Vec::new();
+        for hir in hirs {
+            seq.extend(builder.hir(hir)?);
+        }
+        builder.define(root, vec![seq]);
+        builder.build("root")
+    }
+
+    pub fn from_json_schema(schema: &Value) -> Result<Self> {
+        Self::parse(&json_schema_to_gbnf(schema)?)
+    }
+
+    pub fn initial_stacks(&self) -> Vec<Stack> {
+        let mut out = HashSet::new();
+        for alt in 0..self.rules[self.root].len() {
+            self.expand(vec![(self.root, alt, 0)], &mut out);
+        }
+        out.into_iter().collect()
+    }
+
+    /// The stacks after `c`, empty when no stack accepts it.
+    pub fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = HashSet::new();
+        for stack in stacks {
+            let Some(&(rule, alt, idx)) = stack.last() else {
+                continue;
+            };
+            if self.rules[rule][alt][idx].matches(c) {
+                let mut stack = stack.clone();
+                stack.last_mut().unwrap().2 += 1;
+                self.expand(stack, &mut out);
+            }
+        }
+        out.into_iter().collect()
+    }
+
+    /// Whether a char between `lo` and `hi` may advance one of the stacks.
+    pub fn may_advance(&self, stacks: &[Stack], lo: char, hi: char) -> bool {
+        stacks.iter().any(|stack| match stack.last() {
+            Some(&(rule, alt, idx)) => self.rules[rule][alt][idx].may_match(lo, hi),
+            None => false,
+        })
+    }
+
+    /// An empty stack means the whole grammar is matched.
+    pub fn is_accepting(stacks: &[Stack]) -> bool {
+        stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    /// Follow the rule references until every stack has a char element on top or is empty.
+    fn expand(&self, mut stack: Stack, out: &mut HashSet<Stack>) {
+        loop {
+            let Some(&(rule, alt, idx)) = stack.last() else {
+                out.insert(stack);
+                return;
+            };
+            let seq = &self.rules[rule][alt];
+            if idx == seq.len() {
+                stack.pop();
+                continue;
+            }
+            match seq[idx] {
+                Element::Chars { .. } => {
+                    out.insert(stack);
+                    return;
+                }
+                Element::Rule(next) => {
+                    stack.last_mut().unwrap().2 += 1;
+                    // nothing left to return to, keeps right recursion from growing the stack
+                    if idx + 1 == seq.len() {
+                        stack.pop();
+                    }
+                    for next_alt in 0..self.rules[next].len() {
+                        let mut stack = stack.clone();
+                        stack.push((next, next_alt, 0));
+                        self.expand(stack, out);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The matcher would loop forever on left recursion, like llama.cpp it is refused.
+    fn check_left_recursion(&self) -> Result<()> {
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule, alts) in self.rules.iter().enumerate() {
+                if !nullable[rule]
+                    && alts.iter().any(|seq| {
+                        seq.iter()
+                            .all(|e| matches!(e, Element::Rule(r) if nullable[*r]))
+                    })
+                {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+        }
+        // 0: not visited, 1: on the current path, 2: done
+        fn visit(
+            grammar: &Grammar,
+            nullable: &[bool],
+            rule: usize,
+            state: &mut [u8],
+        ) -> Result<()> {
+            match state[rule] {
+                1 => bail!("rule {} is left recursive", grammar.names[rule]),
+                2 => return Ok(()),
+                _ => {}
+            }
+            state[rule] = 1;
+            for seq in &grammar.rules[rule] {
+                for element in seq {
+                    match element {
+                        Element::Rule(next) => {
+                            visit(grammar, nullable, *next, state)?;
+                            if !nullable[*next] {
+                                break;
+                            }
+                        }
+                        Element::Chars { .. } => break,
+                    }
+                }
+            }
+            state[rule] = 2;
+            Ok(())
+        }
+        let mut state = vec![0; self.rules.len()];
+        for rule in 0..self.rules.len() {
+            visit(self, &nullable, rule, &mut state)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+}
+
+impl Builder {
+    fn id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.rules.len();
+        self.rules.push(None);
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    fn define(&mut self, id: usize, alts: Vec<Vec<Element>>) {
+        self.rules[id] = Some(alts);
+    }
+
+    /// A new rule for a group or a repetition, named after the rule it is part of.
+    fn fresh_id(&mut self, hint: &str) -> usize {
+        let mut n = self.rules.len();
+        while self.ids.contains_key(&format!("{hint}_{n}")) {
+            n += 1;
+        }
+        self.id(&format!("{hint}_{n}"))
+    }
+
+    fn add_rule(&mut self, hint: &str, alts: Vec<Vec<Element>>) -> usize {
+        let id = self.fresh_id(hint);
+        self.define(id, alts);
+        id
+    }
+
+    /// `item` at least `min` and at most `max` times, unbounded for None.
+    fn repeat(
+        &mut self,
+        hint: &str,
+        item: Vec<Element>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Vec<Element> {
+        let mut seq = Vec::new();
+        for _ in 0..min {
+            seq.extend(item.iter().cloned());
+        }
+        match max {
+            None => {
+                let id = self.fresh_id(hint);
+                let mut repeated = item;
+                repeated.push(Element::Rule(id));
+                self.define(id, vec![repeated, vec![]]);
+                seq.push(Element::Rule(id));
+            }
+            Some(max) => {
+                let mut tail = Vec::new();
+                for _ in min..max {
+                    let mut optional = item.clone();
+                    optional.extend(tail);
+                    tail = vec![Element::Rule(self.add_rule(hint, vec![optional, vec![]]))];
+                }
+                seq.extend(tail);
+            }
+        }
+        seq
+    }
+
+    fn hir(&mut self, hir: &Hir) -> Result<Vec<Element>> {
+        Ok(match hir.kind() {
+            HirKind::Empty => Vec::new(),
+            HirKind::Look(look) => bail!("the regex assertion {look:?} is not supported"),
+            HirKind::Literal(literal) => String::from_utf8(literal.0.to_vec())
+                .context("the regex has to match utf-8")?
+                .chars()
+                .map(Element::char)
+                .collect(),
+            HirKind::Class(Class::Unicode(class)) => vec![Element::chars(
+                class
+                    .ranges()
+                    .iter()
+                    .map(|r| (r.start(), r.end()))
+                    .collect(),
+                false,
+            )],
+            HirKind::Class(Class::Bytes(class)) => vec![Element::chars(
+                class
+                    .ranges()
+                    .iter()
+                    .map(|r| (r.start() as char, r.end() as char))
+                    .collect(),
+                false,
+            )],
+            HirKind::Repetition(repetition) => {
+                let item = self.hir(&repetition.sub)?;
+                let max = repetition.max.map(|max| max as usize);
+                self.repeat("root", item, repetition.min as usize, max)
+            }
+            HirKind::Capture(capture) => self.hir(&capture.sub)?,
+            HirKind::Concat(hirs) => {
+                let mut seq = Vec::new();
+                for hir in hirs {
+                    seq.extend(self.hir(hir)?);
+                }
+                seq
+            }
+            HirKind::Alternation(hirs) => {
+                let alts = hirs
+                    .iter()
+                    .map(|hir| self.hir(hir))
+                    .collect::<Result<_>>()?;
+                vec![Element::Rule(self.add_rule("root", alts))]
+            }
+        })
+    }
+
+    fn build(self, root: &str) -> Result<Grammar> {
+        let root = *self
+            .ids
+            .get(root)
+            .with_context(|| format!("the grammar has no {root} rule"))?;
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for (rule, name) in self.rules.into_iter().zip(&self.names) {
+            rules.push(rule.with_context(|| format!("rule {name} is not defined"))?);
+        }
+        let grammar = Grammar {
+            rules,
+            names: self.names,
+            root,
+        };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+}
+
+struct Parser<'a> {
+    src: Vec<char>,
+    pos: usize,
+    builder: &'a mut Builder,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let c = self.peek().context("unexpected end of the grammar")?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for expected in s.chars() {
+            let c = self.next()?;
+            if c != expected {
+                bail!("expected {s} at char {}, got {c}", self.pos - 1)
+            }
+        }
+        Ok(())
+    }
+
+    /// Spaces and comments, newlines only inside groups and after `::=` or `|`.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if newlines => self.pos += 1,
+                '#' => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn is_name_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '-' || c == '_'
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(Self::is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            bail!("expected a rule name at char {start}")
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn parse(&mut self) -> Result<()> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let name = self.name()?;
+            self.skip_space(false);
+            self.expect("::=")?;
+            self.skip_space(true);
+            let alts = self.alternates(&name, false)?;
+            let id = self.builder.id(&name);
+            if self.builder.rules[id].is_some() {
+                bail!("rule {name} is defined twice")
+            }
+            self.builder.define(id, alts);
+            match self.peek() {
+                None | Some('\n') => {}
+                Some(c) => bail!("unexpected {c} at char {}", self.pos),
+            }
+        }
+    }
+
+    fn alternates(&mut self, rule: &str, nested: bool) -> Result<Vec<Vec<Element>>> {
+        let mut alts = vec![self.sequence(rule, nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alts.push(self.sequence(rule, nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self, rule: &str, nested: bool) -> Result<Vec<Element>> {
+        let mut seq = Vec::new();
+        loop {
+            self.skip_space(nested);
+            let item = match self.peek() {
+                None | Some('|') | Some(')') | Some('\n') => return Ok(seq),
+                Some('"') => {
+                    self.pos += 1;
+                    let mut item = Vec::new();
+                    while self.peek() != Some('"') {
+                        item.push(Element::char(self.char()?));
+                    }
+                    self.pos += 1;
+                    item
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    while self.peek() != Some(']') {
+                        let start = self.char()?;
+                        let end = if self.peek() == Some('-')
+                            && self.src.get(self.pos + 1) != Some(&']')
+                        {
+                            self.pos += 1;
+                            self.char()?
+                        } else {
+                            start
+                        };
+                        ranges.push((start, end));
+                    }
+                    self.pos += 1;
+                    vec![Element::chars(ranges, negated)]
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    vec![Element::chars(Vec::new(), true)]
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alts = self.alternates(rule, true)?;
+                    self.expect(")")?;
+                    vec![Element::Rule(self.builder.add_rule(rule, alts))]
+                }
+                Some(c) if Self::is_name_char(c) => {
+                    let name = self.name()?;
+                    vec![Element::Rule(self.builder.id(&name))]
+                }
+                Some(c) => bail!("unexpected {c} at char {}", self.pos),
+            };
+            let item = match self.peek() {
+                Some('*') => {
+                    self.pos += 1;
+                    self.builder.repeat(rule, item, 0, None)
+                }
+                Some('+') => {
+                    self.pos += 1;
+                    self.builder.repeat(rule, item, 1, None)
+                }
+                Some('?') => {
+                    self.pos += 1;
+                    self.builder.repeat(rule, item, 0, Some(1))
+                }
+                Some('{') => {
+                    self.pos += 1;
+                    let (min, max) = self.bounds()?;
+                    self.builder.repeat(rule, item, min, max)
+                }
+                _ => item,
+            };
+            seq.extend(item);
+        }
+    }
+
+    /// `{m}`, `{m,}` or `{m,n}`, after the `{`.
+    fn bounds(&mut self) -> Result<(usize, Option<usize>)> {
+        let number = |parser: &mut Self| -> Result<Option<usize>> {
+            let start = parser.pos;
+            while parser.peek().is_some_and(|c| c.is_ascii_digit()) {
+                parser.pos += 1;
+            }
+            if start == parser.pos {
+                return Ok(None);
+            }
+            let digits: String = parser.src[start..parser.pos].iter().collect();
+            Ok(Some(digits.parse()?))
+        };
+        let min = number(self)?.context("expected the minimum of a repetition")?;
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            number(self)?
+        } else {
+            Some(min)
+        };
+        self.expect("}")?;
+        if max.is_some_and(|max| max < min) {
+            bail!("the repetition {{{min},{max:?}}} is empty")
+        }
+        Ok((min, max))
+    }
+
+    /// A char of a literal or a class, with escapes.
+    fn char(&mut self) -> Result<char> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let hex = |parser: &mut Self, len: usize| -> Result<char> {
+            let digits: String = (0..len).map(|_| parser.next()).collect::<Result<_>>()?;
+            char::from_u32(u32::from_str_radix(&digits, 16)?).context("invalid escape")
+        };
+        Ok(match self.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'x' => hex(self, 2)?,
+            'u' => hex(self, 4)?,
+            'U' => hex(self, 8)?,
+            c => c,
+        })
+    }
+}
+
+const JSON_RULES: &str = r#"
+ws ::= | " " | "\n" [ \t]{0,20}
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws
+array ::= "[" ws ( value ( "," ws value )* )? "]" ws
+string ::= "\"" char* "\"" ws
+char ::= [^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} )
+integer-part ::= "-"? ( [0-9] | [1-9] [0-9]{1,15} )
+integer ::= integer-part ws
+number ::= integer-part ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )? ws
+boolean ::= ( "true" | "false" ) ws
+null ::= "null" ws
+"#;
+
+/// Convert a JSON schema to a GBNF grammar. The properties come in the order of the schema, the
+/// ones not listed in `required` may be left out. Supported: `type` (or a list of types),
+/// `properties`, `required`, `items`, `minItems`, `maxItems`, `minLength`, `maxLength`, `enum`,
+/// `const`, `anyOf`, `oneOf` and local `$ref`s.
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String> {
+    let mut converter = SchemaConverter {
+        root: schema,
+        rules: Vec::new(),
+        refs: HashMap::new(),
+        n: 0,
+    };
+    let root = converter.visit(schema, "root")?;
+    let mut gbnf = format!("root ::= {root}\n");
+    for (name, rule) in &converter.rules {
+        gbnf.push_str(&format!("{name} ::= {rule}\n"));
+    }
+    gbnf.push_str(JSON_RULES);
+    Ok(gbnf)
+}
+
+fn gbnf_literal(s: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+struct SchemaConverter<'a> {
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    refs: HashMap<String, String>,
+    n: usize,
+}
+
+impl SchemaConverter<'_> {
+    fn add_rule(&mut self, hint: &str, rule: String) -> String {
+        let hint: String = hint
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        self.n += 1;
+        let name = format!("{hint}-{}", self.n);
+        self.rules.push((name.clone(), rule));
+        name
+    }
+
+    /// A GBNF expression matching `schema`, followed by whitespace.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let Some(object) = schema.as_object() else {
+            return match schema {
+                Value::Bool(true) => Ok("value".to_string()),
+                _ => bail!("unsupported schema {schema}"),
+            };
+        };
+        if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+            return self.reference(reference);
+        }
+        if let Some(value) = object.get("const") {
+            return Ok(format!("{} ws", gbnf_literal(&value.to_string())));
+        }
+        if let Some(values) = object.get("enum") {
+            let values = values.as_array().context("enum has to be an array")?;
+            let alts: Vec<_> = values
+                .iter()
+                .map(|value| gbnf_literal(&value.to_string()))
+                .collect();
+            return Ok(format!("( {} ) ws", alts.join(" | ")));
+        }
+        if let Some(schemas) = object.get("anyOf").or_else(|| object.get("oneOf")) {
+            let schemas = schemas.as_array().context("anyOf has to be an array")?;
+            return self.alternatives(schemas.iter(), name);
+        }
+        match object.get("type") {
+            Some(Value::String(ty)) => self.typed(object, ty, name),
+            Some(Value::Array(types)) => {
+                let alts = types
+                    .iter()
+                    .map(|ty| {
+                        let ty = ty.as_str().context("type has to be a string")?;
+                        self.typed(object, ty, name)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("( {} )", alts.join(" | ")))
+            }
+            Some(ty) => bail!("unsupported type {ty}"),
+            None if object.contains_key("properties") => self.typed(object, "object", name),
+            None => Ok("value".to_string()),
+        }
+    }
+
+    fn alternatives<'b>(
+        &mut self,
+        schemas: impl Iterator<Item = &'b Value>,
+        name: &str,
+    ) -> Result<String> {
+        let alts = schemas
+            .map(|schema| self.visit(schema, name))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(format!("( {} )", alts.join(" | ")))
+    }
+
+    fn reference(&mut self, reference: &str) -> Result<String> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .with_context(|| format!("cannot resolve $ref {reference}"))?;
+        // the name is known before the visit so that the definition can refer to itself
+        self.n += 1;
+        let hint = reference.rsplit('/').next().unwrap_or("ref");
+        let hint: String = hint
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let name = format!("{hint}-{}", self.n);
+        self.refs.insert(reference.to_string(), name.clone());
+        let rule = self.visit(target, &name)?;
+        self.rules.push((name.clone(), rule));
+        Ok(name)
+    }
+
+    fn typed(
+        &mut self,
+        object: &serde_json::Map<String, Value>,
+        ty: &str,
+        name: &str,
+    ) -> Result<String> {
+        let usize_field = |key: &str| object.get(key).and_then(Value::as_u64).map(|v| v as usize);
+        Ok(match ty {
+            "string" => {
+                let min = usize_field("minLength");
+                let max = usize_field("maxLength");
+                if min.is_none() && max.is_none() {
+                    "string".to_string()
+                } else {
+                    let max = max.map(|max| max.to_string()).unwrap_or_default();
+                    format!("\"\\\"\" char{{{},{max}}} \"\\\"\" ws", min.unwrap_or(0))
+                }
+            }
+            "number" => "number".to_string(),
+            "integer" => "integer".to_string(),
+            "boolean" => "boolean".to_string(),
+            "null" => "null".to_string(),
+            "array" => {
+                let item = match object.get("items") {
+                    Some(items) => self.visit(items, &format!("{name}-item"))?,
+                    None => "value".to_string(),
+                };
+                let min = usize_field("minItems").unwrap_or(0);
+                let max = usize_field("maxItems");
+                let rest = |n: usize| n.saturating_sub(1);
+                let items = match (min, max) {
+                    (_, Some(0)) => String::new(),
+                    (0, max) => format!(
+                        "( {item} ( \",\" ws {item} ){{0,{}}} )?",
+                        max.map(|max| rest(max).to_string()).unwrap_or_default()
+                    ),
+                    (min, max) => format!(
+                        "{item} ( \",\" ws {item} ){{{},{}}}",
+                        rest(min),
+                        max.map(|max| rest(max).to_string()).unwrap_or_default()
+                    ),
+                };
+                format!("\"[\" ws {items} \"]\" ws")
+            }
+            "object" => match object.get("properties").and_then(Value::as_object) {
+                Some(properties) => {
+                    let required: HashSet<&str> = object
+                        .get("required")
+                        .and_then(Value::as_array)
+                        .map(|required| required.iter().filter_map(Value::as_str).collect())
+                        .unwrap_or_default();
+                    let mut kvs = Vec::new();
+                    for (key, schema) in properties {
+                        let value = self.visit(schema, &format!("{name}-{key}"))?;
+                        let key_literal = gbnf_literal(&Value::String(key.clone()).to_string());
+                        let kv = self.add_rule(
+                            &format!("{name}-{key}-kv"),
+                            format!("{key_literal} ws \":\" ws {value}"),
+                        );
+                        kvs.push((kv, required.contains(key.as_str())));
+                    }
+                    // rest(i): the properties from i on, after at least one property
+                    // start(i): the properties from i on, nothing written yet
+                    let mut rest = String::new();
+                    let mut start = String::new();
+                    for (kv, required) in kvs.iter().rev() {
+                        let next_rest = if *required {
+                            format!("\",\" ws {kv} {rest}")
+                        } else {
+                            format!("( \",\" ws {kv} )? {rest}")
+                        };
+                        let next_start = if *required {
+                            format!("{kv} {rest}")
+                        } else if start.is_empty() {
+                            format!("( {kv} {rest} )?")
+                        } else {
+                            format!("( {kv} {rest} | {start} )")
+                        };
+                        rest = self.add_rule(&format!("{name}-rest"), next_rest);
+                        start = self.add_rule(&format!("{name}-start"), next_start);
+                    }
+                    format!("\"{{\" ws {start} \"}}\" ws")
+                }
+                None => "object".to_string(),
+            },
+            _ => bail!("unsupported type {ty}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn prefix_state(grammar: &Grammar, s: &str) -> Vec<Stack> {
+        let mut stacks = grammar.initial_stacks();
+        for c in s.chars() {
+            stacks = grammar.advance(&stacks, c);
+        }
+        stacks
+    }
+
+    fn matches(grammar: &Grammar, s: &str) -> bool {
+        Grammar::is_accepting(&prefix_state(grammar, s))
+    }
+
+    fn is_prefix(grammar: &Grammar, s: &str) -> bool {
+        !prefix_state(grammar, s).is_empty()
+    }
+
+    #[test]
+    fn test_gbnf() {
+        let grammar = Grammar::parse(
+            r#"
+# a comment
+root ::= "a" digit+ ( "x" | "yz" )? [^a-c\n]{1,2}
+digit ::= [0-9]
+"#,
+        )
+        .unwrap();
+        assert!(matches(&grammar, "a12yzd"));
+        assert!(matches(&grammar, "a1dd"));
+        assert!(is_prefix(&grammar, "a12y"));
+        assert!(!matches(&grammar, "a1"));
+        assert!(!matches(&grammar, "a12xb"));
+        assert!(!matches(&grammar, "a1ddd"));
+        assert!(!is_prefix(&grammar, "b"));
+
+        assert!(Grammar::parse("root ::= root \"a\" | \"b\"").is_err());
+        assert!(Grammar::parse("root ::= x? root \"a\"\nx ::= \"x\"").is_err());
+        assert!(Grammar::parse("root ::= undefined").is_err());
+        assert!(Grammar::parse("start ::= \"a\"").is_err());
+        // right recursion is fine
+        let grammar = Grammar::parse("root ::= \"a\" root | \"b\"").unwrap();
+        assert!(matches(&grammar, "aaab"));
+    }
+
+    #[test]
+    fn test_regex() {
+        let grammar = Grammar::from_regex(r"^([a-c]{2}\d+|none)é?$").unwrap();
+        assert!(matches(&grammar, "ab12"));
+        assert!(matches(&grammar, "none"));
+        assert!(matches(&grammar, "cc0é"));
+        assert!(!matches(&grammar, "ab"));
+        assert!(!matches(&grammar, "abc1"));
+        assert!(is_prefix(&grammar, "no"));
+        // the assertions that cannot be checked one char at a time
+        assert!(Grammar::from_regex(r"a\bb").is_err());
+        assert!(Grammar::from_regex(r"a$b").is_err());
+        assert!(Grammar::from_regex(r"(?m)^a").is_err());
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "maxLength": 5},
+                "age": {"type": "integer"},
+                "color": {"enum": ["red", "green"]},
+                "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}, "maxItems": 2}
+            },
+            "required": ["name"],
+            "$defs": {"tag": {"type": ["string", "null"]}}
+        });
+        let grammar = Grammar::from_json_schema(&schema).unwrap();
+        assert!(matches(&grammar, r#"{"name": "bob"}"#));
+        assert!(matches(
+            &grammar,
+            r#"{"name":"bob","age":-3,"color":"red","tags":["a", null]}"#
+        ));
+        assert!(matches(&grammar, "{\n  \"name\": \"bob\", \"tags\": []\n}"));
+        assert!(!matches(&grammar, r#"{"age": 3}"#));
+        assert!(!matches(&grammar, r#"{"age": 3, "name": "bob"}"#));
+        assert!(!matches(&grammar, r#"{"name": "bobby!"}"#));
+        assert!(!matches(&grammar, r#"{"name": "bob", "color": "blue"}"#));
+        assert!(!matches(&grammar, r#"{"name": "bob", "tags": [1]}"#));
+        assert!(!matches(
+            &grammar,
+            r#"{"name": "bob", "tags": ["a", "b", "c"]}"#
+        ));
+
+        // only optional properties, any json without a schema
+        let grammar = Grammar::from_json_schema(&json!({
+            "properties": {"a": {"type": "boolean"}, "b": {"type": "number"}}
+        }))
+        .unwrap();
+        assert!(matches(&grammar, "{}"));
+        assert!(matches(&grammar, r#"{"b": 1.5e3}"#));
+        assert!(matches(&grammar, r#"{"a": true, "b": 0}"#));
+        assert!(!matches(&grammar, r#"{"b": 1, "a": true}"#));
+        let grammar = Grammar::from_json_schema(&json!({})).unwrap();
+        assert!(matches(&grammar, r#"[{"x": [1, "é"]}, null]"#));
+    }
+}
@@ -0,0 +1,200 @@
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+
+use crate::cache::Cache;
+use crate::config::LLaVAConfig;
+use crate::llama::Llama;
+use crate::mistral::Mistral;
+use crate::quantized::Quantization;
+
+/// The decoder LLaVA feeds the merged text and image embeddings into.
+/// Implement it to plug another backbone into `LLaVA`.
+pub trait LanguageModel: Send + Sync {
+    /// Token ids to embeddings, (seq_len) -> (seq_len, hidden_size).
+    fn embed(&self, input_ids: &Tensor) -> Result<Tensor>;
+
+    /// Run the decoder on (1, seq_len, hidden_size) embeddings starting at `index_pos`,
+    /// returns the f32 logits of the last position.
+    fn forward_input_embed(
+        &self,
+        input_embed: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor>;
+
+    /// Same as `forward_input_embed`, but returns the f32 logits of every position,
+    /// (1, seq_len, vocab_size).
+    fn forward_input_embed_all(
+        &self,
+        input_embed: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor>;
+
+    /// A fresh cache matching the layers, rotary embedding, dtype and device of the model.
+    fn create_cache(&self, use_kv_cache: bool) -> Result<Cache>;
+
+    fn vocab_size(&self) -> usize;
+
+    fn hidden_size(&self) -> usize;
+
+    /// The dtype of the embeddings, the inputs of `forward` have to be in it.
+    fn dtype(&self) -> DType;
+
+    fn device(&self) -> &Device;
+
+    /// The f32 logits after `token_id` at position `pos`, `cache` holds the `pos` positions
+    /// before it. Only the new token is embedded and run, its keys and values are written in
+    /// place into the cache.
+    fn decode_step(&self, token_id: u32, pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let input_embed = self
+            .embed(&Tensor::new(&[token_id], self.device())?)?
+            .unsqueeze(0)?;
+        self.forward_input_embed(&input_embed, pos, cache)
+    }
+}
+
+/// Pick the backbone from the architectures/model_type of the config.
+pub fn load_language_model(
+    vb: VarBuilder,
+    config: &LLaVAConfig,
+    quantization: &Quantization,
+) -> Result<Box<dyn LanguageModel>> {
+    let llama_config = config.to_llama_config();
+    if config.is_mistral() {
+        Ok(Box::new(Mistral::load(
+            vb,
+            &llama_config,
+            config.sliding_window,
+            config.max_seq_len(),
+            quantization,
+        )?))
+    } else {
+        Ok(Box::new(Llama::load(
+            vb,
+            &llama_config,
+            config.max_seq_len(),
+            quantization,
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mistral::tests::randomize;
+    use candle_core::{DType, Device};
+    use candle_nn::VarMap;
+    use candle_transformers::models::llama::LlamaConfig;
+
+    fn tiny_llama() -> Llama {
+        let config: LlamaConfig = serde_json::from_str(
+            r#"{"hidden_size": 16, "intermediate_size": 32, "vocab_size": 10, "num_hidden_layers": 2,
+                "num_attention_heads": 4, "num_key_value_heads": 2, "rms_norm_eps": 1e-5,
+                "max_position_embeddings": 64}"#,
+        )
+        .unwrap();
+        let vb = VarBuilder::zeros(DType::F32, &Device::Cpu);
+        Llama::load(vb, &config.into_config(false), 64, &Quantization::default()).unwrap()
+    }
+
+    #[test]
+    fn test_llama_as_language_model() {
+        let model: Box<dyn LanguageModel> = Box::new(tiny_llama());
+        assert_eq!(model.vocab_size(), 10);
+        assert_eq!(model.hidden_size(), 16);
+        let input_ids = Tensor::new(&[1u32, 2, 3], &Device::Cpu).unwrap();
+        let embeds = model.embed(&input_ids).unwrap();
+        assert_eq!(embeds.dims(), &[3, 16]);
+        let mut cache = model.create_cache(true).unwrap();
+        let logits = model
+            .forward_input_embed(&embeds.unsqueeze(0).unwrap(), 0, &mut cache)
+            .unwrap();
+        assert_eq!(logits.dims(), &[1, 10]);
+        assert_eq!(logits.dtype(), DType::F32);
+        assert!(cache.kvs.iter().all(|kv| kv.is_some()));
+        let mut cache = model.create_cache(true).unwrap();
+        let all_logits = model
+            .forward_input_embed_all(&embeds.unsqueeze(0).unwrap(), 0, &mut cache)
+            .unwrap();
+        assert_eq!(all_logits.dims(), &[1, 3, 10]);
+
+        // a decode step is the forward of the embedding of the token
+        let mut cache = model.create_cache(true).unwrap();
+        let prefix = embeds.narrow(0, 0, 2).unwrap().unsqueeze(0).unwrap();
+        model.forward_input_embed(&prefix, 0, &mut cache).unwrap();
+        let logits = model.decode_step(3, 2, &mut cache).unwrap();
+        assert_eq!(logits.dims(), &[1, 10]);
+        assert_eq!(cache.seq_len(), 3);
+    }
+
+    fn tiny_llava_config(architecture: &str, model_type: &str) -> LLaVAConfig {
+        serde_json::from_value(serde_json::json!({
+            "_name_or_path": "tiny",
+            "architectures": [architecture],
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+            "hidden_size": 16,
+            "image_aspect_ratio": "square",
+            "image_crop_resolution": 28,
+            "image_grid_pinpoints": [[28, 28]],
+            "image_split_resolution": 28,
+            "intermediate_size": 32,
+            "max_position_embeddings": 64,
+            "mm_hidden_size": 16,
+            "mm_projector_type": "mlp2x_gelu",
+            "mm_use_im_start_end": false,
+            "mm_vision_select_feature": "patch",
+            "mm_vision_select_layer": -2,
+            "model_type": model_type,
+            "num_attention_heads": 4,
+            "num_hidden_layers": 2,
+            "num_key_value_heads": 2,
+            "pad_token_id": 0,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "sliding_window": 2,
+            "torch_dtype": "float32",
+            "use_cache": true,
+            "vocab_size": 10,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_load_language_model_picks_mistral() {
+        let input_ids = Tensor::new(&[1u32, 5, 2, 7, 3, 9], &Device::Cpu).unwrap();
+        let logits = |model: &dyn LanguageModel| {
+            let embeds = model.embed(&input_ids).unwrap().unsqueeze(0).unwrap();
+            let mut cache = model.create_cache(false).unwrap();
+            model
+                .forward_input_embed(&embeds, 0, &mut cache)
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+        };
+        // liuhaotian and llava-hf configs
+        for (architecture, model_type) in [
+            ("LlavaMistralForCausalLM", "llava_mistral"),
+            ("MistralForCausalLM", "mistral"),
+        ] {
+            let config = tiny_llava_config(architecture, model_type);
+            assert!(config.is_mistral());
+            // the same random weights for all three, only the sliding window tells them apart
+            let varmap = VarMap::new();
+            let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+            let quantization = Quantization::default();
+            let model = load_language_model(vb.clone(), &config, &quantization).unwrap();
+            let llama_config = config.to_llama_config();
+            let mistral = Mistral::load(vb.clone(), &llama_config, Some(2), 64, &quantization);
+            let llama = Llama::load(vb, &llama_config, 64, &quantization).unwrap();
+            randomize(&varmap);
+            assert_eq!(logits(model.as_ref()), logits(&mistral.unwrap()));
+            assert_ne!(logits(model.as_ref()), logits(&llama));
+        }
+        let config = tiny_llava_config("LlavaLlamaForCausalLM", "llava_llama");
+        assert!(!config.is_mistral());
+    }
+}
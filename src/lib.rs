@@ -0,0 +1,39 @@
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+pub mod beam_search;
+pub mod bundle;
+pub mod cache;
+pub mod chat;
+pub mod clip;
+pub mod clip_image_processor;
+pub mod config;
+pub mod constants;
+pub mod constraint;
+pub mod conversation;
+pub mod device;
+pub mod grammar;
+pub mod language_model;
+pub mod llama;
+pub mod logits_processor;
+pub mod mistral;
+pub mod model;
+pub mod model_source;
+pub mod pipeline;
+pub mod prefix_cache;
+pub mod quantized;
+pub mod sampling;
+pub mod score;
+pub mod sentencepiece;
+pub mod server;
+pub mod utils;
+
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod test_support;
+
+pub use chat::ChatSession;
+pub use model_source::ModelSource;
+pub use pipeline::{GenerationOptions, GenerationOutput, LlavaPipeline, LoadOptions, TokenLogprob};
@@ -1,75 +1,23 @@
 /*
 Based on
 https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/llama.rs
-modify forward procedure to better fit LLaVA model
+modify forward procedure to better fit LLaVA model. Mistral runs the same layers with another
+attention mask, see mistral.rs.
 */
 use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
 use candle_nn::{embedding, Embedding, Module, VarBuilder};
-use candle_transformers::models::{
-    llama::Config,
-    with_tracing::{linear_no_bias as linear, Linear, RmsNorm},
-};
-use std::collections::HashMap;
+use candle_transformers::models::{llama::Config, with_tracing::RmsNorm};
 
-pub const MAX_SEQ_LEN: usize = 4096;
-
-#[derive(Debug, Clone)]
-pub struct Cache {
-    masks: HashMap<usize, Tensor>,
-    pub use_kv_cache: bool,
-    kvs: Vec<Option<(Tensor, Tensor)>>,
-    cos: Tensor,
-    sin: Tensor,
-    device: Device,
-}
-
-impl Cache {
-    pub fn new(use_kv_cache: bool, dtype: DType, config: &Config, device: &Device) -> Result<Self> {
-        // precompute freqs_cis
-        let n_elem = config.hidden_size / config.num_attention_heads;
-        let theta: Vec<_> = (0..n_elem)
-            .step_by(2)
-            .map(|i| 1f32 / config.rope_theta.powf(i as f32 / n_elem as f32))
-            .collect();
-        let theta = Tensor::new(theta.as_slice(), device)?;
-        let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
-            .to_dtype(DType::F32)?
-            .reshape((MAX_SEQ_LEN, 1))?
-            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-        // This is different from the paper, see:
-        // https://github.com/huggingface/transformers/blob/6112b1c6442aaf7affd2b0676a1cd4eee30c45cf/src/transformers/models/llama/modeling_llama.py#L112
-        let cos = idx_theta.cos()?.to_dtype(dtype)?;
-        let sin = idx_theta.sin()?.to_dtype(dtype)?;
-        Ok(Self {
-            masks: HashMap::new(),
-            use_kv_cache,
-            kvs: vec![None; config.num_hidden_layers],
-            device: device.clone(),
-            cos,
-            sin,
-        })
-    }
-
-    fn mask(&mut self, t: usize) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
-            self.masks.insert(t, mask.clone());
-            Ok(mask)
-        }
-    }
-}
+use crate::cache::Cache;
+use crate::language_model::LanguageModel;
+use crate::quantized::{linear_no_bias as linear, QLinear, Quantization};
 
 #[derive(Debug, Clone)]
 struct CausalSelfAttention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: QLinear,
+    k_proj: QLinear,
+    v_proj: QLinear,
+    o_proj: QLinear,
     num_attention_heads: usize,
     num_key_value_heads: usize,
     head_dim: usize,
@@ -91,21 +39,22 @@ fn flash_attn(
 
 #[cfg(not(feature = "flash-attn"))]
 fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
-    unimplemented!("compile with '--features flash-attn'")
+    candle_core::bail!("compile with '--features flash-attn'")
 }
 
 impl CausalSelfAttention {
     fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize, cache: &Cache) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
         let (_b_sz, _, seq_len, _hidden_size) = x.dims4()?;
-        let cos = cache.cos.narrow(0, index_pos, seq_len)?;
-        let sin = cache.sin.narrow(0, index_pos, seq_len)?;
+        let (cos, sin) = cache.cos_sin(index_pos, seq_len)?;
         candle_nn::rotary_emb::rope(x, &cos, &sin)
     }
 
+    /// `mask` is an additive (seq_len, index_pos + seq_len) mask used in place of the causal one.
     fn forward(
         &self,
         x: &Tensor,
+        mask: Option<&Tensor>,
         index_pos: usize,
         block_idx: usize,
         cache: &mut Cache,
@@ -132,29 +81,13 @@ impl CausalSelfAttention {
         let mut k = self.apply_rotary_emb(&k, index_pos, cache)?;
 
         if cache.use_kv_cache {
-            if let Some((cache_k, cache_v)) = &cache.kvs[block_idx] {
-                k = Tensor::cat(&[cache_k, &k], 2)?.contiguous()?;
-                v = Tensor::cat(&[cache_v, &v], 2)?.contiguous()?;
-                let k_seq_len = k.dims()[1];
-                if k_seq_len > MAX_SEQ_LEN {
-                    k = k
-                        .narrow(D::Minus1, k_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
-                        .contiguous()?
-                }
-                let v_seq_len = v.dims()[1];
-                if v_seq_len > 2 * MAX_SEQ_LEN {
-                    v = v
-                        .narrow(D::Minus1, v_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
-                        .contiguous()?
-                }
-            }
-            cache.kvs[block_idx] = Some((k.clone(), v.clone()))
+            (k, v) = cache.append(block_idx, index_pos, &k, &v)?;
         }
 
         let k = self.repeat_kv(k)?;
         let v = self.repeat_kv(v)?;
 
-        let y = if self.use_flash_attn {
+        let y = if self.use_flash_attn && mask.is_none() {
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
             let q = q.transpose(1, 2)?;
             let k = k.transpose(1, 2)?;
@@ -167,11 +100,13 @@ impl CausalSelfAttention {
             let k = k.to_dtype(DType::F32)?;
             let v = v.to_dtype(DType::F32)?;
             let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-            let att = if seq_len == 1 {
-                att
-            } else {
-                let mask = cache.mask(seq_len)?.broadcast_as(att.shape())?;
-                masked_fill(&att, &mask, f32::NEG_INFINITY)?
+            let att = match mask {
+                Some(mask) => att.broadcast_add(mask)?,
+                None if seq_len == 1 => att,
+                None => {
+                    let mask = cache.mask(seq_len, index_pos)?.broadcast_as(att.shape())?;
+                    masked_fill(&att, &mask, f32::NEG_INFINITY)?
+                }
             };
             let att = candle_nn::ops::softmax(&att, D::Minus1)?;
             // Convert to contiguous as matmul doesn't support strided vs for now.
@@ -189,16 +124,16 @@ impl CausalSelfAttention {
         )
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    fn load(vb: VarBuilder, cfg: &Config, quantization: &Quantization) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "attn");
         let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
         let size_in = cfg.hidden_size;
         let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
         let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = linear(size_in, size_q, vb.pp("q_proj"))?;
-        let k_proj = linear(size_in, size_kv, vb.pp("k_proj"))?;
-        let v_proj = linear(size_in, size_kv, vb.pp("v_proj"))?;
-        let o_proj = linear(size_q, size_in, vb.pp("o_proj"))?;
+        let q_proj = linear(size_in, size_q, vb.pp("q_proj"), quantization)?;
+        let k_proj = linear(size_in, size_kv, vb.pp("k_proj"), quantization)?;
+        let v_proj = linear(size_in, size_kv, vb.pp("v_proj"), quantization)?;
+        let o_proj = linear(size_q, size_in, vb.pp("o_proj"), quantization)?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -223,9 +158,9 @@ fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor>
 
 #[derive(Debug, Clone)]
 struct Mlp {
-    c_fc1: Linear,
-    c_fc2: Linear,
-    c_proj: Linear,
+    c_fc1: QLinear,
+    c_fc2: QLinear,
+    c_proj: QLinear,
     span: tracing::Span,
 }
 
@@ -236,13 +171,13 @@ impl Mlp {
         self.c_proj.forward(&x)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    fn load(vb: VarBuilder, cfg: &Config, quantization: &Quantization) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "mlp");
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
-        let c_fc1 = linear(h_size, i_size, vb.pp("gate_proj"))?;
-        let c_fc2 = linear(h_size, i_size, vb.pp("up_proj"))?;
-        let c_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
+        let c_fc1 = linear(h_size, i_size, vb.pp("gate_proj"), quantization)?;
+        let c_fc2 = linear(h_size, i_size, vb.pp("up_proj"), quantization)?;
+        let c_proj = linear(i_size, h_size, vb.pp("down_proj"), quantization)?;
         Ok(Self {
             c_fc1,
             c_fc2,
@@ -265,6 +200,7 @@ impl Block {
     fn forward(
         &self,
         x: &Tensor,
+        mask: Option<&Tensor>,
         index_pos: usize,
         block_idx: usize,
         cache: &mut Cache,
@@ -272,16 +208,16 @@ impl Block {
         let _enter = self.span.enter();
         let residual = x;
         let x = self.rms_1.forward(x)?;
-        let x = (self.attn.forward(&x, index_pos, block_idx, cache)? + residual)?;
+        let x = (self.attn.forward(&x, mask, index_pos, block_idx, cache)? + residual)?;
         let residual = &x;
         let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
         Ok(x)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    fn load(vb: VarBuilder, cfg: &Config, quantization: &Quantization) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "block");
-        let attn = CausalSelfAttention::load(vb.pp("self_attn"), cfg)?;
-        let mlp = Mlp::load(vb.pp("mlp"), cfg)?;
+        let attn = CausalSelfAttention::load(vb.pp("self_attn"), cfg, quantization)?;
+        let mlp = Mlp::load(vb.pp("mlp"), cfg, quantization)?;
         let rms_1 = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
         let rms_2 = RmsNorm::new(
             cfg.hidden_size,
@@ -303,30 +239,109 @@ pub struct Llama {
     wte: Embedding,
     blocks: Vec<Block>,
     ln_f: RmsNorm,
-    lm_head: Linear,
+    lm_head: QLinear,
+    max_seq_len: usize,
+    config: Config,
 }
 
-impl Llama {
-    pub fn embed(&self, x: &Tensor) -> Result<Tensor> {
+impl LanguageModel for Llama {
+    fn embed(&self, x: &Tensor) -> Result<Tensor> {
         self.wte.forward(x)
     }
 
-    pub fn forward_input_embed(
+    fn forward_input_embed(
         &self,
         input_embed: &Tensor,
         index_pos: usize,
         cache: &mut Cache,
     ) -> Result<Tensor> {
-        let (_, seq_len, _) = input_embed.dims3()?;
+        self.forward_with_mask(input_embed, None, index_pos, cache)
+    }
+
+    fn forward_input_embed_all(
+        &self,
+        input_embed: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        self.forward_all_with_mask(input_embed, None, index_pos, cache)
+    }
+
+    fn create_cache(&self, use_kv_cache: bool) -> Result<Cache> {
+        let embeddings = self.wte.embeddings();
+        Cache::new(
+            use_kv_cache,
+            embeddings.dtype(),
+            self.config.num_hidden_layers,
+            self.config.hidden_size / self.config.num_attention_heads,
+            self.config.rope_theta,
+            self.max_seq_len,
+            embeddings.device(),
+        )
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.config.vocab_size
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.config.hidden_size
+    }
+
+    fn dtype(&self) -> DType {
+        self.wte.embeddings().dtype()
+    }
+
+    fn device(&self) -> &Device {
+        self.wte.embeddings().device()
+    }
+}
+
+impl Llama {
+    /// The normed output of the last block, (1, seq_len, hidden_size). `mask` is an additive
+    /// (seq_len, index_pos + seq_len) mask in place of the causal one.
+    fn hidden_states(
+        &self,
+        input_embed: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
         let mut x = input_embed.clone();
         for (block_idx, block) in self.blocks.iter().enumerate() {
-            x = block.forward(&x, index_pos, block_idx, cache)?;
+            x = block.forward(&x, mask, index_pos, block_idx, cache)?;
         }
-        let x = self.ln_f.forward(&x)?;
+        self.ln_f.forward(&x)
+    }
+
+    /// `forward_input_embed` with an additive attention mask in place of the causal one.
+    pub(crate) fn forward_with_mask(
+        &self,
+        input_embed: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let (_, seq_len, _) = input_embed.dims3()?;
+        let x = self.hidden_states(input_embed, mask, index_pos, cache)?;
         let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
         let logits = self.lm_head.forward(&x)?;
         logits.to_dtype(DType::F32)
     }
+
+    /// `forward_input_embed_all` with an additive attention mask in place of the causal one.
+    pub(crate) fn forward_all_with_mask(
+        &self,
+        input_embed: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let x = self.hidden_states(input_embed, mask, index_pos, cache)?;
+        let logits = self.lm_head.forward(&x)?;
+        logits.to_dtype(DType::F32)
+    }
+
     /*
     pub fn forward(&self, x: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
@@ -341,19 +356,31 @@ impl Llama {
     }
     */
 
-    pub fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        max_seq_len: usize,
+        quantization: &Quantization,
+    ) -> Result<Self> {
         let wte = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("model.embed_tokens"))?;
-        let lm_head = linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
+        let lm_head = linear(
+            cfg.hidden_size,
+            cfg.vocab_size,
+            vb.pp("lm_head"),
+            quantization,
+        )?;
         let ln_f = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?;
-        let blocks: Vec<_> = (0..cfg.num_hidden_layers)
-            .map(|i| Block::load(vb.pp(&format!("model.layers.{i}")), cfg).unwrap())
-            .collect();
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| Block::load(vb.pp(format!("model.layers.{i}")), cfg, quantization))
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(Self {
             wte,
             blocks,
             ln_f,
             lm_head,
+            max_seq_len,
+            config: cfg.clone(),
         })
     }
 }
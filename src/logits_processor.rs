@@ -0,0 +1,166 @@
+/*
+Rules that change the `[vocab]` logits of LLaVA::forward before a token is sampled. They run in
+order, the penalties of GenerationOptions first, then GenerationOptions::logits_processors.
+*/
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use candle_core::Result;
+
+pub trait LogitsProcessor: Debug + Send + Sync {
+    /// Change `logits` in place, `tokens` are the tokens generated so far.
+    fn process(&self, logits: &mut [f32], tokens: &[u32]) -> Result<()>;
+}
+
+/// These tokens are never sampled.
+#[derive(Debug, Clone)]
+pub struct TokenBan(pub Vec<u32>);
+
+impl LogitsProcessor for TokenBan {
+    fn process(&self, logits: &mut [f32], _tokens: &[u32]) -> Result<()> {
+        for token in &self.0 {
+            if let Some(logit) = logits.get_mut(*token as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Added to the logits of the tokens, like the OpenAI `logit_bias`.
+#[derive(Debug, Clone)]
+pub struct LogitBias(pub HashMap<u32, f32>);
+
+impl LogitsProcessor for LogitBias {
+    fn process(&self, logits: &mut [f32], _tokens: &[u32]) -> Result<()> {
+        for (token, bias) in &self.0 {
+            if let Some(logit) = logits.get_mut(*token as usize) {
+                *logit += bias;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The answer starts with these tokens, e.g. to prime a json object.
+#[derive(Debug, Clone)]
+pub struct ForcedTokens(pub Vec<u32>);
+
+impl LogitsProcessor for ForcedTokens {
+    fn process(&self, logits: &mut [f32], tokens: &[u32]) -> Result<()> {
+        if let Some(forced) = self.0.get(tokens.len()) {
+            logits.iter_mut().for_each(|l| *l = f32::NEG_INFINITY);
+            if let Some(logit) = logits.get_mut(*forced as usize) {
+                *logit = 0.;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The repetition penalty of the python transformers (divides positive logits, multiplies
+/// negative ones) then the presence and frequency penalties of OpenAI, all on the last
+/// `last_n` tokens. As in transformers the repetition penalty also applies to the text tokens of
+/// the prompt, the presence and frequency penalties only count the generated tokens.
+#[derive(Debug, Clone)]
+pub struct Penalties {
+    pub repeat_penalty: f32,
+    pub last_n: usize,
+    pub presence_penalty: f32,
+    pub frequency_penalty: f32,
+    /// The text tokens of the prompt, the window continues into them before the generated ones.
+    pub prompt_tokens: Vec<u32>,
+}
+
+impl Penalties {
+    pub fn is_noop(&self) -> bool {
+        self.repeat_penalty == 1. && self.presence_penalty == 0. && self.frequency_penalty == 0.
+    }
+}
+
+impl LogitsProcessor for Penalties {
+    fn process(&self, logits: &mut [f32], tokens: &[u32]) -> Result<()> {
+        let prompt_len = self.prompt_tokens.len();
+        let start = (prompt_len + tokens.len()).saturating_sub(self.last_n);
+        // the occurrences among the generated tokens, 0 for a token only in the prompt
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        let window = self
+            .prompt_tokens
+            .iter()
+            .chain(tokens)
+            .enumerate()
+            .skip(start);
+        for (i, token) in window {
+            *counts.entry(*token).or_default() += usize::from(i >= prompt_len);
+        }
+        for (token, count) in counts {
+            let Some(logit) = logits.get_mut(token as usize) else {
+                continue;
+            };
+            if self.repeat_penalty != 1. {
+                if *logit >= 0. {
+                    *logit /= self.repeat_penalty
+                } else {
+                    *logit *= self.repeat_penalty
+                }
+            }
+            if count > 0 {
+                *logit -= self.presence_penalty + self.frequency_penalty * count as f32;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_penalties() {
+        let penalties = Penalties {
+            repeat_penalty: 2.,
+            last_n: 3,
+            presence_penalty: 0.5,
+            frequency_penalty: 0.25,
+            prompt_tokens: Vec::new(),
+        };
+        let mut logits = [1f32, -1., 1., 1.];
+        // token 3 is out of the window
+        penalties.process(&mut logits, &[3, 0, 1, 1]).unwrap();
+        assert_eq!(logits, [1. / 2. - 0.75, -2. - 1., 1., 1.]);
+    }
+
+    #[test]
+    fn test_penalties_see_the_prompt() {
+        let penalties = Penalties {
+            repeat_penalty: 2.,
+            last_n: 4,
+            presence_penalty: 0.5,
+            frequency_penalty: 0.25,
+            prompt_tokens: vec![3, 2, 0],
+        };
+        let mut logits = [1f32, -1., 1., 1.];
+        // token 3 is out of the window, 2 is only in the prompt, 0 in both
+        penalties.process(&mut logits, &[1, 0]).unwrap();
+        assert_eq!(logits, [1. / 2. - 0.75, -2. - 0.75, 1. / 2., 1.]);
+    }
+
+    #[test]
+    fn test_ban_bias_and_forced_tokens() {
+        let mut logits = [1f32, 2., 3.];
+        TokenBan(vec![2, 7]).process(&mut logits, &[]).unwrap();
+        LogitBias(HashMap::from([(0, 1.5)]))
+            .process(&mut logits, &[])
+            .unwrap();
+        assert_eq!(logits, [2.5, 2., f32::NEG_INFINITY]);
+
+        let forced = ForcedTokens(vec![1, 2]);
+        let mut logits = [1f32, 2., 3.];
+        forced.process(&mut logits, &[5]).unwrap();
+        assert_eq!(logits, [f32::NEG_INFINITY, f32::NEG_INFINITY, 0.]);
+        let mut logits = [1f32, 2., 3.];
+        forced.process(&mut logits, &[1, 2]).unwrap();
+        assert_eq!(logits, [1., 2., 3.]);
+    }
+}
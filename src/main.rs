@@ -1,53 +1,119 @@
-mod clip;
-mod clip_image_processor;
-mod config;
-mod constants;
-mod conversation;
-mod llama;
-mod model;
-mod utils;
-use candle_transformers::generation::{LogitsProcessor, Sampling};
-use config::{HFGenerationConfig, HFLLaVAConfig, HFPreProcessorConfig};
-use constants::*;
-use utils::{process_image, tokenizer_image_token};
-
-use crate::llama::Cache;
-use crate::{
-    config::LLaVAConfig, conversation::Conversation, model::LLaVA, utils::get_model_name_from_path,
-};
-use anyhow::{bail, Error as E, Result};
-use candle_core::{DType, IndexOp, Tensor};
-use candle_nn::VarBuilder;
-use clap::Parser;
-use clip_image_processor::CLIPImageProcessor;
-use hf_hub::api::sync::Api;
-use std::io::Write;
-use std::process::Command;
-use tokenizers::Tokenizer;
+use anyhow::Result;
+use candle_core::quantized::GgmlDType;
+use candle_core::DType;
+use candle_llava::beam_search::BeamSearchOptions;
+use candle_llava::bundle::{parse_ggml_dtype, quantize_checkpoint, QuantizeOptions};
+use candle_llava::grammar::Grammar;
+use candle_llava::logits_processor::{LogitBias, LogitsProcessor};
+use candle_llava::pipeline::parse_dtype;
+use candle_llava::sampling;
+use candle_llava::{ChatSession, GenerationOptions, LlavaPipeline, LoadOptions, ModelSource};
+use clap::{Parser, Subcommand};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about,long_about=None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// A hub repo id or a local directory.
     #[arg(long, default_value = "liuhaotian/llava-v1.6-vicuna-7b")]
     model_path: String,
     #[arg(long)]
     model_base: Option<String>,
-    #[arg(long, default_value = "images/llava_logo.png")]
-    image_file: String, // Required
+    /// Repeat the flag or separate the files by ',' for several images, one per
+    /// `<image-placeholder>` in the prompt. Defaults to images/llava_logo.png outside of chat.
+    #[arg(long, value_delimiter = ',')]
+    image_file: Vec<String>,
+    /// A name of python LLaVA `conv_templates`, guessed from the model path by default.
     #[arg(long)]
     conv_mode: Option<String>,
-    #[arg(long, default_value_t = 0.2)]
-    temperature: f32,
+    /// 0 is greedy. Defaults to generation_config.json of the model, else 0.2.
+    #[arg(long)]
+    temperature: Option<f64>,
+    /// Only sample among the k most likely tokens.
+    #[arg(long)]
+    top_k: Option<usize>,
+    /// Nucleus sampling probability cutoff.
+    #[arg(long)]
+    top_p: Option<f64>,
+    /// Drop the tokens less likely than min-p times the most likely one.
+    #[arg(long)]
+    min_p: Option<f64>,
+    /// Penalty on the repeated tokens, 1 means no penalty.
+    #[arg(long)]
+    repeat_penalty: Option<f32>,
+    /// The number of last tokens the penalties look at, the prompt text included.
+    #[arg(long, default_value_t = 64)]
+    repeat_last_n: usize,
+    #[arg(long, default_value_t = 0.)]
+    presence_penalty: f32,
+    #[arg(long, default_value_t = 0.)]
+    frequency_penalty: f32,
+    /// `<token id>=<bias>` added to the logits of the token, repeat the flag for several tokens.
+    #[arg(long, value_parser = parse_logit_bias)]
+    logit_bias: Vec<(u32, f32)>,
+    /// The answer has to match this regex.
+    #[arg(long, group = "constraint")]
+    regex: Option<String>,
+    /// A GBNF grammar file the answer has to match, starting at its `root` rule.
+    #[arg(long, group = "constraint")]
+    grammar: Option<String>,
+    /// A JSON schema file the answer has to follow.
+    #[arg(long, group = "constraint")]
+    json_schema: Option<String>,
     #[arg(long, default_value_t = 512)]
     max_new_tokens: usize,
+    /// Print the answer as json, with the log probability of each token and of the N most
+    /// likely alternatives.
+    #[arg(long, value_name = "N")]
+    logprobs: Option<usize>,
+    /// Beam search with this many beams instead of sampling, for a single prompt.
+    #[arg(long, default_value_t = 1)]
+    num_beams: usize,
+    /// Beam scores are divided by length^length-penalty.
+    #[arg(long, default_value_t = 1.)]
+    length_penalty: f64,
+    /// Stop the beam search as soon as num-beams answers are finished.
     #[arg(long, action)]
-    load_8bit: bool, // now useless
+    early_stopping: bool,
+    /// Print the n best answers of the beam search with their scores.
+    #[arg(long, default_value_t = 1)]
+    num_return_sequences: usize,
+    /// Quantize the language model to Q8_0 as it is loaded.
+    #[arg(long, action, conflicts_with = "load_4bit")]
+    load_8bit: bool,
+    /// Quantize the language model to Q4K as it is loaded.
     #[arg(long, action)]
-    load_4bit: bool, //now useless
-    #[arg(long, action)]
-    debug: bool, // now useless
+    load_4bit: bool,
+    /// Read the weights from a gguf file with the tensor names of the checkpoint, the config and
+    /// tokenizer still come from --model-path.
+    #[arg(long)]
+    gguf: Option<String>,
+    /// cpu, cuda, cuda:N or metal. Defaults to the first CUDA device, else Metal, else the CPU.
+    #[arg(long)]
+    device: Option<String>,
+    /// f32, f16 or bf16, the `torch_dtype` of the config by default.
+    #[arg(long, value_parser = parse_dtype)]
+    dtype: Option<DType>,
+    /// The dtype of the vision tower and projector, --dtype by default.
+    #[arg(long, value_parser = parse_dtype)]
+    vision_dtype: Option<DType>,
+    /// The most positions of prompt and answer the kv cache holds, max_position_embeddings of the
+    /// config by default.
+    #[arg(long)]
+    max_seq_len: Option<usize>,
+    /// Needs a build with `--features flash-attn`.
     #[arg(long, action)]
-    cpu: bool,
+    use_flash_attn: bool,
+    /// Keep the kv cache of past prompts up to this many MiB in batch and serve, so that a prompt
+    /// sharing its start with one of them, e.g. the system prompt and image, only prefills the
+    /// rest. 0 turns it off.
+    #[arg(long, default_value_t = 1024)]
+    prefix_cache_mb: usize,
     #[arg(long, action)]
     no_kv_cache: bool,
     #[arg(long, default_value = "Is this a cat?")]
@@ -57,200 +123,330 @@ struct Args {
     seed: u64,
 }
 
-//from https://github.com/huggingface/candle/blob/main/candle-examples/examples/clip/main.rs
-fn load_image<T: AsRef<std::path::Path>>(
-    path: T,
-    processor: &CLIPImageProcessor,
-    llava_config: &LLaVAConfig,
-    dtype: DType,
-) -> anyhow::Result<((u32, u32), Tensor)> {
-    let img = image::io::Reader::open(path)?.decode()?;
-    let img_tensor = process_image(&img, processor, llava_config)?;
-    Ok(((img.width(), img.height()), img_tensor.to_dtype(dtype)?))
-}
-
-fn get_model_name(path: &str) -> String {
-    path.split('/').last().unwrap().to_string()
+fn parse_logit_bias(s: &str) -> Result<(u32, f32)> {
+    let (token, bias) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("expected <token id>=<bias>"))?;
+    Ok((token.trim().parse()?, bias.trim().parse()?))
 }
 
-fn main() -> Result<()> {
-    let mut args = Args::parse();
-    let device = candle_examples::device(args.cpu)?;
-    let api = Api::new()?;
-    let api = api.model(args.model_path.clone());
-    let model_name = get_model_name(&args.model_path);
-
-    let (llava_config, tokenizer, clip_vision_config, image_processor) = if model_name
-        .contains("hf")
-    {
-        let config_filename = api.get("config.json")?;
-        let hf_llava_config: HFLLaVAConfig =
-            serde_json::from_slice(&std::fs::read(config_filename)?)?;
-        let generation_config_filename = api.get("generation_config.json")?;
-        let generation_config: HFGenerationConfig =
-            serde_json::from_slice(&std::fs::read(generation_config_filename)?)?;
-        let preprocessor_config_filename = api.get("preprocessor_config.json")?;
-        let preprocessor_config: HFPreProcessorConfig =
-            serde_json::from_slice(&std::fs::read(preprocessor_config_filename)?)?;
-        let llava_config =
-            hf_llava_config.to_llava_config(&model_name, &generation_config, &preprocessor_config);
-        let tokenizer_filename = api.get("tokenizer.json")?;
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-        let clip_vision_config = hf_llava_config.to_clip_vision_config();
-        (
-            llava_config,
-            tokenizer,
-            Some(clip_vision_config),
-            preprocessor_config.to_clip_image_processor(),
-        )
+fn grammar(args: &Args) -> Result<Option<Grammar>> {
+    Ok(if let Some(regex) = &args.regex {
+        Some(Grammar::from_regex(regex)?)
+    } else if let Some(grammar) = &args.grammar {
+        Some(Grammar::parse(&std::fs::read_to_string(grammar)?)?)
+    } else if let Some(json_schema) = &args.json_schema {
+        let schema = serde_json::from_slice(&std::fs::read(json_schema)?)?;
+        Some(Grammar::from_json_schema(&schema)?)
     } else {
-        let config_filename = api.get("config.json")?;
-        let llava_config: LLaVAConfig = serde_json::from_slice(&std::fs::read(config_filename)?)?;
-        println!(
-            "use python to generate tokenizer.json. Will save tokenizer to tokenizer/tokenizer.json"
-        );
-        let cmd = format!("python -c \"from transformers import AutoTokenizer;tokenizer=AutoTokenizer.from_pretrained('{}');tokenizer.save_pretrained('tokenizer')\"", args.model_path);
-        let output = Command::new("python")
-            .args(["-c", &cmd])
-            .output()
-            .expect("python error!");
-        println!("python output: {:?}", output);
-        println!("loading tokenizer from tokenizer/tokenizer.json");
-        let tokenizer = Tokenizer::from_file("tokenizer/tokenizer.json").map_err(E::msg)?;
-        (
-            llava_config.clone(),
-            tokenizer,
-            None,
-            CLIPImageProcessor::from_pretrained(&llava_config.mm_vision_tower.unwrap())?,
-        )
-    };
-
-    let llama_config = llava_config.to_llama_config();
-    let dtype: DType = match llava_config.torch_dtype.as_str() {
-        "float16" => DType::F16,
-        "bfloat16" => DType::BF16,
-        _ => bail!("unsupported dtype"),
-    };
+        None
+    })
+}
 
-    let eos_token_id = llava_config.eos_token_id;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Multi-turn chat on stdin, the images given by --image-file go with the first message.
+    Chat,
+    /// An OpenAI compatible server with /v1/chat/completions and /v1/models.
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+    },
+    /// Answer the prompts of a jsonl file, one `{"prompt": ..., "images": [...]}` per line, the
+    /// images default to --image-file. Prints a json answer per line.
+    Batch { file: PathBuf },
+    /// Write the checkpoint of --model-path to a single gguf file, with the language model
+    /// quantized. Pass the file as --model-path to load it.
+    Quantize {
+        #[arg(long)]
+        output: PathBuf,
+        /// The type of the language model weights, e.g. q4k, q5k, q8_0.
+        #[arg(long, default_value = "q4k", value_parser = parse_ggml_dtype)]
+        dtype: GgmlDType,
+        /// The type of the vision tower and projector weights.
+        #[arg(long, default_value = "f16", value_parser = parse_ggml_dtype)]
+        vision_dtype: GgmlDType,
+    },
+}
 
-    println!("setting kv cache");
-    let mut cache = Cache::new(!args.no_kv_cache, dtype, &llama_config, &device)?;
+const CHAT_HELP: &str = "commands: /image <path> attaches an image to the next message, /reset, /system <text>, /save <path>, /save-session <path>, /load-session <path>, /help, /exit";
 
-    println!("loading model weights");
+fn chat(pipeline: &LlavaPipeline, args: &Args, options: &GenerationOptions) -> Result<()> {
+    let mut session = ChatSession::new(pipeline)?;
+    let mut image_files = Vec::new();
+    for image_file in &args.image_file {
+        session.attach_image(pipeline, &image::io::Reader::open(image_file)?.decode()?)?;
+        image_files.push(image_file.clone());
+    }
+    println!("{CHAT_HELP}");
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        print!("{}: ", session.conv.roles[0].trim());
+        std::io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let line = line.trim();
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command {
+            "" => continue,
+            "/exit" | "/quit" => break,
+            "/help" => println!("{CHAT_HELP}"),
+            "/image" => match image::io::Reader::open(rest)
+                .map_err(anyhow::Error::from)
+                .and_then(|reader| Ok(reader.decode()?))
+                .and_then(|image| session.attach_image(pipeline, &image))
+            {
+                Ok(()) => {
+                    image_files.push(rest.to_string());
+                    println!("attached {rest}");
+                }
+                Err(e) => println!("cannot attach {rest}: {e}"),
+            },
+            "/reset" => {
+                session.reset();
+                image_files.clear();
+                println!("conversation cleared");
+            }
+            "/system" => {
+                session.set_system(rest);
+                println!("system prompt set");
+            }
+            "/save" => {
+                let conversation = serde_json::json!({
+                    "conv_mode": pipeline.conv_mode,
+                    "conversation": session.conv,
+                    "images": image_files,
+                });
+                match std::fs::write(rest, serde_json::to_string_pretty(&conversation)?) {
+                    Ok(()) => println!("saved to {rest}"),
+                    Err(e) => println!("cannot save to {rest}: {e}"),
+                }
+            }
+            "/save-session" => match session.save(pipeline, rest) {
+                Ok(()) => println!("session saved to {rest}"),
+                Err(e) => println!("cannot save the session to {rest}: {e}"),
+            },
+            "/load-session" => match ChatSession::load(pipeline, rest) {
+                Ok(loaded) => {
+                    session = loaded;
+                    image_files.clear();
+                    println!("session loaded from {rest}");
+                }
+                Err(e) => println!("cannot load the session from {rest}: {e}"),
+            },
+            _ if command.starts_with('/') => println!("unknown command {command}, {CHAT_HELP}"),
+            _ => {
+                print!("{}: ", session.conv.roles[1].trim());
+                let result = session.send_with_callback(pipeline, line, options, |t| {
+                    print!("{t}");
+                    std::io::stdout().flush()?;
+                    Ok(())
+                });
+                println!();
+                if let Err(e) = result {
+                    println!("error: {e}");
+                }
+            }
+        }
+    }
+    Ok(())
+}
 
-    let weight_filenames =
-        candle_examples::hub_load_safetensors(&api, "model.safetensors.index.json")?;
-    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&weight_filenames, dtype, &device)? };
-    let llava: LLaVA = LLaVA::load(vb, &llava_config, clip_vision_config)?;
+fn batch(
+    pipeline: &mut LlavaPipeline,
+    file: &PathBuf,
+    args: &Args,
+    options: &GenerationOptions,
+) -> Result<()> {
+    let mut images = HashMap::new();
+    for line in std::fs::read_to_string(file)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: serde_json::Value = serde_json::from_str(line)?;
+        let prompt = request["prompt"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("no prompt in {line}"))?;
+        let image_files = match request["images"].as_array() {
+            Some(image_files) => image_files
+                .iter()
+                .map(|image_file| {
+                    image_file
+                        .as_str()
+                        .map(str::to_string)
+                        .ok_or_else(|| anyhow::anyhow!("the images are paths, got {image_file}"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => args.image_file.clone(),
+        };
+        let mut prompt_images = Vec::new();
+        for image_file in image_files {
+            if !images.contains_key(&image_file) {
+                let image = image::io::Reader::open(&image_file)?.decode()?;
+                images.insert(image_file.clone(), image);
+            }
+            prompt_images.push(images[&image_file].clone());
+        }
+        let answer = match pipeline.generate(prompt, &prompt_images, options) {
+            Ok(output) => serde_json::json!({
+                "prompt": prompt,
+                "text": output.text.trim(),
+                "prompt_tokens": output.prompt_len,
+                "cached_tokens": output.cached_len,
+            }),
+            Err(e) => serde_json::json!({"prompt": prompt, "error": e.to_string()}),
+        };
+        println!("{answer}");
+    }
+    Ok(())
+}
 
-    println!("generating conv template");
-    let image_token_se = format!(
-        "{}{}{}",
-        DEFAULT_IM_START_TOKEN, DEFAULT_IMAGE_TOKEN, DEFAULT_IM_END_TOKEN
-    );
-    let qs = if args.prompt.contains(IMAGE_PLACEHOLDER) {
-        if llava_config.mm_use_im_start_end {
-            args.prompt.replace(IMAGE_PLACEHOLDER, &image_token_se)
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let source = ModelSource::from_model_path(&args.model_path)?;
+    if let Some(Command::Quantize {
+        output,
+        dtype,
+        vision_dtype,
+    }) = &args.command
+    {
+        let options = QuantizeOptions {
+            dtype: *dtype,
+            vision_dtype: *vision_dtype,
+        };
+        return quantize_checkpoint(&source, output, &options);
+    }
+    let device = candle_llava::device::device(args.device.as_deref())?;
+    let load_options = LoadOptions {
+        use_kv_cache: !args.no_kv_cache,
+        quantize: if args.load_8bit {
+            Some(GgmlDType::Q8_0)
+        } else if args.load_4bit {
+            Some(GgmlDType::Q4K)
         } else {
-            args.prompt.replace(IMAGE_PLACEHOLDER, DEFAULT_IMAGE_TOKEN)
-        }
-    } else if llava_config.mm_use_im_start_end {
-        format!("{}\n{}", image_token_se, args.prompt)
-    } else {
-        format!("{}\n{}", DEFAULT_IMAGE_TOKEN, args.prompt)
+            None
+        },
+        gguf: args.gguf.as_ref().map(PathBuf::from),
+        use_flash_attn: args.use_flash_attn,
+        dtype: args.dtype,
+        vision_dtype: args.vision_dtype,
+        max_seq_len: args.max_seq_len,
+        prefix_cache_size: (args.prefix_cache_mb > 0).then_some(args.prefix_cache_mb << 20),
     };
+    let mut pipeline = LlavaPipeline::load_with_options(&source, &device, &load_options)?;
+    if let Some(conv_mode) = &args.conv_mode {
+        pipeline.set_conv_mode(conv_mode)?;
+    }
 
-    let model_name = get_model_name_from_path(&args.model_path).to_lowercase();
-    let conv_mode = if model_name.contains("llama-2") {
-        "llava_llama_2"
-    } else if model_name.contains("mistral") {
-        "mistral_instruct"
-    } else if model_name.contains("v1.6-34b") {
-        "chatml_direct"
-    } else if model_name.contains("v1") {
-        "llava_v1"
-    } else if model_name.contains("mpt") {
-        "mpt"
-    } else {
-        "llava_v0"
+    let defaults = pipeline.default_options();
+    let mut logits_processors: Vec<Arc<dyn LogitsProcessor>> = Vec::new();
+    if !args.logit_bias.is_empty() {
+        logits_processors.push(Arc::new(LogitBias(
+            args.logit_bias.iter().copied().collect(),
+        )));
+    }
+    if let Some(grammar) = grammar(&args)? {
+        logits_processors.push(pipeline.grammar_constraint(grammar)?);
+    }
+    let options = GenerationOptions {
+        temperature: args.temperature.unwrap_or(defaults.temperature),
+        top_k: args.top_k.or(defaults.top_k),
+        top_p: args.top_p.or(defaults.top_p),
+        min_p: args.min_p.or(defaults.min_p),
+        repeat_penalty: args.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+        repeat_last_n: args.repeat_last_n,
+        presence_penalty: args.presence_penalty,
+        frequency_penalty: args.frequency_penalty,
+        logits_processors,
+        max_new_tokens: args.max_new_tokens,
+        seed: args.seed,
+        logprobs: args.logprobs,
     };
-    if args.conv_mode.is_some() && args.conv_mode.as_deref() != Some(conv_mode) {
-        println!(
-            "Warning: the model is trained with {}, but you are using {}",
-            conv_mode,
-            args.conv_mode.as_deref().unwrap()
+    if args.num_beams > 1 && args.command.is_some() {
+        anyhow::bail!("--num-beams is only supported for a single prompt");
+    }
+    if args.num_beams > 1
+        && (args.temperature.is_some()
+            || args.top_k.is_some()
+            || args.top_p.is_some()
+            || args.min_p.is_some())
+    {
+        anyhow::bail!("beam search does not sample, --temperature, --top-k, --top-p and --min-p do not apply to --num-beams");
+    }
+    if args.num_return_sequences == 0 || args.num_return_sequences > args.num_beams.max(1) {
+        anyhow::bail!(
+            "--num-return-sequences has to be between 1 and --num-beams {}",
+            args.num_beams
         );
-    } else {
-        args.conv_mode = Some(conv_mode.to_string());
+    }
+    if args.logprobs.is_some() && (args.num_beams > 1 || args.command.is_some()) {
+        anyhow::bail!("--logprobs is only supported for a single sampled prompt");
+    }
+    match &args.command {
+        Some(Command::Chat) => return chat(&pipeline, &args, &options),
+        Some(Command::Batch { file }) => return batch(&mut pipeline, file, &args, &options),
+        Some(Command::Serve { addr }) => {
+            let runtime = tokio::runtime::Runtime::new()?;
+            return runtime.block_on(candle_llava::server::serve(
+                pipeline,
+                &source.model_name(),
+                options,
+                addr,
+            ));
+        }
+        Some(Command::Quantize { .. }) | None => {}
     }
 
-    let mut conv = match args.conv_mode {
-        Some(conv_mode) => match conv_mode.as_str() {
-            "chatml_direct" => Conversation::conv_chatml_direct(),
-            "llava_v1" => Conversation::conv_llava_v1(),
-            _ => todo!("not implement yet"),
-        },
-        None => bail!("conv_mode is required"),
+    eprintln!("loading image");
+    let image_files = if args.image_file.is_empty() {
+        vec!["images/llava_logo.png".to_string()]
+    } else {
+        args.image_file.clone()
     };
-    conv.append_user_message(Some(&qs));
-    conv.append_assistant_message(None);
-    let prompt = conv.get_prompt();
-    println!("loading image");
-    let (image_size, image_tensor) =
-        load_image(&args.image_file, &image_processor, &llava_config, dtype)?;
-    let image_tensor = image_tensor.to_device(&device)?;
-
-    let mut logits_processor = {
-        let temperature = f64::from(args.temperature);
-        let sampling = if temperature <= 0. {
-            Sampling::ArgMax
-        } else {
-            Sampling::All { temperature }
+    let images = image_files
+        .iter()
+        .map(|image_file| Ok(image::io::Reader::open(image_file)?.decode()?))
+        .collect::<Result<Vec<_>>>()?;
+    if args.num_beams > 1 {
+        let conv = pipeline.prompt_conversation(&args.prompt, images.len())?;
+        let beam_search_options = BeamSearchOptions {
+            num_beams: args.num_beams,
+            length_penalty: args.length_penalty,
+            early_stopping: args.early_stopping,
+            num_return_sequences: args.num_return_sequences,
+            max_new_tokens: args.max_new_tokens,
+            logits_processors: sampling::logits_processors(
+                &options,
+                &pipeline.prompt_tokens(&conv)?,
+            ),
         };
-        LogitsProcessor::from_sampling(args.seed, sampling)
-    };
-
-    // get input tokens
-    let tokens = tokenizer_image_token(
-        &prompt,
-        &tokenizer,
-        llava_config.image_token_index as i64,
-        &llava_config,
-    )?;
-    let input_embeds =
-        llava.prepare_inputs_labels_for_multimodal(&tokens, &[image_tensor], &[image_size])?;
-    //inference loop, based on https://github.com/huggingface/candle/blob/main/candle-examples/examples/llama/main.rs
-    let mut tokenizer = candle_examples::token_output_stream::TokenOutputStream::new(tokenizer);
-    let mut index_pos = 0;
-    let mut _input_embeds = input_embeds.clone();
-    for index in 0..args.max_new_tokens {
-        let (_, input_embeds_len, _) = _input_embeds.dims3()?;
-        let (context_size, context_index) = if cache.use_kv_cache && index > 0 {
-            (1, index_pos)
+        let hypotheses = pipeline.beam_search(&args.prompt, &images, &beam_search_options)?;
+        if let [hypothesis] = hypotheses.as_slice() {
+            println!("{}", hypothesis.text);
         } else {
-            (input_embeds_len, 0)
-        };
-        let input = _input_embeds.i((.., input_embeds_len.saturating_sub(context_size).., ..))?;
-        let logits = llava.forward(&input, context_index, &mut cache)?; //[1,32000]
-        let logits = logits.squeeze(0)?;
-        let (_, input_len, _) = input.dims3()?;
-        index_pos += input_len;
-        let next_token = logits_processor.sample(&logits)?;
-        let next_token_tensor = Tensor::from_vec(vec![next_token], 1, &device)?;
-        let next_embeds = llava.llama.embed(&next_token_tensor)?.unsqueeze(0)?;
-        _input_embeds = Tensor::cat(&[_input_embeds, next_embeds], 1)?;
-        if next_token == eos_token_id as u32 {
-            break;
-        }
-        if let Some(t) = tokenizer.next_token(next_token)? {
-            print!("{t}");
-            std::io::stdout().flush()?;
+            for hypothesis in hypotheses {
+                println!("[{:.4}] {}", hypothesis.score, hypothesis.text);
+            }
         }
+        return Ok(());
     }
-    if let Some(rest) = tokenizer.decode_rest().map_err(E::msg)? {
-        print!("{rest}");
+    if args.logprobs.is_some() {
+        let output = pipeline.generate(&args.prompt, &images, &options)?;
+        let output = serde_json::json!({
+            "text": output.text.trim(),
+            "tokens": output.tokens,
+            "logprobs": output.logprobs,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
     }
-
+    pipeline.generate_with_callback(&args.prompt, &images, &options, |t| {
+        print!("{t}");
+        std::io::stdout().flush()?;
+        Ok(())
+    })?;
     Ok(())
 }
@@ -0,0 +1,273 @@
+/*
+Based on
+https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/mistral.rs
+Mistral has the layers of llama, it only differs in the sliding window of its attention mask, so
+it runs the `Llama` of llama.rs with that mask.
+*/
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::llama::Config;
+
+use crate::cache::Cache;
+use crate::language_model::LanguageModel;
+use crate::llama::Llama;
+use crate::quantized::Quantization;
+
+#[derive(Debug, Clone)]
+pub struct Mistral {
+    llama: Llama,
+    sliding_window: usize,
+}
+
+impl Mistral {
+    /// Additive mask of shape (seq_len, index_pos + seq_len): a query only attends to the keys that
+    /// are neither in the future nor `sliding_window` or more positions behind, i.e. to the last
+    /// `sliding_window` keys including itself, as in the transformers implementation.
+    fn attention_mask(&self, seq_len: usize, index_pos: usize) -> Result<Option<Tensor>> {
+        let kv_len = index_pos + seq_len;
+        if seq_len == 1 && kv_len < self.sliding_window {
+            return Ok(None);
+        }
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| {
+                let query_pos = index_pos + i;
+                (0..kv_len).map(move |j| {
+                    if j > query_pos || j + self.sliding_window <= query_pos {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        Ok(Some(Tensor::from_slice(
+            &mask,
+            (seq_len, kv_len),
+            self.llama.device(),
+        )?))
+    }
+
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        sliding_window: Option<usize>,
+        max_seq_len: usize,
+        quantization: &Quantization,
+    ) -> Result<Self> {
+        Ok(Self {
+            llama: Llama::load(vb, cfg, max_seq_len, quantization)?,
+            // null in the config of Mistral-7B-Instruct-v0.2, i.e. full attention
+            sliding_window: sliding_window.unwrap_or(max_seq_len),
+        })
+    }
+}
+
+impl LanguageModel for Mistral {
+    fn embed(&self, x: &Tensor) -> Result<Tensor> {
+        self.llama.embed(x)
+    }
+
+    fn forward_input_embed(
+        &self,
+        input_embed: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let (_, seq_len, _) = input_embed.dims3()?;
+        let mask = self.attention_mask(seq_len, index_pos)?;
+        self.llama
+            .forward_with_mask(input_embed, mask.as_ref(), index_pos, cache)
+    }
+
+    fn forward_input_embed_all(
+        &self,
+        input_embed: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let (_, seq_len, _) = input_embed.dims3()?;
+        let mask = self.attention_mask(seq_len, index_pos)?;
+        self.llama
+            .forward_all_with_mask(input_embed, mask.as_ref(), index_pos, cache)
+    }
+
+    fn create_cache(&self, use_kv_cache: bool) -> Result<Cache> {
+        self.llama.create_cache(use_kv_cache)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.llama.vocab_size()
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.llama.hidden_size()
+    }
+
+    fn dtype(&self) -> DType {
+        self.llama.dtype()
+    }
+
+    fn device(&self) -> &Device {
+        self.llama.device()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+    use candle_transformers::models::llama::LlamaConfig;
+
+    const SLIDING_WINDOW: usize = 3;
+
+    /// Random values for the variables `varmap` got when a model was loaded from it, they start
+    /// at zero.
+    pub(crate) fn randomize(varmap: &VarMap) {
+        for var in varmap.all_vars() {
+            let values = Tensor::randn(0f32, 0.5, var.shape(), var.device()).unwrap();
+            var.set(&values).unwrap();
+        }
+    }
+
+    fn tiny_config() -> Config {
+        let config: LlamaConfig = serde_json::from_str(
+            r#"{"hidden_size": 16, "intermediate_size": 32, "vocab_size": 10, "num_hidden_layers": 2,
+                "num_attention_heads": 4, "num_key_value_heads": 2, "rms_norm_eps": 1e-5,
+                "max_position_embeddings": 64}"#,
+        )
+        .unwrap();
+        config.into_config(false)
+    }
+
+    fn tiny_mistral(vb: VarBuilder) -> Mistral {
+        Mistral::load(
+            vb,
+            &tiny_config(),
+            Some(SLIDING_WINDOW),
+            64,
+            &Quantization::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_attention_mask() {
+        let model = tiny_mistral(VarBuilder::zeros(DType::F32, &Device::Cpu));
+        // every query against every key of the whole sequence, then the rows of the queries
+        let kv_len = 8;
+        let full: Vec<Vec<f32>> = (0..kv_len)
+            .map(|query_pos| {
+                (0..kv_len)
+                    .map(|key_pos| {
+                        let visible = key_pos <= query_pos && query_pos - key_pos < SLIDING_WINDOW;
+                        if visible {
+                            0.
+                        } else {
+                            f32::NEG_INFINITY
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        for index_pos in 0..kv_len {
+            for seq_len in 1..=kv_len - index_pos {
+                let expected = (index_pos..index_pos + seq_len)
+                    .map(|query_pos| full[query_pos][..index_pos + seq_len].to_vec())
+                    .collect::<Vec<_>>();
+                match model.attention_mask(seq_len, index_pos).unwrap() {
+                    Some(mask) => assert_eq!(mask.to_vec2::<f32>().unwrap(), expected),
+                    None => {
+                        // only a single query that sees every key goes without a mask
+                        assert!(seq_len == 1 && index_pos + 1 < SLIDING_WINDOW);
+                        assert!(expected[0].iter().all(|m| *m == 0.));
+                    }
+                }
+            }
+        }
+        assert!(model
+            .attention_mask(1, SLIDING_WINDOW - 2)
+            .unwrap()
+            .is_none());
+        assert!(model
+            .attention_mask(1, SLIDING_WINDOW - 1)
+            .unwrap()
+            .is_some());
+        // the key exactly `SLIDING_WINDOW` positions behind is the first one out of the window
+        let mask = model
+            .attention_mask(1, SLIDING_WINDOW)
+            .unwrap()
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        assert_eq!(mask[0][0], f32::NEG_INFINITY);
+        assert!(mask[0][1..].iter().all(|m| *m == 0.));
+        assert_eq!(mask[0].len() - 1, SLIDING_WINDOW);
+    }
+
+    #[test]
+    fn test_without_window_is_llama() {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let quantization = Quantization::default();
+        let mistral = Mistral::load(vb.clone(), &tiny_config(), None, 64, &quantization).unwrap();
+        // the same variables
+        let llama = Llama::load(vb, &tiny_config(), 64, &quantization).unwrap();
+        randomize(&varmap);
+        let embeds = llama
+            .embed(&Tensor::new(&[1u32, 5, 2, 7], &Device::Cpu).unwrap())
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap();
+        let logits = |model: &dyn LanguageModel| {
+            let mut cache = model.create_cache(false).unwrap();
+            model
+                .forward_input_embed_all(&embeds, 0, &mut cache)
+                .unwrap()
+        };
+        let diff = (logits(&mistral) - logits(&llama))
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-5, "{diff}");
+    }
+
+    #[test]
+    fn test_decode_past_the_window() {
+        let varmap = VarMap::new();
+        let model = tiny_mistral(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu));
+        randomize(&varmap);
+        let ids = [1u32, 5, 2, 7, 3, 9, 4, 6];
+        let embeds = model
+            .embed(&Tensor::new(&ids, &Device::Cpu).unwrap())
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap();
+        let mut cache = model.create_cache(false).unwrap();
+        let full = model
+            .forward_input_embed_all(&embeds, 0, &mut cache)
+            .unwrap()
+            .squeeze(0)
+            .unwrap();
+
+        let mut cache = model.create_cache(true).unwrap();
+        let prompt = embeds.narrow(1, 0, 2).unwrap();
+        let mut logits = vec![model.forward_input_embed(&prompt, 0, &mut cache).unwrap()];
+        for (pos, &id) in ids.iter().enumerate().skip(2) {
+            logits.push(model.decode_step(id, pos, &mut cache).unwrap());
+        }
+        let decoded = Tensor::cat(&logits, 0).unwrap();
+        let diff = (decoded - full.narrow(0, 1, ids.len() - 1).unwrap())
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-5, "{diff}");
+    }
+}
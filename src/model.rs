@@ -1,20 +1,22 @@
+use crate::cache::Cache;
 use crate::clip::clip_vit_large_patch14_336;
-use crate::llama::Cache;
-use crate::llama::Llama;
+use crate::language_model::{load_language_model, LanguageModel};
 use crate::utils::get_anyres_image_grid_shape;
 use candle_core::bail;
+use candle_core::DType;
 use candle_core::Device;
 use candle_core::IndexOp;
 use candle_core::Result;
 use candle_core::Tensor;
 use candle_nn::Module;
-use candle_nn::{seq, Activation, Sequential, VarBuilder};
+use candle_nn::VarBuilder;
 use candle_transformers::models::clip::vision_model::ClipVisionConfig;
-use candle_transformers::models::with_tracing::linear;
+use candle_transformers::models::with_tracing::{linear, Linear};
 use regex::Regex;
 
 use crate::clip::ClipVisionTransformerWithHiddenStates;
-use crate::config::LLaVAConfig;
+use crate::config::{CheckpointFormat, LLaVAConfig};
+use crate::quantized::Quantization;
 
 fn mlp_gelu_match(mm_projector_type: &str) -> Option<usize> {
     let mlp_gelu_regex = Regex::new(r"^mlp(\d+)x_gelu$").unwrap();
@@ -52,74 +54,52 @@ fn unpad_image(tensor: &Tensor, original_size: &(u32, u32)) -> Result<Tensor> {
     }
 }
 
-pub struct IdentityMap {}
-
-impl Module for IdentityMap {
-    fn forward(&self, x: &Tensor) -> Result<Tensor> {
-        Ok(x.clone())
-    }
-}
-
+/// The projector is a stack of linear layers with gelu in between, no layers is the identity.
+/// The layers are kept in a Vec rather than a `Sequential` so that the model stays `Send`.
 pub struct MMProjector {
-    pub modules: Sequential,
+    pub layers: Vec<Linear>,
 }
 
 impl MMProjector {
     pub fn load(vb: &VarBuilder, config: &LLaVAConfig) -> Result<Self> {
-        if config.mm_projector_type == "linear" {
-            let vb_prefix = if config._name_or_path.contains("hf") {
-                "multi_modal_projector.linear_1"
-            } else {
-                "model.mm_projector.0"
-            };
-            let linear = linear(config.mm_hidden_size, config.hidden_size, vb.pp(vb_prefix))?;
-            let modules = seq().add(linear);
-            Ok(Self { modules })
+        let depth = if config.mm_projector_type == "linear" {
+            1
         } else if let Some(mlp_depth) = mlp_gelu_match(&config.mm_projector_type) {
-            let modules = if config._name_or_path.contains("hf") {
-                let mut modules = seq().add(linear(
-                    config.mm_hidden_size,
-                    config.hidden_size,
-                    vb.pp("multi_modal_projector.linear_1"),
-                )?);
-                for i in 1..mlp_depth {
-                    modules = modules.add(Activation::Gelu).add(linear(
-                        config.hidden_size,
-                        config.hidden_size,
-                        vb.pp(format!("multi_modal_projector.linear_{}", i + 1)),
-                    )?);
-                }
-                modules
-            } else {
-                let mut modules = seq().add(linear(
-                    config.mm_hidden_size,
-                    config.hidden_size,
-                    vb.pp("model.mm_projector.0"),
-                )?);
-                for i in 1..mlp_depth {
-                    modules = modules.add(Activation::Gelu).add(linear(
-                        config.hidden_size,
-                        config.hidden_size,
-                        vb.pp(format!("model.mm_projector.{}", i * 2)),
-                    )?);
-                }
-                modules
-            };
-            Ok(Self { modules })
+            mlp_depth
         } else if config.mm_projector_type == "identity" {
-            Ok(Self {
-                modules: seq().add(IdentityMap {}),
-            })
+            0
         } else {
             bail!(
                 "Unsupported MM projector type: {}",
                 config.mm_projector_type
             )
+        };
+        let mut layers = Vec::with_capacity(depth);
+        for i in 0..depth {
+            let in_dim = if i == 0 {
+                config.mm_hidden_size
+            } else {
+                config.hidden_size
+            };
+            let vb_prefix = if config.checkpoint_format == CheckpointFormat::Hf {
+                format!("multi_modal_projector.linear_{}", i + 1)
+            } else {
+                format!("model.mm_projector.{}", i * 2)
+            };
+            layers.push(linear(in_dim, config.hidden_size, vb.pp(vb_prefix))?);
         }
+        Ok(Self { layers })
     }
 
     pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
-        self.modules.forward(x)
+        let mut x = x.clone();
+        for (i, layer) in self.layers.iter().enumerate() {
+            if i > 0 {
+                x = x.gelu()?;
+            }
+            x = layer.forward(&x)?;
+        }
+        Ok(x)
     }
 }
 
@@ -176,21 +156,61 @@ pub struct LLaVA {
     pub clip_vision_tower: ClipVisionTower,
     pub image_newline: Tensor,
     pub mm_projector: MMProjector,
-    pub llama: Llama,
+    pub language_model: Box<dyn LanguageModel>,
     config: LLaVAConfig,
     device: Device,
 }
 
 impl LLaVA {
+    /// `quantization` applies to the language model, the vision tower and the projector are
+    /// loaded from `vb` as they are.
     pub fn load(
         vb: VarBuilder,
         config: &LLaVAConfig,
         clip_vision_config: Option<ClipVisionConfig>,
+        quantization: &Quantization,
+    ) -> Result<Self> {
+        let dtype = vb.dtype();
+        Self::load_with_dtypes(vb, config, clip_vision_config, quantization, dtype, dtype)
+    }
+
+    /// `load` with the vision tower and projector in `vision_dtype` and the language model in
+    /// `language_model_dtype`, whatever the dtype of `vb`.
+    pub fn load_with_dtypes(
+        vb: VarBuilder,
+        config: &LLaVAConfig,
+        clip_vision_config: Option<ClipVisionConfig>,
+        quantization: &Quantization,
+        vision_dtype: DType,
+        language_model_dtype: DType,
+    ) -> Result<Self> {
+        let language_model_vb = vb.clone().set_dtype(language_model_dtype);
+        let language_model = if config.checkpoint_format == CheckpointFormat::Hf {
+            load_language_model(language_model_vb.pp("language_model"), config, quantization)?
+        } else {
+            load_language_model(language_model_vb, config, quantization)?
+        };
+        Self::load_with_language_model(
+            vb.set_dtype(vision_dtype),
+            config,
+            clip_vision_config,
+            language_model,
+        )
+    }
+
+    /// Load the vision tower and projector around an already loaded language model. They take
+    /// the dtype of `vb`, the image features are converted to the dtype of the language model.
+    pub fn load_with_language_model(
+        vb: VarBuilder,
+        config: &LLaVAConfig,
+        clip_vision_config: Option<ClipVisionConfig>,
+        language_model: Box<dyn LanguageModel>,
     ) -> Result<Self> {
         let device = vb.device().clone();
-        let llama_config = config.to_llama_config();
         let mm_projector = MMProjector::load(&vb, config)?;
-        let (clip_vision_tower, image_newline, llama) = if config._name_or_path.contains("hf") {
+        let hidden_size = language_model.hidden_size();
+        let (clip_vision_tower, image_newline) = if config.checkpoint_format == CheckpointFormat::Hf
+        {
             (
                 ClipVisionTower::new(
                     vb.pp("vision_tower.vision_model"),
@@ -198,9 +218,9 @@ impl LLaVA {
                     &config.mm_vision_select_feature,
                     &clip_vision_config,
                 )?,
-                vb.get(&[config.hidden_size], "image_newline")?
-                    .to_device(&device)?,
-                Llama::load(vb.pp("language_model"), &llama_config)?,
+                vb.get(&[hidden_size], "image_newline")?
+                    .to_device(&device)?
+                    .to_dtype(language_model.dtype())?,
             )
         } else {
             (
@@ -210,16 +230,16 @@ impl LLaVA {
                     &config.mm_vision_select_feature,
                     &clip_vision_config,
                 )?,
-                vb.get(&[config.hidden_size], "model.image_newline")?
-                    .to_device(&device)?,
-                Llama::load(vb, &llama_config)?,
+                vb.get(&[hidden_size], "model.image_newline")?
+                    .to_device(&device)?
+                    .to_dtype(language_model.dtype())?,
             )
         };
         Ok(Self {
             clip_vision_tower,
             image_newline,
             mm_projector,
-            llama,
+            language_model,
             config: (*config).clone(),
             device,
         })
@@ -228,16 +248,31 @@ impl LLaVA {
     pub fn encode_images(&self, x: &Tensor) -> Result<Tensor> {
         let image_features = self.clip_vision_tower.forward(x)?;
         let image_features = self.mm_projector.forward(&image_features)?;
-        Ok(image_features)
+        image_features.to_dtype(self.language_model.dtype())
     }
-    // currently only for single image, 4 dim tensor
+    // one image token in input_ids per image, each image a 4 dim tensor
     pub fn prepare_inputs_labels_for_multimodal(
         &self,
         input_ids: &Tensor,
         images: &[Tensor],
         image_sizes: &[(u32, u32)],
     ) -> Result<Tensor> {
-        //TODO: process of multiple images/ new line
+        let image_features = self.encode_image_features(images, image_sizes)?;
+        // not cut to tokenizer_model_max_length, that could split an image, the generation checks
+        // the length against the context instead
+        self.embed_with_image_features(input_ids, &image_features)
+    }
+
+    /// The projected features of each image, (num_positions, hidden_size), with the
+    /// anyres patches and newlines already merged.
+    pub fn encode_image_features(
+        &self,
+        images: &[Tensor],
+        image_sizes: &[(u32, u32)],
+    ) -> Result<Vec<Tensor>> {
+        if images.is_empty() {
+            return Ok(Vec::new());
+        }
         // 576: 336(input size)/14(patch size)=24 24*24+1(class)=577 577-1=576
         let concat_images = Tensor::cat(images, 0)?;
         let image_features_together = self.encode_images(&concat_images)?;
@@ -295,7 +330,7 @@ impl LLaVA {
                         let new_image_feature_dims = new_image_feature.dims();
                         let image_new_line = self
                             .image_newline
-                            .reshape((self.config.hidden_size, 1, 1))?
+                            .reshape((self.language_model.hidden_size(), 1, 1))?
                             .broadcast_as((
                                 new_image_feature_dims[0],
                                 new_image_feature_dims[1],
@@ -329,34 +364,49 @@ impl LLaVA {
         } else {
             bail!("Unexpected mm_patch_merge_type: {mm_patch_merge_type}")
         };
+        Ok(image_features)
+    }
+
+    /// Embed `input_ids` (1, seq_len) and splice in one entry of `image_features` at each image token.
+    pub fn embed_with_image_features(
+        &self,
+        input_ids: &Tensor,
+        image_features: &[Tensor],
+    ) -> Result<Tensor> {
         // can easily be replaced by nonzero if it is implemented in candle
         let input_ids_vec = input_ids.squeeze(0)?.to_vec1::<i64>()?;
-        let mut image_indices = {
-            let mut image_indices = vec![0_i64];
-            image_indices.extend(
-                input_ids_vec
-                    .iter()
-                    .enumerate()
-                    .filter_map(|(i, x)| {
-                        if *x == self.config.image_token_index as i64 {
-                            Some(i as i64)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<i64>>(),
-            );
-            image_indices
-        };
-        if image_indices.len() == 1 {
-            //no image, only [0],
-            return self.llama.embed(input_ids);
+        let image_token_index = self.config.image_token_index as i64;
+        let num_image_tokens = input_ids_vec
+            .iter()
+            .filter(|x| **x == image_token_index)
+            .count();
+        if num_image_tokens != image_features.len() {
+            bail!(
+                "the prompt has {num_image_tokens} image tokens but {} images are given",
+                image_features.len()
+            )
+        }
+        if image_features.is_empty() {
+            return self
+                .language_model
+                .embed(&input_ids.to_device(&self.device)?);
         }
+        // start of each text chunk in the ids without image tokens, the k-th image
+        // token at position i splits the text at i - k
+        let mut image_indices = vec![0_i64];
+        image_indices.extend(
+            input_ids_vec
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| **x == image_token_index)
+                .enumerate()
+                .map(|(k, (i, _))| (i - k) as i64),
+        );
 
         let input_ids_noim = input_ids_vec
             .iter()
             .filter_map(|x| {
-                if *x != self.config.image_token_index as i64 {
+                if *x != image_token_index {
                     Some(*x)
                 } else {
                     None
@@ -366,7 +416,7 @@ impl LLaVA {
         let input_ids_noim_len = input_ids_noim.len();
         image_indices.push((input_ids_noim_len) as i64);
         let input_ids_noim = Tensor::from_vec(input_ids_noim, input_ids_noim_len, &self.device)?;
-        let cur_input_embeds = self.llama.embed(&input_ids_noim)?;
+        let cur_input_embeds = self.language_model.embed(&input_ids_noim)?;
         // can be replace by split if it is implemented in candle
         let input_embed_no_ims = {
             let mut input_embeds = Vec::new();
@@ -385,28 +435,34 @@ impl LLaVA {
         }
         cur_new_input_embeds.push(input_embed_no_ims[image_features.len()].clone());
         let new_input_embeds = Tensor::cat(&cur_new_input_embeds, 0)?;
-        //trancate
-        let new_input_embeds =
-            if let Some(tokenizer_model_max_length) = self.config.tokenizer_model_max_length {
-                let (new_input_embeds_length, _) = new_input_embeds.shape().dims2()?;
-                if new_input_embeds_length > tokenizer_model_max_length {
-                    new_input_embeds.i((..tokenizer_model_max_length, ..))?
-                } else {
-                    new_input_embeds
-                }
-            } else {
-                new_input_embeds
-            };
         new_input_embeds.unsqueeze(0)
     }
 
+    /// The logits after the last of `input_embeds` (1, seq_len, hidden_size), which follow the
+    /// first `position_id` cached positions.
     pub fn forward(
         &self,
         input_embeds: &Tensor,
         position_id: usize,
         cache: &mut Cache,
     ) -> Result<Tensor> {
-        self.llama
+        self.language_model
             .forward_input_embed(input_embeds, position_id, cache)
     }
+
+    /// The logits after `token_id` at position `pos`, `cache` holds the `pos` positions before it.
+    pub fn decode_step(&self, token_id: u32, pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        self.language_model.decode_step(token_id, pos, cache)
+    }
+
+    /// The logits of every position of `input_embeds`, (1, seq_len, vocab_size).
+    pub fn forward_all(
+        &self,
+        input_embeds: &Tensor,
+        position_id: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        self.language_model
+            .forward_input_embed_all(input_embeds, position_id, cache)
+    }
 }
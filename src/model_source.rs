@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use hf_hub::api::sync::{Api, ApiError};
+
+use crate::utils::{weights_fingerprint, TensorSample, FINGERPRINT_SAMPLE_BYTES};
+
+pub const SAFETENSORS_INDEX: &str = "model.safetensors.index.json";
+pub const SAFETENSORS_SINGLE: &str = "model.safetensors";
+
+/// Where config, tokenizer, preprocessor and weight files come from.
+#[derive(Debug, Clone)]
+pub enum ModelSource {
+    /// A repo id on the hugging face hub, e.g. "liuhaotian/llava-v1.6-vicuna-7b".
+    Hub(String),
+    /// A local directory laid out like a hub repo. Never touches the network.
+    Local(PathBuf),
+    /// An explicit list of files, looked up by file name. Never touches the network.
+    Files(Vec<PathBuf>),
+    /// A single gguf file written by `quantize`, holding the configs and the tokenizer too.
+    Gguf(PathBuf),
+}
+
+impl ModelSource {
+    /// Existing local directories and `.gguf` files are used as is. Anything else that looks like
+    /// a path, i.e. starts with `.`, `/` or `~` or has more than one separator, is an error, the
+    /// rest is treated as a hub repo id.
+    pub fn from_model_path(model_path: &str) -> Result<Self> {
+        let path = Path::new(model_path);
+        if path.is_dir() {
+            return Ok(ModelSource::Local(path.to_path_buf()));
+        }
+        let is_gguf = path.extension().is_some_and(|ext| ext == "gguf");
+        if path.is_file() && is_gguf {
+            return Ok(ModelSource::Gguf(path.to_path_buf()));
+        }
+        let looks_like_path = model_path.starts_with(['.', '/', '~'])
+            || model_path.matches(['/', '\\']).count() > 1
+            || path.is_absolute();
+        if looks_like_path || is_gguf {
+            if path.exists() {
+                bail!("{model_path} is neither a model directory nor a .gguf file")
+            }
+            bail!("{model_path} does not exist")
+        }
+        Ok(ModelSource::Hub(model_path.to_string()))
+    }
+
+    /// The path or repo id, used to guess the conv mode like python LLaVA does.
+    pub fn name(&self) -> String {
+        match self {
+            ModelSource::Hub(repo_id) => repo_id.clone(),
+            ModelSource::Local(dir) | ModelSource::Gguf(dir) => dir.to_string_lossy().to_string(),
+            ModelSource::Files(files) => files
+                .first()
+                .and_then(|f| f.parent())
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The last component of `name`, e.g. "llava-v1.6-vicuna-7b-hf".
+    pub fn model_name(&self) -> String {
+        self.name()
+            .trim_end_matches('/')
+            .split('/')
+            .next_back()
+            .unwrap()
+            .to_string()
+    }
+
+    pub fn get(&self, filename: &str) -> Result<PathBuf> {
+        match self {
+            ModelSource::Hub(repo_id) => {
+                let api = Api::new()?.model(repo_id.clone());
+                api.get(filename)
+                    .with_context(|| format!("cannot fetch {filename} from {repo_id}"))
+            }
+            ModelSource::Local(dir) => {
+                let path = dir.join(filename);
+                if !path.is_file() {
+                    bail!("{} does not exist", path.display())
+                }
+                Ok(path)
+            }
+            ModelSource::Files(files) => match files
+                .iter()
+                .find(|f| f.file_name().is_some_and(|name| name == filename))
+            {
+                Some(path) => Ok(path.clone()),
+                None => bail!("{filename} is not in the file list"),
+            },
+            ModelSource::Gguf(path) => {
+                bail!(
+                    "{filename} is not a file, it is bundled in {}",
+                    path.display()
+                )
+            }
+        }
+    }
+
+    /// Like `get`, but a missing file is `None`. Other errors, e.g. no network, are still errors.
+    pub fn get_optional(&self, filename: &str) -> Result<Option<PathBuf>> {
+        match self {
+            ModelSource::Hub(repo_id) => {
+                let api = Api::new()?.model(repo_id.clone());
+                match api.get(filename) {
+                    Ok(path) => Ok(Some(path)),
+                    Err(ApiError::RequestError(e)) if matches!(*e, ureq::Error::Status(404, _)) => {
+                        Ok(None)
+                    }
+                    Err(e) => {
+                        Err(e).with_context(|| format!("cannot fetch {filename} from {repo_id}"))
+                    }
+                }
+            }
+            ModelSource::Local(dir) => {
+                let path = dir.join(filename);
+                Ok(path.is_file().then_some(path))
+            }
+            ModelSource::Files(files) => Ok(files
+                .iter()
+                .find(|f| f.file_name().is_some_and(|name| name == filename))
+                .cloned()),
+            ModelSource::Gguf(_) => Ok(None),
+        }
+    }
+
+    /// Resolve the safetensors shards listed in `model.safetensors.index.json`,
+    /// falling back to a single `model.safetensors`.
+    pub fn weight_files(&self) -> Result<Vec<PathBuf>> {
+        if let ModelSource::Files(files) = self {
+            let weights = files
+                .iter()
+                .filter(|f| f.extension().is_some_and(|ext| ext == "safetensors"))
+                .cloned()
+                .collect::<Vec<_>>();
+            if weights.is_empty() {
+                bail!("no safetensors file in the file list")
+            }
+            return Ok(weights);
+        }
+        if let ModelSource::Gguf(path) = self {
+            bail!("{} is not a safetensors checkpoint", path.display())
+        }
+        match self.get_optional(SAFETENSORS_INDEX)? {
+            Some(index_file) => {
+                let index: serde_json::Value =
+                    serde_json::from_slice(&std::fs::read(&index_file)?)?;
+                let weight_map = match index.get("weight_map") {
+                    Some(serde_json::Value::Object(map)) => map,
+                    _ => bail!("no weight map in {}", index_file.display()),
+                };
+                let mut shards = HashSet::new();
+                for value in weight_map.values() {
+                    if let Some(file) = value.as_str() {
+                        shards.insert(file.to_string());
+                    }
+                }
+                let mut shards = shards.into_iter().collect::<Vec<_>>();
+                shards.sort();
+                shards.iter().map(|shard| self.get(shard)).collect()
+            }
+            None => Ok(vec![self.get(SAFETENSORS_SINGLE)?]),
+        }
+    }
+}
+
+/// Names of all tensors stored in the given safetensors files, read from the file headers.
+pub fn tensor_names(weight_files: &[PathBuf]) -> Result<Vec<String>> {
+    let safetensors = unsafe { candle_core::safetensors::MmapedSafetensors::multi(weight_files)? };
+    Ok(safetensors
+        .tensors()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// `weights_fingerprint` of the given safetensors files, read from the file headers and the first
+/// bytes of each tensor.
+pub fn safetensors_fingerprint(weight_files: &[PathBuf]) -> Result<String> {
+    let safetensors = unsafe { candle_core::safetensors::MmapedSafetensors::multi(weight_files)? };
+    let tensors = safetensors
+        .tensors()
+        .into_iter()
+        .map(|(name, view)| {
+            let data = view.data();
+            TensorSample {
+                name,
+                shape: view.shape().to_vec(),
+                dtype: format!("{:?}", view.dtype()),
+                data: data[..data.len().min(FINGERPRINT_SAMPLE_BYTES)].to_vec(),
+            }
+        })
+        .collect();
+    Ok(weights_fingerprint(tensors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp_model_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("candle-llava-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (filename, content) in files {
+            std::fs::write(dir.join(filename), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_local_sharded_weights() {
+        let index = r#"{"metadata": {}, "weight_map": {"a": "model-00002-of-00002.safetensors", "b": "model-00001-of-00002.safetensors", "c": "model-00001-of-00002.safetensors"}}"#;
+        let dir = tmp_model_dir(
+            "sharded",
+            &[
+                ("config.json", "{}"),
+                (SAFETENSORS_INDEX, index),
+                ("model-00001-of-00002.safetensors", ""),
+                ("model-00002-of-00002.safetensors", ""),
+            ],
+        );
+        let source = ModelSource::from_model_path(dir.to_str().unwrap()).unwrap();
+        assert!(matches!(source, ModelSource::Local(_)));
+        assert_eq!(source.get("config.json").unwrap(), dir.join("config.json"));
+        assert!(source.get("tokenizer.json").is_err());
+        assert_eq!(source.get_optional("tokenizer.json").unwrap(), None);
+        assert_eq!(
+            source.weight_files().unwrap(),
+            vec![
+                dir.join("model-00001-of-00002.safetensors"),
+                dir.join("model-00002-of-00002.safetensors")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_model_path() {
+        assert!(matches!(
+            ModelSource::from_model_path("liuhaotian/llava-v1.6-vicuna-7b").unwrap(),
+            ModelSource::Hub(_)
+        ));
+        for model_path in [
+            "./models/llava",
+            "../llava",
+            "/no/such/llava",
+            "~/models/llava",
+            "models/liuhaotian/llava-v1.6-vicuna-7b",
+            "llava-q4k.gguf",
+        ] {
+            let err = ModelSource::from_model_path(model_path).unwrap_err();
+            assert!(err.to_string().contains("does not exist"), "{err}");
+        }
+        let dir = tmp_model_dir("from-path", &[("config.json", "{}")]);
+        let err =
+            ModelSource::from_model_path(dir.join("config.json").to_str().unwrap()).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(err.to_string().contains("neither"), "{err}");
+    }
+
+    #[test]
+    fn test_local_single_weights() {
+        let dir = tmp_model_dir("single", &[(SAFETENSORS_SINGLE, "")]);
+        let source = ModelSource::Local(dir.clone());
+        assert_eq!(
+            source.weight_files().unwrap(),
+            vec![dir.join(SAFETENSORS_SINGLE)]
+        );
+    }
+
+    #[test]
+    fn test_file_list() {
+        let source = ModelSource::Files(vec![
+            PathBuf::from("/models/llava/config.json"),
+            PathBuf::from("/models/weights/a.safetensors"),
+        ]);
+        assert_eq!(
+            source.get("config.json").unwrap(),
+            PathBuf::from("/models/llava/config.json")
+        );
+        assert_eq!(source.model_name(), "llava");
+        assert_eq!(source.get_optional("tokenizer.json").unwrap(), None);
+        assert_eq!(
+            source.weight_files().unwrap(),
+            vec![PathBuf::from("/models/weights/a.safetensors")]
+        );
+    }
+}
@@ -0,0 +1,1249 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Error as E, Result};
+use candle_core::quantized::GgmlDType;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::clip::vision_model::ClipVisionConfig;
+use image::DynamicImage;
+use serde::Serialize;
+use tokenizers::Tokenizer;
+
+use crate::cache::Cache;
+use crate::clip_image_processor::CLIPImageProcessor;
+use crate::config::{
+    CheckpointFormat, HFGenerationConfig, HFLLaVAConfig, HFPreProcessorConfig, LLaVAConfig,
+};
+use crate::constants::*;
+use crate::constraint::GrammarConstraint;
+use crate::conversation::{Conversation, CONV_TEMPLATE_NAMES};
+use crate::grammar::Grammar;
+use crate::logits_processor::LogitsProcessor;
+use crate::model::LLaVA;
+use crate::model_source::{safetensors_fingerprint, tensor_names, ModelSource};
+use crate::prefix_cache::{image_hash, PrefixCache, PrefixToken};
+use crate::quantized::{GgufWeights, Quantization};
+use crate::sampling::{self, Sampler};
+use crate::sentencepiece::load_or_convert_tokenizer;
+use crate::utils::{get_model_name_from_path, process_image, tokenizer_image_token};
+
+#[derive(Debug, Clone)]
+pub struct GenerationOptions {
+    /// 0 is greedy decoding, the other sampling options only apply when sampling.
+    pub temperature: f64,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    /// Drop the tokens less likely than `min_p` times the most likely one.
+    pub min_p: Option<f64>,
+    /// 1 is no penalty, the penalties look at the last `repeat_last_n` tokens of the prompt text
+    /// and the answer, see `Penalties`.
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    pub presence_penalty: f32,
+    pub frequency_penalty: f32,
+    /// Applied after the penalties, in order.
+    pub logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    pub max_new_tokens: usize,
+    /// The seed to use when generating random samples. Copy from candle llama. Not exist in python llava.
+    pub seed: u64,
+    /// Keep the log probability of each generated token and of the n most likely alternatives.
+    pub logprobs: Option<usize>,
+}
+
+impl Default for GenerationOptions {
+    fn default() -> Self {
+        Self {
+            temperature: 0.2,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            repeat_penalty: 1.,
+            repeat_last_n: 64,
+            presence_penalty: 0.,
+            frequency_penalty: 0.,
+            logits_processors: Vec::new(),
+            max_new_tokens: 512,
+            seed: 299792458,
+            logprobs: None,
+        }
+    }
+}
+
+impl GenerationOptions {
+    /// Take the sampling options that `generation_config.json` sets, `do_sample: false` is greedy.
+    /// As in transformers, the temperature is only used with `do_sample: true`; without `do_sample`
+    /// the temperature of `self` stays.
+    pub fn with_generation_config(mut self, generation_config: &HFGenerationConfig) -> Self {
+        match (generation_config.do_sample, generation_config.temperature) {
+            (Some(true), Some(temperature)) => self.temperature = temperature,
+            (Some(false), _) => self.temperature = 0.,
+            _ => {}
+        }
+        self.top_k = generation_config.top_k.or(self.top_k);
+        self.top_p = generation_config.top_p.or(self.top_p);
+        self.min_p = generation_config.min_p.or(self.min_p);
+        if let Some(repetition_penalty) = generation_config.repetition_penalty {
+            self.repeat_penalty = repetition_penalty;
+        }
+        self
+    }
+}
+
+/// A token and its log probability under the model, before the penalties, the logits
+/// processors and the temperature.
+#[derive(Debug, Clone, Serialize)]
+pub struct TopLogprob {
+    pub token: u32,
+    pub text: String,
+    pub logprob: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub text: String,
+    pub logprob: f32,
+    /// The most likely tokens at this position, the sampled one may be among them.
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GenerationOutput {
+    pub text: String,
+    pub tokens: Vec<u32>,
+    pub prompt_len: usize,
+    /// The positions of the prompt taken from the prefix cache.
+    pub cached_len: usize,
+    /// One per token when `GenerationOptions::logprobs` is set, else empty.
+    pub logprobs: Vec<TokenLogprob>,
+}
+
+/// What `LlavaPipeline::generate_from_embeds` produced.
+#[derive(Debug, Clone)]
+pub struct Generated {
+    pub text: String,
+    pub tokens: Vec<u32>,
+    pub logprobs: Vec<TokenLogprob>,
+    /// Number of positions in the cache afterwards, the last sampled token is not in it.
+    pub index_pos: usize,
+}
+
+/// Everything a checkpoint holds besides the weights.
+pub struct ModelConfigs {
+    /// The path or repo id of the checkpoint, the conv mode is guessed from it.
+    pub name: String,
+    pub llava_config: LLaVAConfig,
+    /// None for the original checkpoints, their vision tower is CLIP ViT-L/14-336.
+    pub clip_vision_config: Option<ClipVisionConfig>,
+    pub tokenizer: Tokenizer,
+    pub image_processor: CLIPImageProcessor,
+    /// Only the llava-hf checkpoints have one.
+    pub generation_config: Option<HFGenerationConfig>,
+}
+
+impl ModelConfigs {
+    /// Read the config files of `source`, the names of the weights tell the checkpoint format.
+    pub fn from_source<S: AsRef<str>>(source: &ModelSource, tensor_names: &[S]) -> Result<Self> {
+        let model_name = source.model_name();
+        let config_json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(source.get("config.json")?)?)?;
+        let checkpoint_format = CheckpointFormat::detect(&config_json, tensor_names)?;
+
+        let (llava_config, tokenizer, clip_vision_config, image_processor, generation_config) =
+            match checkpoint_format {
+                CheckpointFormat::Hf => {
+                    let hf_llava_config: HFLLaVAConfig = serde_json::from_value(config_json)?;
+                    let generation_config_filename = source.get("generation_config.json")?;
+                    let generation_config: HFGenerationConfig =
+                        serde_json::from_slice(&std::fs::read(generation_config_filename)?)?;
+                    let preprocessor_config_filename = source.get("preprocessor_config.json")?;
+                    let preprocessor_config: HFPreProcessorConfig =
+                        serde_json::from_slice(&std::fs::read(preprocessor_config_filename)?)?;
+                    let llava_config = hf_llava_config.to_llava_config(
+                        &model_name,
+                        &generation_config,
+                        &preprocessor_config,
+                    );
+                    let tokenizer_filename = source.get("tokenizer.json")?;
+                    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+                    let clip_vision_config = hf_llava_config.to_clip_vision_config();
+                    (
+                        llava_config,
+                        tokenizer,
+                        Some(clip_vision_config),
+                        preprocessor_config.to_clip_image_processor(),
+                        Some(generation_config),
+                    )
+                }
+                CheckpointFormat::Original => {
+                    let llava_config: LLaVAConfig = serde_json::from_value(config_json)?;
+                    let tokenizer = match source.get_optional("tokenizer.json")? {
+                        Some(tokenizer_filename) => {
+                            Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?
+                        }
+                        None => load_or_convert_tokenizer(source)?,
+                    };
+                    // a local checkpoint may ship the preprocessor config of its vision tower,
+                    // the known towers need no download, the others are fetched from the hub
+                    // only when the checkpoint itself comes from there
+                    let image_processor = match source.get_optional("preprocessor_config.json")? {
+                        Some(preprocessor_config_filename) => {
+                            CLIPImageProcessor::from_file(preprocessor_config_filename)?
+                        }
+                        None => {
+                            let vision_tower = llava_config
+                                .mm_vision_tower
+                                .clone()
+                                .context("mm_vision_tower is missing in config.json")?;
+                            match CLIPImageProcessor::for_vision_tower(&vision_tower) {
+                                Some(image_processor) => image_processor,
+                                None if matches!(source, ModelSource::Hub(_))
+                                    || Path::new(&vision_tower).is_dir() =>
+                                {
+                                    CLIPImageProcessor::from_pretrained(&vision_tower)?
+                                }
+                                None => bail!(
+                                    "unknown vision tower {vision_tower} and no \
+                                     preprocessor_config.json in {}",
+                                    source.name()
+                                ),
+                            }
+                        }
+                    };
+                    (llava_config, tokenizer, None, image_processor, None)
+                }
+            };
+        Ok(Self {
+            name: source.name(),
+            llava_config,
+            clip_vision_config,
+            tokenizer,
+            image_processor,
+            generation_config,
+        })
+    }
+}
+
+/// "f32", "f16" or "bf16", or the torch names used by `torch_dtype`.
+pub fn parse_dtype(s: &str) -> Result<DType> {
+    let dtype = match s {
+        "f32" | "float32" => DType::F32,
+        "f16" | "float16" => DType::F16,
+        "bf16" | "bfloat16" => DType::BF16,
+        _ => bail!("unsupported dtype {s}, expected f32, f16 or bf16"),
+    };
+    Ok(dtype)
+}
+
+/// How `LlavaPipeline::load_with_options` reads the weights.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    pub use_kv_cache: bool,
+    /// Quantize the linear layers of the language model as they are loaded, e.g. Q4K or Q8_0.
+    pub quantize: Option<GgmlDType>,
+    /// Read the weights from this gguf file instead of the safetensors of the source, the
+    /// tensors keep the names of the checkpoint.
+    pub gguf: Option<PathBuf>,
+    /// Run the attention of the llama backbone through flash-attn, see the `flash-attn` feature.
+    pub use_flash_attn: bool,
+    /// The dtype of the language model, `torch_dtype` of the config by default.
+    pub dtype: Option<DType>,
+    /// The dtype of the vision tower and the projector, the one of the language model by default.
+    pub vision_dtype: Option<DType>,
+    /// The length of the kv cache, `max_position_embeddings` by default.
+    pub max_seq_len: Option<usize>,
+    /// Keep the keys and values of past prompts up to this many bytes, see `PrefixCache`.
+    pub prefix_cache_size: Option<usize>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            use_kv_cache: true,
+            quantize: None,
+            gguf: None,
+            use_flash_attn: false,
+            dtype: None,
+            vision_dtype: None,
+            max_seq_len: None,
+            prefix_cache_size: None,
+        }
+    }
+}
+
+pub struct LlavaPipeline {
+    pub llava: LLaVA,
+    pub tokenizer: Tokenizer,
+    pub image_processor: CLIPImageProcessor,
+    pub llava_config: LLaVAConfig,
+    pub cache: Cache,
+    pub conv_mode: String,
+    /// Only the llava-hf checkpoints have one.
+    pub generation_config: Option<HFGenerationConfig>,
+    /// Used by `generate_conversation` when the kv cache is.
+    pub prefix_cache: Option<PrefixCache>,
+    /// See `weights_fingerprint`.
+    pub(crate) weights_fingerprint: String,
+    dtype: DType,
+    vision_dtype: DType,
+    device: Device,
+}
+
+//from https://github.com/huggingface/candle/blob/main/candle-examples/examples/clip/main.rs
+pub fn load_image<T: AsRef<Path>>(
+    path: T,
+    processor: &CLIPImageProcessor,
+    llava_config: &LLaVAConfig,
+    dtype: DType,
+) -> Result<((u32, u32), Tensor)> {
+    let img = image::io::Reader::open(path)?.decode()?;
+    let img_tensor = process_image(&img, processor, llava_config)?;
+    Ok(((img.width(), img.height()), img_tensor.to_dtype(dtype)?))
+}
+
+pub fn default_conv_mode(model_path: &str) -> &'static str {
+    let model_name = get_model_name_from_path(model_path).to_lowercase();
+    if model_name.contains("llama-2") {
+        "llava_llama_2"
+    } else if model_name.contains("mistral") {
+        "mistral_instruct"
+    } else if model_name.contains("v1.6-34b") {
+        "chatml_direct"
+    } else if model_name.contains("v1") {
+        "llava_v1"
+    } else if model_name.contains("mpt") {
+        "mpt"
+    } else {
+        "llava_v0"
+    }
+}
+
+/// Each `<image-placeholder>` or `<image>` in the prompt takes one image in order,
+/// without any the image tokens are put in front of the prompt.
+pub fn insert_image_tokens(
+    prompt: &str,
+    num_images: usize,
+    mm_use_im_start_end: bool,
+) -> Result<String> {
+    let wrapped_image_token = format!(
+        "{}{}{}",
+        DEFAULT_IM_START_TOKEN, DEFAULT_IMAGE_TOKEN, DEFAULT_IM_END_TOKEN
+    );
+    let image_token = if mm_use_im_start_end {
+        wrapped_image_token.clone()
+    } else {
+        DEFAULT_IMAGE_TOKEN.to_string()
+    };
+    // every form of placeholder becomes a bare <image> first
+    let prompt = prompt
+        .replace(&wrapped_image_token, DEFAULT_IMAGE_TOKEN)
+        .replace(IMAGE_PLACEHOLDER, DEFAULT_IMAGE_TOKEN);
+    let num_placeholders = prompt.matches(DEFAULT_IMAGE_TOKEN).count();
+    if num_placeholders == 0 {
+        let mut qs = String::new();
+        for _ in 0..num_images {
+            qs.push_str(&image_token);
+            qs.push('\n');
+        }
+        qs.push_str(&prompt);
+        Ok(qs)
+    } else if num_placeholders != num_images {
+        bail!("the prompt has {num_placeholders} image placeholders but {num_images} images are given")
+    } else {
+        Ok(prompt.replace(DEFAULT_IMAGE_TOKEN, &image_token))
+    }
+}
+
+impl LlavaPipeline {
+    pub fn load(source: &ModelSource, device: &Device, use_kv_cache: bool) -> Result<Self> {
+        let options = LoadOptions {
+            use_kv_cache,
+            ..Default::default()
+        };
+        Self::load_with_options(source, device, &options)
+    }
+
+    pub fn load_with_options(
+        source: &ModelSource,
+        device: &Device,
+        options: &LoadOptions,
+    ) -> Result<Self> {
+        let (configs, gguf, weight_filenames) = match source {
+            ModelSource::Gguf(bundle_filename) => {
+                let gguf = GgufWeights::load(bundle_filename, device)?;
+                let configs = ModelConfigs::from_gguf_metadata(&gguf.metadata)?;
+                (configs, Some(gguf), Vec::new())
+            }
+            _ => {
+                let gguf = match &options.gguf {
+                    Some(gguf_filename) => Some(GgufWeights::load(gguf_filename, device)?),
+                    None => None,
+                };
+                let (weight_filenames, tensor_names) = match &gguf {
+                    Some(gguf) => (Vec::new(), gguf.tensor_names()),
+                    None => {
+                        let weight_filenames = source.weight_files()?;
+                        let tensor_names = tensor_names(&weight_filenames)?;
+                        (weight_filenames, tensor_names)
+                    }
+                };
+                let configs = ModelConfigs::from_source(source, &tensor_names)?;
+                (configs, gguf, weight_filenames)
+            }
+        };
+        let ModelConfigs {
+            name,
+            mut llava_config,
+            clip_vision_config,
+            tokenizer,
+            image_processor,
+            generation_config,
+        } = configs;
+        eprintln!("checkpoint format: {:?}", llava_config.checkpoint_format);
+
+        let dtype = match options.dtype {
+            Some(dtype) => dtype,
+            None => parse_dtype(&llava_config.torch_dtype)?,
+        };
+        let vision_dtype = options.vision_dtype.unwrap_or(dtype);
+        eprintln!("dtype: {dtype:?}, vision tower and projector: {vision_dtype:?}");
+        if options.use_flash_attn {
+            if !cfg!(feature = "flash-attn") {
+                bail!("flash attention needs a build with `--features flash-attn`")
+            }
+            if !device.is_cuda() {
+                bail!("flash attention only runs on a CUDA device")
+            }
+            llava_config.use_flash_attn = true;
+        }
+        if let Some(max_seq_len) = options.max_seq_len {
+            if max_seq_len > llava_config.max_position_embeddings {
+                eprintln!(
+                    "warning: max_seq_len {max_seq_len} is above max_position_embeddings {}",
+                    llava_config.max_position_embeddings
+                );
+            }
+            llava_config.max_seq_len = Some(max_seq_len);
+        }
+
+        let mut weights_fingerprint = match &gguf {
+            Some(gguf) => gguf.fingerprint.clone(),
+            None => safetensors_fingerprint(&weight_filenames)?,
+        };
+        if let Some(quantize) = options.quantize {
+            // quantized on load, the same files give other activations
+            weights_fingerprint = format!("{weights_fingerprint}-{quantize:?}");
+        }
+
+        eprintln!("loading model weights");
+        let vb = match &gguf {
+            Some(gguf) => gguf.var_builder(dtype, device),
+            None => unsafe {
+                VarBuilder::from_mmaped_safetensors(&weight_filenames, dtype, device)?
+            },
+        };
+        if let Some(quantize) = options.quantize {
+            eprintln!("quantizing the language model to {quantize:?}");
+        }
+        let quantization = Quantization {
+            dtype: options.quantize,
+            gguf,
+        };
+        let llava: LLaVA = LLaVA::load_with_dtypes(
+            vb,
+            &llava_config,
+            clip_vision_config,
+            &quantization,
+            vision_dtype,
+            dtype,
+        )?;
+
+        eprintln!("setting kv cache");
+        let cache = llava.language_model.create_cache(options.use_kv_cache)?;
+
+        Ok(Self {
+            llava,
+            tokenizer,
+            image_processor,
+            llava_config,
+            cache,
+            conv_mode: default_conv_mode(&name).to_string(),
+            generation_config,
+            prefix_cache: options.prefix_cache_size.map(PrefixCache::new),
+            weights_fingerprint,
+            dtype,
+            vision_dtype,
+            device: device.clone(),
+        })
+    }
+
+    /// Errors when `conv_mode` is not one of `CONV_TEMPLATE_NAMES`.
+    pub fn set_conv_mode(&mut self, conv_mode: &str) -> Result<()> {
+        if Conversation::from_template(conv_mode).is_none() {
+            bail!(
+                "unknown conv_mode {conv_mode}, expected one of {}",
+                CONV_TEMPLATE_NAMES.join(", ")
+            )
+        }
+        if conv_mode != self.conv_mode {
+            eprintln!(
+                "Warning: the model is trained with {}, but you are using {}",
+                self.conv_mode, conv_mode
+            );
+        }
+        self.conv_mode = conv_mode.to_string();
+        Ok(())
+    }
+
+    /// `GenerationOptions::default()` updated with the sampling options of `generation_config.json`.
+    pub fn default_options(&self) -> GenerationOptions {
+        match &self.generation_config {
+            Some(generation_config) => {
+                GenerationOptions::default().with_generation_config(generation_config)
+            }
+            None => GenerationOptions::default(),
+        }
+    }
+
+    /// A logits processor keeping the answer in `grammar`, to add to `GenerationOptions::logits_processors`.
+    pub fn grammar_constraint(&self, grammar: Grammar) -> Result<Arc<GrammarConstraint>> {
+        Ok(Arc::new(GrammarConstraint::new(
+            grammar,
+            &self.tokenizer,
+            self.llava_config.eos_token_id as u32,
+        )?))
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// A hash of the tensors of the checkpoint, names, shapes, dtypes and a sample of the values,
+    /// see `utils::weights_fingerprint`. Chat sessions only resume on the weights they were saved
+    /// with.
+    pub fn weights_fingerprint(&self) -> &str {
+        &self.weights_fingerprint
+    }
+
+    /// The dtype of the language model.
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    /// The dtype of the vision tower and the projector, the images are converted to it.
+    pub fn vision_dtype(&self) -> DType {
+        self.vision_dtype
+    }
+
+    pub fn conversation(&self) -> Result<Conversation> {
+        match Conversation::from_template(&self.conv_mode) {
+            Some(conv) => Ok(conv),
+            None => bail!(
+                "unknown conv_mode {}, expected one of {}",
+                self.conv_mode,
+                CONV_TEMPLATE_NAMES.join(", ")
+            ),
+        }
+    }
+
+    /// Insert the image tokens into the user query, following the python `eval_model`.
+    pub fn image_query(&self, prompt: &str, num_images: usize) -> Result<String> {
+        insert_image_tokens(prompt, num_images, self.llava_config.mm_use_im_start_end)
+    }
+
+    /// A conversation with `prompt` as the only user message, waiting for the answer.
+    pub fn prompt_conversation(&self, prompt: &str, num_images: usize) -> Result<Conversation> {
+        let qs = self.image_query(prompt, num_images)?;
+        let mut conv = self.conversation()?;
+        conv.append_user_message(Some(&qs));
+        conv.append_assistant_message(None);
+        Ok(conv)
+    }
+
+    pub fn process_image(&self, image: &DynamicImage) -> Result<((u32, u32), Tensor)> {
+        let image_tensor = process_image(image, &self.image_processor, &self.llava_config)?
+            .to_dtype(self.vision_dtype)?
+            .to_device(&self.device)?;
+        Ok(((image.width(), image.height()), image_tensor))
+    }
+
+    pub fn generate(
+        &mut self,
+        prompt: &str,
+        images: &[DynamicImage],
+        options: &GenerationOptions,
+    ) -> Result<GenerationOutput> {
+        self.generate_with_callback(prompt, images, options, |_| Ok(()))
+    }
+
+    /// Same as `generate`, `on_token` is called with each piece of decoded text as soon as it is available.
+    pub fn generate_with_callback<F>(
+        &mut self,
+        prompt: &str,
+        images: &[DynamicImage],
+        options: &GenerationOptions,
+        on_token: F,
+    ) -> Result<GenerationOutput>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let conv = self.prompt_conversation(prompt, images.len())?;
+        self.generate_conversation(&conv, images, options, on_token)
+    }
+
+    /// The input embeddings (1, seq_len, hidden_size) of the prompt of `conv` and its images.
+    pub fn conversation_embeds(
+        &self,
+        conv: &Conversation,
+        images: &[DynamicImage],
+    ) -> Result<Tensor> {
+        let prompt = conv.get_prompt();
+
+        let mut image_tensors = Vec::new();
+        let mut image_sizes = Vec::new();
+        for image in images {
+            let (image_size, image_tensor) = self.process_image(image)?;
+            image_sizes.push(image_size);
+            image_tensors.push(image_tensor);
+        }
+
+        // get input tokens
+        let tokens = tokenizer_image_token(
+            &prompt,
+            &self.tokenizer,
+            self.llava_config.image_token_index as i64,
+            &self.llava_config,
+        )?;
+        Ok(self.llava.prepare_inputs_labels_for_multimodal(
+            &tokens,
+            &image_tensors,
+            &image_sizes,
+        )?)
+    }
+
+    /// The text tokens of the prompt of `conv`, the ones the repetition penalty sees before the
+    /// answer.
+    pub fn prompt_tokens(&self, conv: &Conversation) -> Result<Vec<u32>> {
+        let input_ids = tokenizer_image_token(
+            &conv.get_prompt(),
+            &self.tokenizer,
+            self.llava_config.image_token_index as i64,
+            &self.llava_config,
+        )?
+        .squeeze(0)?
+        .to_vec1::<i64>()?;
+        Ok(self.text_tokens(&input_ids))
+    }
+
+    /// `input_ids` without the image tokens.
+    pub(crate) fn text_tokens(&self, input_ids: &[i64]) -> Vec<u32> {
+        let image_token_index = self.llava_config.image_token_index as i64;
+        input_ids
+            .iter()
+            .filter(|id| **id != image_token_index)
+            .map(|id| *id as u32)
+            .collect()
+    }
+
+    /// Answer the last message of `conv`, its prompt holds one image token per image. With a
+    /// prefix cache, only what comes after the longest cached prefix of the prompt is prefilled
+    /// and only its images are encoded.
+    pub fn generate_conversation<F>(
+        &mut self,
+        conv: &Conversation,
+        images: &[DynamicImage],
+        options: &GenerationOptions,
+        on_token: F,
+    ) -> Result<GenerationOutput>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let input_ids = tokenizer_image_token(
+            &conv.get_prompt(),
+            &self.tokenizer,
+            self.llava_config.image_token_index as i64,
+            &self.llava_config,
+        )?
+        .squeeze(0)?
+        .to_vec1::<i64>()?;
+        // taken, not cloned, so that its buffers are not shared and are written in place
+        let empty = self.cache.with_max_seq_len(0)?;
+        let mut cache = std::mem::replace(&mut self.cache, empty);
+        cache.clear();
+        let mut prefix_cache = if cache.use_kv_cache {
+            self.prefix_cache.take()
+        } else {
+            None
+        };
+        let output = self.generate_from_ids(
+            &mut cache,
+            prefix_cache.as_mut(),
+            &input_ids,
+            images,
+            options,
+            on_token,
+        );
+        self.cache = cache;
+        if prefix_cache.is_some() {
+            self.prefix_cache = prefix_cache;
+        }
+        output
+    }
+
+    fn generate_from_ids<F>(
+        &self,
+        cache: &mut Cache,
+        mut prefix_cache: Option<&mut PrefixCache>,
+        input_ids: &[i64],
+        images: &[DynamicImage],
+        options: &GenerationOptions,
+        on_token: F,
+    ) -> Result<GenerationOutput>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let image_token_index = self.llava_config.image_token_index as i64;
+        let num_images = |ids: &[i64]| ids.iter().filter(|id| **id == image_token_index).count();
+        if num_images(input_ids) != images.len() {
+            bail!(
+                "the prompt has {} image tokens but {} images are given",
+                num_images(input_ids),
+                images.len()
+            )
+        }
+        let mut image_hashes = images.iter().map(image_hash);
+        let mut key = input_ids
+            .iter()
+            .map(|id| {
+                if *id == image_token_index {
+                    PrefixToken::Image(image_hashes.next().unwrap_or_default())
+                } else {
+                    PrefixToken::Token(*id)
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut positions = match prefix_cache.as_deref_mut() {
+            Some(prefix_cache) => prefix_cache.restore(&key, cache)?,
+            None => Vec::new(),
+        };
+        let cached_len = positions.last().copied().unwrap_or(0);
+
+        // encode and embed what is not cached
+        let new_ids = &input_ids[positions.len()..];
+        let mut image_tensors = Vec::new();
+        let mut image_sizes = Vec::new();
+        for image in &images[images.len() - num_images(new_ids)..] {
+            let (image_size, image_tensor) = self.process_image(image)?;
+            image_sizes.push(image_size);
+            image_tensors.push(image_tensor);
+        }
+        let image_features = self
+            .llava
+            .encode_image_features(&image_tensors, &image_sizes)?;
+        let input_embeds = self.llava.embed_with_image_features(
+            &Tensor::new(new_ids, &Device::Cpu)?.unsqueeze(0)?,
+            &image_features,
+        )?;
+        let mut index_pos = cached_len;
+        let mut image_features = image_features.iter();
+        for id in new_ids {
+            index_pos += if *id == image_token_index {
+                match image_features.next() {
+                    Some(features) => features.dim(0)?,
+                    None => 1,
+                }
+            } else {
+                1
+            };
+            positions.push(index_pos);
+        }
+        let prompt_len = index_pos;
+
+        let generated = self.generate_from_embeds(
+            cache,
+            input_embeds,
+            cached_len,
+            &self.text_tokens(input_ids),
+            options,
+            on_token,
+        )?;
+        if let Some(prefix_cache) = prefix_cache {
+            // the last sampled token is not in the cache
+            let fed = generated.index_pos - prompt_len;
+            for token in &generated.tokens[..fed] {
+                index_pos += 1;
+                key.push(PrefixToken::Token(*token as i64));
+                positions.push(index_pos);
+            }
+            prefix_cache.insert(key, positions, cache)?;
+        }
+        Ok(GenerationOutput {
+            text: generated.text,
+            tokens: generated.tokens,
+            prompt_len,
+            cached_len,
+            logprobs: generated.logprobs,
+        })
+    }
+
+    /// Sample from `input_embeds` (1, seq_len, hidden_size), placed right after the first `index_pos`
+    /// positions of `cache`. Without kv cache `input_embeds` has to hold the whole context.
+    /// `prompt_tokens` are the text tokens of the whole prompt, for the repetition penalty.
+    pub fn generate_from_embeds<F>(
+        &self,
+        cache: &mut Cache,
+        input_embeds: Tensor,
+        index_pos: usize,
+        prompt_tokens: &[u32],
+        options: &GenerationOptions,
+        mut on_token: F,
+    ) -> Result<Generated>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let mut sampler = Sampler::new(options, prompt_tokens);
+        let eos_token_id = self.llava_config.eos_token_id as u32;
+
+        //inference loop, based on https://github.com/huggingface/candle/blob/main/candle-examples/examples/llama/main.rs
+        let mut tokenizer =
+            candle_examples::token_output_stream::TokenOutputStream::new(self.tokenizer.clone());
+        let mut generated_tokens = Vec::new();
+        let mut logprobs = Vec::new();
+        // the prompt has to fit, the answer stops when the context is full
+        let max_seq_len = cache.max_seq_len();
+        let context_len = if cache.use_kv_cache { index_pos } else { 0 } + input_embeds.dim(1)?;
+        if context_len > max_seq_len {
+            bail!("the prompt takes {context_len} positions, the model handles {max_seq_len}")
+        }
+        let max_new_tokens = options.max_new_tokens.min(max_seq_len - context_len + 1);
+        let mut index_pos = index_pos;
+        // without kv cache every step runs the whole context again, its embeddings go into a
+        // buffer allocated once
+        let mut context = if cache.use_kv_cache {
+            None
+        } else {
+            let (b_sz, seq_len, hidden_size) = input_embeds.dims3()?;
+            let buffer = Tensor::zeros(
+                (b_sz, seq_len + max_new_tokens, hidden_size),
+                input_embeds.dtype(),
+                input_embeds.device(),
+            )?;
+            buffer.slice_set(&input_embeds.contiguous()?, 1, 0)?;
+            Some((buffer, seq_len))
+        };
+        let mut last_token = None;
+        for _ in 0..max_new_tokens {
+            let logits = match (&mut context, last_token) {
+                (Some((buffer, len)), last_token) => {
+                    if let Some(token) = last_token {
+                        let embeds = self
+                            .llava
+                            .language_model
+                            .embed(&Tensor::new(&[token], &self.device)?)?
+                            .unsqueeze(0)?;
+                        buffer.slice_set(&embeds, 1, *len)?;
+                        *len += 1;
+                    }
+                    self.llava.forward(&buffer.narrow(1, 0, *len)?, 0, cache)?
+                }
+                (None, None) => {
+                    let logits = self.llava.forward(&input_embeds, index_pos, cache)?;
+                    index_pos += input_embeds.dim(1)?;
+                    logits
+                }
+                (None, Some(token)) => {
+                    let logits = self.llava.decode_step(token, index_pos, cache)?;
+                    index_pos += 1;
+                    logits
+                }
+            };
+            let logits = logits.squeeze(0)?; //[32000]
+            let next_token = sampler.sample(&logits, &generated_tokens)?;
+            if next_token == eos_token_id {
+                break;
+            }
+            if let Some(top_n) = options.logprobs {
+                logprobs.push(self.token_logprob(&logits, next_token, top_n)?);
+            }
+            generated_tokens.push(next_token);
+            if let Some(t) = tokenizer.next_token(next_token)? {
+                on_token(&t)?;
+            }
+            last_token = Some(next_token);
+        }
+        if let Some(rest) = tokenizer.decode_rest().map_err(E::msg)? {
+            on_token(&rest)?;
+        }
+        let text = tokenizer.decode_all().map_err(E::msg)?;
+        Ok(Generated {
+            text,
+            tokens: generated_tokens,
+            logprobs,
+            index_pos,
+        })
+    }
+
+    /// The log probabilities of `token` and of the `top_n` most likely tokens for the `[vocab]` logits.
+    pub fn token_logprob(&self, logits: &Tensor, token: u32, top_n: usize) -> Result<TokenLogprob> {
+        let (logprob, top) = sampling::logprobs(logits, token, top_n)?;
+        let text = |token: u32| self.tokenizer.decode(&[token], false).map_err(E::msg);
+        Ok(TokenLogprob {
+            token,
+            text: text(token)?,
+            logprob,
+            top_logprobs: top
+                .into_iter()
+                .map(|(token, logprob)| {
+                    Ok(TopLogprob {
+                        token,
+                        text: text(token)?,
+                        logprob,
+                    })
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    pub(crate) use crate::test_support::{tiny_clip_vision_config, tiny_image};
+    use crate::test_support::{tiny_image_processor, tiny_llava_config, tiny_tokenizer};
+    use crate::utils::{weights_fingerprint, TensorSample, FINGERPRINT_SAMPLE_BYTES};
+    use candle_nn::VarMap;
+    use std::collections::HashMap;
+
+    /// A llava-v1 shaped pipeline with random weights, a 28x28 clip (4 patches) and a 2 layer llama.
+    pub(crate) fn tiny_pipeline(use_kv_cache: bool) -> LlavaPipeline {
+        tiny_pipeline_with_weights(use_kv_cache).0
+    }
+
+    /// `tiny_pipeline` and its weights.
+    pub(crate) fn tiny_pipeline_with_weights(use_kv_cache: bool) -> (LlavaPipeline, VarMap) {
+        let llava_config: LLaVAConfig = serde_json::from_value(tiny_llava_config()).unwrap();
+        let image_processor: CLIPImageProcessor =
+            serde_json::from_value(tiny_image_processor()).unwrap();
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let llava = LLaVA::load(
+            vb,
+            &llava_config,
+            Some(tiny_clip_vision_config()),
+            &Quantization::default(),
+        )
+        .unwrap();
+        // VarMap initializes from the thread rng, overwrite it to get reproducible outputs
+        let data = varmap.data().lock().unwrap();
+        let mut names = data.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        let mut state = 299792458u64;
+        let mut samples = Vec::new();
+        for name in names {
+            let var = &data[&name];
+            // roughly what the usual initializers give: norms around 1, fan-in scaled matrices
+            let (scale, offset) = match var.dims() {
+                [_, .., fan_in] if var.rank() > 1 => (2. / (*fan_in as f32).sqrt(), 0.),
+                _ if name.ends_with("weight") => (0.2, 1.),
+                _ => (0.2, 0.),
+            };
+            let values = (0..var.elem_count())
+                .map(|_| {
+                    state = state
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    ((state >> 40) as f32 / (1u64 << 24) as f32 - 0.5) * scale + offset
+                })
+                .collect::<Vec<_>>();
+            samples.push(TensorSample {
+                name: name.clone(),
+                shape: var.dims().to_vec(),
+                dtype: "F32".to_string(),
+                data: values
+                    .iter()
+                    .take(FINGERPRINT_SAMPLE_BYTES / 4)
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect(),
+            });
+            var.set(&Tensor::from_vec(values, var.shape(), &device).unwrap())
+                .unwrap();
+        }
+        drop(data);
+        let cache = llava.language_model.create_cache(use_kv_cache).unwrap();
+        let pipeline = LlavaPipeline {
+            llava,
+            tokenizer: tiny_tokenizer(),
+            image_processor,
+            llava_config,
+            cache,
+            conv_mode: "llava_v1".to_string(),
+            generation_config: None,
+            prefix_cache: None,
+            weights_fingerprint: weights_fingerprint(samples),
+            dtype: DType::F32,
+            vision_dtype: DType::F32,
+            device,
+        };
+        (pipeline, varmap)
+    }
+
+    #[test]
+    fn test_generation_stops_when_the_context_is_full() {
+        let mut pipeline = tiny_pipeline(true);
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 8,
+            ..Default::default()
+        };
+        let images = [tiny_image()];
+        let unbounded = pipeline
+            .generate("is this a cat?", &images, &options)
+            .unwrap();
+        let prompt_len = unbounded.prompt_len;
+        pipeline.cache =
+            Cache::new(true, DType::F32, 2, 8, 10000., prompt_len + 2, &Device::Cpu).unwrap();
+        let output = pipeline
+            .generate("is this a cat?", &images, &options)
+            .unwrap();
+        assert_eq!(output.tokens, unbounded.tokens[..3]);
+        pipeline.cache =
+            Cache::new(true, DType::F32, 2, 8, 10000., prompt_len - 1, &Device::Cpu).unwrap();
+        assert!(pipeline
+            .generate("is this a cat?", &images, &options)
+            .is_err());
+    }
+
+    #[test]
+    fn test_mixed_precision() {
+        let (mut pipeline, varmap) = tiny_pipeline_with_weights(true);
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 4,
+            ..Default::default()
+        };
+        let images = [tiny_image()];
+        let expected = pipeline
+            .generate("is this a cat?", &images, &options)
+            .unwrap();
+
+        let tensors = varmap
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+            .collect::<HashMap<_, _>>();
+        let vb = VarBuilder::from_tensors(tensors, DType::F32, &Device::Cpu);
+        pipeline.llava = LLaVA::load_with_dtypes(
+            vb,
+            &pipeline.llava_config,
+            Some(tiny_clip_vision_config()),
+            &Quantization::default(),
+            DType::F16,
+            DType::F32,
+        )
+        .unwrap();
+        pipeline.vision_dtype = DType::F16;
+        assert_eq!(pipeline.llava.language_model.dtype(), DType::F32);
+        assert_eq!(pipeline.llava.image_newline.dtype(), DType::F32);
+        let output = pipeline
+            .generate("is this a cat?", &images, &options)
+            .unwrap();
+        assert_eq!(output.tokens, expected.tokens);
+    }
+
+    #[test]
+    fn test_generate_with_and_without_kv_cache() {
+        let mut pipeline = tiny_pipeline(true);
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 8,
+            ..Default::default()
+        };
+        let images = [tiny_image()];
+        let with_cache = pipeline
+            .generate("what color is the cat?", &images, &options)
+            .unwrap();
+        // the next request overwrites the buffers of this one
+        let again = pipeline
+            .generate("what color is the cat?", &images, &options)
+            .unwrap();
+        assert_eq!(again.tokens, with_cache.tokens);
+        pipeline.cache = pipeline.llava.language_model.create_cache(false).unwrap();
+        let without_cache = pipeline
+            .generate("what color is the cat?", &images, &options)
+            .unwrap();
+        assert_eq!(with_cache.tokens, without_cache.tokens);
+        assert_eq!(with_cache.prompt_len, without_cache.prompt_len);
+    }
+
+    #[test]
+    fn test_generate_with_grammar() {
+        let mut pipeline = tiny_pipeline(true);
+        let grammar = Grammar::from_regex("(cat|dog)(cat|dog)").unwrap();
+        let options = GenerationOptions {
+            temperature: 1.,
+            max_new_tokens: 8,
+            logits_processors: vec![pipeline.grammar_constraint(grammar).unwrap()],
+            ..Default::default()
+        };
+        let output = pipeline
+            .generate("what color is the cat?", &[tiny_image()], &options)
+            .unwrap();
+        let words: Vec<_> = output
+            .tokens
+            .iter()
+            .map(|t| pipeline.tokenizer.id_to_token(*t).unwrap())
+            .collect();
+        assert_eq!(words.len(), 2);
+        assert!(words.iter().all(|w| w == "cat" || w == "dog"));
+    }
+
+    #[test]
+    fn test_generate_with_logprobs() {
+        let mut pipeline = tiny_pipeline(true);
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 4,
+            logprobs: Some(3),
+            ..Default::default()
+        };
+        let output = pipeline
+            .generate("what color is the cat?", &[tiny_image()], &options)
+            .unwrap();
+        assert!(!output.tokens.is_empty());
+        assert_eq!(output.logprobs.len(), output.tokens.len());
+        for (token, logprob) in output.tokens.iter().zip(&output.logprobs) {
+            // greedy picks the most likely token
+            assert_eq!(logprob.token, *token);
+            assert_eq!(logprob.top_logprobs.len(), 3);
+            assert_eq!(logprob.top_logprobs[0].token, *token);
+            assert_eq!(logprob.top_logprobs[0].logprob, logprob.logprob);
+            assert!(logprob
+                .top_logprobs
+                .windows(2)
+                .all(|w| w[0].logprob >= w[1].logprob));
+        }
+    }
+
+    #[test]
+    fn test_options_with_generation_config() {
+        let generation_config: HFGenerationConfig = serde_json::from_str(
+            r#"{"bos_token_id": 1, "eos_token_id": 2, "pad_token_id": 0, "top_p": 0.6, "repetition_penalty": 1.1}"#,
+        )
+        .unwrap();
+        let options = GenerationOptions::default().with_generation_config(&generation_config);
+        assert_eq!(options.temperature, 0.2);
+        assert_eq!(options.top_p, Some(0.6));
+        assert_eq!(options.top_k, None);
+        assert_eq!(options.repeat_penalty, 1.1);
+        let generation_config = HFGenerationConfig {
+            do_sample: Some(false),
+            temperature: Some(0.9),
+            ..generation_config
+        };
+        let options = GenerationOptions::default().with_generation_config(&generation_config);
+        assert_eq!(options.temperature, 0.);
+        // without do_sample the temperature of the config is not used either, the default
+        // temperature of the options stays
+        let generation_config = HFGenerationConfig {
+            do_sample: None,
+            ..generation_config
+        };
+        let options = GenerationOptions::default().with_generation_config(&generation_config);
+        assert_eq!(options.temperature, 0.2);
+        let generation_config = HFGenerationConfig {
+            do_sample: Some(true),
+            ..generation_config
+        };
+        let options = GenerationOptions::default().with_generation_config(&generation_config);
+        assert_eq!(options.temperature, 0.9);
+    }
+
+    #[test]
+    fn test_original_checkpoint_loads_offline() {
+        let pipeline = tiny_pipeline(false);
+        let dir = std::env::temp_dir().join(format!("candle-llava-{}-offline", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = serde_json::to_value(&pipeline.llava_config).unwrap();
+        config["mm_vision_tower"] = "openai/clip-vit-large-patch14-336".into();
+        std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+        pipeline
+            .tokenizer
+            .save(dir.join("tokenizer.json"), false)
+            .unwrap();
+        // no preprocessor_config.json, like the liuhaotian checkpoints
+        let configs =
+            ModelConfigs::from_source(&ModelSource::Local(dir), &["model.mm_projector.0.weight"])
+                .unwrap();
+        assert_eq!(configs.image_processor.crop_size, 336);
+        assert!(configs.clip_vision_config.is_none());
+    }
+
+    #[test]
+    fn test_default_conv_mode() {
+        for model_path in [
+            "liuhaotian/llava-v1.6-mistral-7b",
+            "llava-hf/llava-v1.6-mistral-7b-hf",
+            "/models/llava-v1.6-mistral-7b-hf/",
+        ] {
+            assert_eq!(default_conv_mode(model_path), "mistral_instruct");
+        }
+        assert_eq!(
+            default_conv_mode("liuhaotian/llava-v1.6-vicuna-7b"),
+            "llava_v1"
+        );
+    }
+
+    #[test]
+    fn test_set_conv_mode() {
+        let mut pipeline = tiny_pipeline(false);
+        pipeline.set_conv_mode("mistral_instruct").unwrap();
+        assert_eq!(pipeline.conv_mode, "mistral_instruct");
+        let err = pipeline.set_conv_mode("llava_v7").unwrap_err();
+        assert!(err.to_string().contains("llava_v7"), "{err}");
+        assert_eq!(pipeline.conv_mode, "mistral_instruct");
+    }
+
+    #[test]
+    fn test_insert_image_tokens() {
+        assert_eq!(
+            insert_image_tokens("Is this a cat?", 1, false).unwrap(),
+            "<image>\nIs this a cat?"
+        );
+        assert_eq!(
+            insert_image_tokens("Is this a cat?", 0, false).unwrap(),
+            "Is this a cat?"
+        );
+        assert_eq!(
+            insert_image_tokens(
+                "Compare <image-placeholder> with <image-placeholder>.",
+                2,
+                true
+            )
+            .unwrap(),
+            "Compare <im_start><image><im_end> with <im_start><image><im_end>."
+        );
+        assert_eq!(
+            insert_image_tokens("<image>\nfirst, <image-placeholder> second", 2, false).unwrap(),
+            "<image>\nfirst, <image> second"
+        );
+        assert_eq!(
+            insert_image_tokens("<image>\nfirst, <image-placeholder> second", 2, true).unwrap(),
+            "<im_start><image><im_end>\nfirst, <im_start><image><im_end> second"
+        );
+        // already wrapped images are not wrapped twice, nor unwrapped
+        assert_eq!(
+            insert_image_tokens("<im_start><image><im_end>\nIs this a cat?", 1, true).unwrap(),
+            "<im_start><image><im_end>\nIs this a cat?"
+        );
+        assert_eq!(
+            insert_image_tokens("<im_start><image><im_end>\nIs this a cat?", 1, false).unwrap(),
+            "<image>\nIs this a cat?"
+        );
+        assert!(insert_image_tokens("Compare <image-placeholder>.", 2, false).is_err());
+        assert!(insert_image_tokens("<image> and <image>", 1, false).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+/*
+The keys and values of prompts already prefilled, kept so that a later prompt that starts the same
+way, e.g. with the same system prompt and image, only prefills what comes after. The entries are
+keyed by their tokens, an image by a hash of its pixels, and the least recently used ones are
+evicted to stay within a memory budget.
+*/
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use candle_core::Result;
+use image::DynamicImage;
+
+use crate::cache::{Cache, LayerKv};
+
+/// One item of a prompt, a token or a whole image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PrefixToken {
+    Token(i64),
+    /// See `image_hash`.
+    Image(u64),
+}
+
+/// A hash of the size, color type and pixels of `image`.
+pub fn image_hash(image: &DynamicImage) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (image.width(), image.height(), image.color()).hash(&mut hasher);
+    image.as_bytes().hash(&mut hasher);
+    hasher.finish()
+}
+
+struct Entry {
+    key: Vec<PrefixToken>,
+    /// The cache length right after each item of `key`.
+    positions: Vec<usize>,
+    kvs: Vec<Option<LayerKv>>,
+    size_in_bytes: usize,
+    last_used: u64,
+}
+
+pub struct PrefixCache {
+    entries: Vec<Entry>,
+    max_size_in_bytes: usize,
+    size_in_bytes: usize,
+    clock: u64,
+}
+
+impl PrefixCache {
+    /// The entries together hold at most `max_size_in_bytes` of keys and values.
+    pub fn new(max_size_in_bytes: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            max_size_in_bytes,
+            size_in_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.size_in_bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size_in_bytes = 0;
+    }
+
+    /// Put the keys and values of the longest cached prefix of `key` into `cache`, the last item
+    /// of `key` is always left out to get the logits after it. Returns the cache length after
+    /// each item of the prefix, nothing when no prefix is cached.
+    pub fn restore(&mut self, key: &[PrefixToken], cache: &mut Cache) -> Result<Vec<usize>> {
+        let best = self
+            .entries
+            .iter_mut()
+            .map(|entry| {
+                let len = common_prefix_len(&entry.key, key).min(key.len().saturating_sub(1));
+                (len, entry)
+            })
+            .max_by_key(|(len, _)| *len);
+        let (len, entry) = match best {
+            Some((len, entry)) if len > 0 => (len, entry),
+            _ => return Ok(Vec::new()),
+        };
+        self.clock += 1;
+        entry.last_used = self.clock;
+        cache.load(&entry.kvs, entry.positions[len - 1])?;
+        Ok(entry.positions[..len].to_vec())
+    }
+
+    /// Keep the keys and values of `cache` for `key`, `positions` holds the cache length after
+    /// each of its items. The least recently used entries are evicted to make room.
+    pub fn insert(
+        &mut self,
+        key: Vec<PrefixToken>,
+        positions: Vec<usize>,
+        cache: &Cache,
+    ) -> Result<()> {
+        let Some(&len) = positions.last() else {
+            return Ok(());
+        };
+        self.clock += 1;
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.key.starts_with(&key)) {
+            entry.last_used = self.clock;
+            return Ok(());
+        }
+        // the entries this one extends are of no use anymore
+        self.entries.retain(|entry| !key.starts_with(&entry.key));
+        self.size_in_bytes = self.entries.iter().map(|e| e.size_in_bytes).sum();
+
+        let mut cache = cache.clone();
+        cache.truncate(len)?;
+        let kvs = cache
+            .kvs
+            .iter()
+            .map(|kv| kv.as_ref().map(|kv| kv.compact()).transpose())
+            .collect::<Result<Vec<_>>>()?;
+        let size_in_bytes = kvs
+            .iter()
+            .flatten()
+            .map(|kv| kv.size_in_bytes())
+            .sum::<usize>();
+        if size_in_bytes > self.max_size_in_bytes {
+            return Ok(());
+        }
+        self.entries.push(Entry {
+            key,
+            positions,
+            kvs,
+            size_in_bytes,
+            last_used: self.clock,
+        });
+        self.size_in_bytes += size_in_bytes;
+        while self.size_in_bytes > self.max_size_in_bytes {
+            let (lru, _) = self
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, e)| e.last_used)
+                .expect("the budget holds the last entry");
+            self.size_in_bytes -= self.entries.remove(lru).size_in_bytes;
+        }
+        Ok(())
+    }
+}
+
+fn common_prefix_len(a: &[PrefixToken], b: &[PrefixToken]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::tests::{tiny_image, tiny_pipeline};
+    use crate::GenerationOptions;
+    use candle_core::{DType, Device, Tensor};
+
+    fn tokens(ids: &[i64]) -> Vec<PrefixToken> {
+        ids.iter().map(|id| PrefixToken::Token(*id)).collect()
+    }
+
+    fn filled_cache(len: usize) -> Cache {
+        let mut cache = Cache::new(true, DType::F32, 1, 4, 10000., 64, &Device::Cpu).unwrap();
+        let kv = Tensor::ones((1, 2, len, 4), DType::F32, &Device::Cpu).unwrap();
+        cache.append(0, 0, &kv, &kv).unwrap();
+        cache
+    }
+
+    #[test]
+    fn test_prefix_cache_lru() {
+        // 2 * 2 * 4 f32 per position, room for 20 positions
+        let mut prefix_cache = PrefixCache::new(20 * 64);
+        prefix_cache
+            .insert(tokens(&[1, 2, 3]), vec![1, 2, 3], &filled_cache(3))
+            .unwrap();
+        prefix_cache
+            .insert(tokens(&[1, 2, 3, 4]), vec![1, 2, 3, 4], &filled_cache(4))
+            .unwrap();
+        // the longer prompt replaces the shorter
+        assert_eq!(prefix_cache.len(), 1);
+        assert_eq!(prefix_cache.size_in_bytes(), 4 * 64);
+        prefix_cache
+            .insert(tokens(&[5; 10]), (1..=10).collect(), &filled_cache(10))
+            .unwrap();
+
+        let mut cache = filled_cache(1);
+        cache.clear();
+        let reused = prefix_cache
+            .restore(&tokens(&[1, 2, 7]), &mut cache)
+            .unwrap();
+        assert_eq!(reused, [1, 2]);
+        assert_eq!(cache.seq_len(), 2);
+        // the last item is always prefilled
+        let reused = prefix_cache
+            .restore(&tokens(&[1, 2, 3, 4]), &mut cache)
+            .unwrap();
+        assert_eq!(reused.len(), 3);
+        assert!(prefix_cache
+            .restore(&tokens(&[8]), &mut cache)
+            .unwrap()
+            .is_empty());
+
+        // over the budget, the least recently used goes
+        prefix_cache
+            .insert(tokens(&[6; 8]), (1..=8).collect(), &filled_cache(8))
+            .unwrap();
+        assert_eq!(prefix_cache.len(), 2);
+        assert!(prefix_cache.size_in_bytes() <= 20 * 64);
+        assert_eq!(
+            prefix_cache
+                .restore(&tokens(&[5; 10]), &mut cache)
+                .unwrap()
+                .len(),
+            0
+        );
+        // too big to be kept at all
+        prefix_cache
+            .insert(tokens(&[9; 30]), (1..=30).collect(), &filled_cache(30))
+            .unwrap();
+        assert_eq!(prefix_cache.len(), 2);
+    }
+
+    #[test]
+    fn test_prefix_cache_generation() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 4,
+            ..Default::default()
+        };
+        let images = [tiny_image()];
+        let mut pipeline = tiny_pipeline(true);
+        let expected = ["is this a cat?", "what color is it?"]
+            .map(|prompt| pipeline.generate(prompt, &images, &options).unwrap().tokens);
+
+        pipeline.prefix_cache = Some(PrefixCache::new(1 << 20));
+        for (prompt, expected) in ["is this a cat?", "what color is it?"]
+            .iter()
+            .zip(&expected)
+        {
+            let output = pipeline.generate(prompt, &images, &options).unwrap();
+            // the system prompt and the image are shared
+            if *prompt == "what color is it?" {
+                assert!(output.cached_len > 4, "{}", output.cached_len);
+            }
+            assert_eq!(&output.tokens, expected);
+        }
+        assert_eq!(pipeline.prefix_cache.as_ref().unwrap().len(), 2);
+        let output = pipeline
+            .generate("is this a cat?", &images, &options)
+            .unwrap();
+        assert_eq!(output.tokens, expected[0]);
+        assert_eq!(output.cached_len, output.prompt_len - 1);
+    }
+}
@@ -0,0 +1,266 @@
+/*
+Quantized linear layers for the language model, through the candle quantized matmul. The weights
+are either quantized as they are loaded from the safetensors, or read already quantized from a
+gguf file holding the tensors under their checkpoint names.
+*/
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use candle_core::quantized::{gguf_file, GgmlDType, QMatMul, QTensor};
+use candle_core::{DType, Device, Module, Result, Shape, Tensor};
+use candle_nn::var_builder::SimpleBackend;
+use candle_nn::{Init, VarBuilder};
+
+use crate::utils::{weights_fingerprint, TensorSample, FINGERPRINT_SAMPLE_BYTES};
+
+/// The tensors of a gguf file, kept as they are stored.
+#[derive(Clone)]
+pub struct GgufWeights {
+    tensors: Arc<HashMap<String, Arc<QTensor>>>,
+    pub metadata: Arc<HashMap<String, gguf_file::Value>>,
+    /// `weights_fingerprint` of the file, with the ggml dtypes.
+    pub fingerprint: String,
+}
+
+impl GgufWeights {
+    pub fn load<P: AsRef<Path>>(path: P, device: &Device) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = std::fs::File::open(path)
+            .map_err(|e| candle_core::Error::Msg(format!("cannot open {}: {e}", path.display())))?;
+        let content = gguf_file::Content::read(&mut file)?;
+        let mut tensors = HashMap::new();
+        let mut samples = Vec::new();
+        for (name, info) in content.tensor_infos.iter() {
+            tensors.insert(
+                name.clone(),
+                Arc::new(content.tensor(&mut file, name, device)?),
+            );
+            let mut data = Vec::new();
+            file.seek(SeekFrom::Start(content.tensor_data_offset + info.offset))?;
+            (&mut file)
+                .take(FINGERPRINT_SAMPLE_BYTES as u64)
+                .read_to_end(&mut data)?;
+            samples.push(TensorSample {
+                name: name.clone(),
+                shape: info.shape.dims().to_vec(),
+                dtype: format!("{:?}", info.ggml_dtype),
+                data,
+            });
+        }
+        Ok(Self {
+            tensors: Arc::new(tensors),
+            metadata: Arc::new(content.metadata),
+            fingerprint: weights_fingerprint(samples),
+        })
+    }
+
+    pub fn tensor_names(&self) -> Vec<String> {
+        self.tensors.keys().cloned().collect()
+    }
+
+    /// The tensor `name` when it is stored with a quantized type, not as plain floats.
+    pub fn quantized(&self, name: &str) -> Option<Arc<QTensor>> {
+        self.tensors
+            .get(name)
+            .filter(|t| !matches!(t.dtype(), GgmlDType::F32 | GgmlDType::F16 | GgmlDType::BF16))
+            .cloned()
+    }
+
+    /// A VarBuilder over the dequantized tensors, for the layers that do not run quantized.
+    pub fn var_builder(&self, dtype: DType, device: &Device) -> VarBuilder<'static> {
+        VarBuilder::from_backend(Box::new(self.clone()), dtype, device.clone())
+    }
+}
+
+impl SimpleBackend for GgufWeights {
+    fn get(&self, s: Shape, name: &str, _: Init, dtype: DType, dev: &Device) -> Result<Tensor> {
+        let tensor = self.get_unchecked(name, dtype, dev)?;
+        if tensor.shape() != &s {
+            candle_core::bail!(
+                "shape mismatch for {name}, got {:?}, expected {s:?}",
+                tensor.shape()
+            )
+        }
+        Ok(tensor)
+    }
+
+    fn get_unchecked(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor> {
+        match self.tensors.get(name) {
+            Some(qtensor) => qtensor.dequantize(dev)?.to_dtype(dtype),
+            None => candle_core::bail!("cannot find tensor {name}"),
+        }
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+}
+
+/// How the linear layers of the language model are stored.
+#[derive(Clone, Default)]
+pub struct Quantization {
+    /// Quantize the weights that are not quantized yet to this type, e.g. Q4K or Q8_0.
+    pub dtype: Option<GgmlDType>,
+    /// Take the quantized weights from this gguf file.
+    pub gguf: Option<GgufWeights>,
+}
+
+impl Quantization {
+    pub fn is_none(&self) -> bool {
+        self.dtype.is_none() && self.gguf.is_none()
+    }
+}
+
+/// A linear layer without bias, its weight is quantized or not.
+#[derive(Debug, Clone)]
+pub struct QLinear {
+    weight: QMatMul,
+    span: tracing::Span,
+}
+
+impl QLinear {
+    pub fn is_quantized(&self) -> bool {
+        matches!(self.weight, QMatMul::QTensor(_))
+    }
+}
+
+impl Module for QLinear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        match &self.weight {
+            // the quantized matmul takes f32 inputs
+            QMatMul::QTensor(_) if x.dtype() != DType::F32 => self
+                .weight
+                .forward(&x.to_dtype(DType::F32)?.contiguous()?)?
+                .to_dtype(x.dtype()),
+            QMatMul::QTensor(_) => self.weight.forward(&x.contiguous()?),
+            _ => self.weight.forward(x),
+        }
+    }
+}
+
+/// Same as `with_tracing::linear_no_bias`. The weight stays in the dtype of `vb` when the rows
+/// do not split into blocks of the quantized type.
+pub fn linear_no_bias(
+    in_dim: usize,
+    out_dim: usize,
+    vb: VarBuilder,
+    quantization: &Quantization,
+) -> Result<QLinear> {
+    let span = tracing::span!(tracing::Level::TRACE, "qlinear");
+    let name = format!("{}.weight", vb.prefix());
+    if let Some(qtensor) = quantization
+        .gguf
+        .as_ref()
+        .and_then(|gguf| gguf.quantized(&name))
+    {
+        if qtensor.shape().dims() != [out_dim, in_dim] {
+            candle_core::bail!(
+                "shape mismatch for {name}, got {:?}, expected ({out_dim}, {in_dim})",
+                qtensor.shape()
+            )
+        }
+        return Ok(QLinear {
+            weight: QMatMul::from_arc(qtensor)?,
+            span,
+        });
+    }
+    let weight = vb.get((out_dim, in_dim), "weight")?;
+    let weight = match quantization.dtype {
+        Some(dtype) if in_dim % dtype.block_size() == 0 => {
+            QMatMul::from_qtensor(QTensor::quantize(&weight, dtype)?)?
+        }
+        _ => QMatMul::Tensor(weight),
+    };
+    Ok(QLinear { weight, span })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weight() -> Tensor {
+        Tensor::arange(0f32, 64. * 32., &Device::Cpu)
+            .unwrap()
+            .affine(1. / 2048., -0.5)
+            .unwrap()
+            .sin()
+            .unwrap()
+            .reshape((64, 32))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_quantize_on_load() {
+        let device = Device::Cpu;
+        let vb = VarBuilder::from_tensors(
+            HashMap::from([("proj.weight".to_string(), weight())]),
+            DType::F32,
+            &device,
+        );
+        let x = Tensor::ones((1, 3, 32), DType::F32, &device).unwrap();
+        let full = linear_no_bias(32, 64, vb.pp("proj"), &Quantization::default()).unwrap();
+        assert!(!full.is_quantized());
+        let q8 = Quantization {
+            dtype: Some(GgmlDType::Q8_0),
+            gguf: None,
+        };
+        let quantized = linear_no_bias(32, 64, vb.pp("proj"), &q8).unwrap();
+        assert!(quantized.is_quantized());
+        let diff = (full.forward(&x).unwrap() - quantized.forward(&x).unwrap())
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 0.05, "{diff}");
+        // 32 columns do not make a Q4K block of 256
+        let q4k = Quantization {
+            dtype: Some(GgmlDType::Q4K),
+            gguf: None,
+        };
+        assert!(!linear_no_bias(32, 64, vb.pp("proj"), &q4k)
+            .unwrap()
+            .is_quantized());
+    }
+
+    #[test]
+    fn test_gguf_weights() {
+        let device = Device::Cpu;
+        let path = std::env::temp_dir().join(format!("candle-llava-{}.gguf", std::process::id()));
+        let quantized = QTensor::quantize(&weight(), GgmlDType::Q8_0).unwrap();
+        let norm = QTensor::quantize(
+            &Tensor::ones(32, DType::F32, &device).unwrap(),
+            GgmlDType::F32,
+        )
+        .unwrap();
+        let mut file = std::fs::File::create(&path).unwrap();
+        gguf_file::write(
+            &mut file,
+            &[(
+                "general.name",
+                &gguf_file::Value::String("tiny".to_string()),
+            )],
+            &[("proj.weight", &quantized), ("norm.weight", &norm)],
+        )
+        .unwrap();
+        drop(file);
+
+        let gguf = GgufWeights::load(&path, &device).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(gguf.quantized("norm.weight").is_none());
+        let vb = gguf.var_builder(DType::F32, &device);
+        assert_eq!(vb.get(32, "norm.weight").unwrap().dims(), &[32]);
+        let quantization = Quantization {
+            dtype: None,
+            gguf: Some(gguf),
+        };
+        let linear = linear_no_bias(32, 64, vb.pp("proj"), &quantization).unwrap();
+        assert!(linear.is_quantized());
+        assert!(linear_no_bias(32, 32, vb.pp("proj"), &quantization).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+/*
+Sampling of the next token: the logits processors, then top-k, min-p and top-p filtering.
+The filtered logits are sampled by the candle LogitsProcessor. `logprobs` reads the logits as the
+model gave them.
+*/
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Result, Tensor, D};
+use candle_transformers::generation::{self, Sampling};
+
+use crate::logits_processor::{LogitsProcessor, Penalties};
+use crate::pipeline::GenerationOptions;
+
+/// The penalties of `options` then its `logits_processors`, everything that runs on the logits
+/// before the sampling itself. `prompt_tokens` are the text tokens of the prompt, see `Penalties`.
+pub fn logits_processors(
+    options: &GenerationOptions,
+    prompt_tokens: &[u32],
+) -> Vec<Arc<dyn LogitsProcessor>> {
+    let penalties = Penalties {
+        repeat_penalty: options.repeat_penalty,
+        last_n: options.repeat_last_n,
+        presence_penalty: options.presence_penalty,
+        frequency_penalty: options.frequency_penalty,
+        prompt_tokens: prompt_tokens[prompt_tokens.len().saturating_sub(options.repeat_last_n)..]
+            .to_vec(),
+    };
+    let mut processors: Vec<Arc<dyn LogitsProcessor>> = Vec::new();
+    if !penalties.is_noop() {
+        processors.push(Arc::new(penalties));
+    }
+    processors.extend(options.logits_processors.iter().cloned());
+    processors
+}
+
+pub struct Sampler {
+    logits_processor: generation::LogitsProcessor,
+    processors: Vec<Arc<dyn LogitsProcessor>>,
+    temperature: f64,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    min_p: Option<f64>,
+}
+
+impl Sampler {
+    pub fn new(options: &GenerationOptions, prompt_tokens: &[u32]) -> Self {
+        let temperature = options.temperature;
+        let sampling = if temperature <= 0. {
+            Sampling::ArgMax
+        } else {
+            Sampling::All { temperature }
+        };
+        Self {
+            logits_processor: generation::LogitsProcessor::from_sampling(options.seed, sampling),
+            processors: logits_processors(options, prompt_tokens),
+            temperature,
+            top_k: options.top_k,
+            top_p: options.top_p,
+            min_p: options.min_p,
+        }
+    }
+
+    /// Sample the next token from the `[vocab]` logits, `tokens` are the tokens generated so far.
+    pub fn sample(&mut self, logits: &Tensor, tokens: &[u32]) -> Result<u32> {
+        let mut logits: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+        for processor in &self.processors {
+            processor.process(&mut logits, tokens)?;
+        }
+        if self.temperature > 0. {
+            self.filter(&mut logits);
+        }
+        let logits = Tensor::new(logits.as_slice(), &Device::Cpu)?;
+        self.logits_processor.sample(&logits)
+    }
+
+    /// Set the logits of the tokens outside of top-k, min-p and top-p to -inf. The probabilities
+    /// are the ones after temperature, at least the most likely token is kept.
+    fn filter(&self, logits: &mut [f32]) {
+        if self.top_k.is_none() && self.top_p.is_none() && self.min_p.is_none() {
+            return;
+        }
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let mut probs: Vec<f64> = logits
+            .iter()
+            .map(|l| ((l - max) as f64 / self.temperature).exp())
+            .collect();
+        let sum: f64 = probs.iter().sum();
+        probs.iter_mut().for_each(|p| *p /= sum);
+        let mut order: Vec<usize> = (0..probs.len()).collect();
+        order.sort_by(|&i, &j| probs[j].total_cmp(&probs[i]));
+
+        let mut keep = order.len();
+        if let Some(top_k) = self.top_k {
+            keep = keep.min(top_k.max(1));
+        }
+        if let Some(min_p) = self.min_p {
+            let threshold = probs[order[0]] * min_p;
+            keep = keep.min(order.iter().take_while(|&&i| probs[i] >= threshold).count());
+        }
+        if let Some(top_p) = self.top_p {
+            let mut cumulative = 0.;
+            let mut n = 0;
+            for &i in &order[..keep] {
+                n += 1;
+                cumulative += probs[i];
+                if cumulative >= top_p {
+                    break;
+                }
+            }
+            keep = n;
+        }
+        for &i in &order[keep.max(1)..] {
+            logits[i] = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// The log probability of `token` and the `top_n` most likely tokens with theirs, most likely
+/// first, from the `[vocab]` logits as the model gave them.
+pub fn logprobs(logits: &Tensor, token: u32, top_n: usize) -> Result<(f32, Vec<(u32, f32)>)> {
+    let log_probs: Vec<f32> =
+        candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?.to_vec1()?;
+    let logprob = log_probs
+        .get(token as usize)
+        .copied()
+        .unwrap_or(f32::NEG_INFINITY);
+    let mut order: Vec<usize> = (0..log_probs.len()).collect();
+    order.sort_by(|&i, &j| log_probs[j].total_cmp(&log_probs[i]));
+    let top = order
+        .into_iter()
+        .take(top_n)
+        .map(|i| (i as u32, log_probs[i]))
+        .collect();
+    Ok((logprob, top))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logits_processor::ForcedTokens;
+
+    fn sampler(options: GenerationOptions) -> Sampler {
+        Sampler::new(
+            &GenerationOptions {
+                temperature: 1.,
+                ..options
+            },
+            &[],
+        )
+    }
+
+    fn kept(sampler: &Sampler, logits: &[f32]) -> Vec<usize> {
+        let mut logits = logits.to_vec();
+        sampler.filter(&mut logits);
+        (0..logits.len())
+            .filter(|&i| logits[i] > f32::NEG_INFINITY)
+            .collect()
+    }
+
+    #[test]
+    fn test_filter() {
+        // probabilities 0.5, 0.25, 0.125, 0.125
+        let logits = [2f32.ln() * 2., 2f32.ln(), 0., 0.];
+        let s = sampler(GenerationOptions::default());
+        assert_eq!(kept(&s, &logits), vec![0, 1, 2, 3]);
+        let s = sampler(GenerationOptions {
+            top_k: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(kept(&s, &logits), vec![0, 1]);
+        let s = sampler(GenerationOptions {
+            top_p: Some(0.7),
+            ..Default::default()
+        });
+        assert_eq!(kept(&s, &logits), vec![0, 1]);
+        let s = sampler(GenerationOptions {
+            top_p: Some(0.1),
+            ..Default::default()
+        });
+        assert_eq!(kept(&s, &logits), vec![0]);
+        let s = sampler(GenerationOptions {
+            min_p: Some(0.2),
+            ..Default::default()
+        });
+        assert_eq!(kept(&s, &logits), vec![0, 1, 2, 3]);
+        let s = sampler(GenerationOptions {
+            min_p: Some(0.4),
+            ..Default::default()
+        });
+        assert_eq!(kept(&s, &logits), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_greedy_with_penalty() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            top_k: Some(1),
+            repeat_penalty: 10.,
+            ..Default::default()
+        };
+        let mut s = Sampler::new(&options, &[]);
+        let logits = Tensor::new(&[1f32, 3., 2.], &Device::Cpu).unwrap();
+        assert_eq!(s.sample(&logits, &[]).unwrap(), 1);
+        // the penalty moves the argmax
+        assert_eq!(s.sample(&logits, &[1]).unwrap(), 2);
+        // a token of the prompt is penalized from the first step
+        let mut s = Sampler::new(&options, &[0, 1]);
+        assert_eq!(s.sample(&logits, &[]).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_logits_processors_run_in_order() {
+        let options = GenerationOptions {
+            temperature: 0.,
+            repeat_penalty: 10.,
+            logits_processors: vec![Arc::new(ForcedTokens(vec![1]))],
+            ..Default::default()
+        };
+        let mut s = Sampler::new(&options, &[]);
+        let logits = Tensor::new(&[1f32, 3., 2.], &Device::Cpu).unwrap();
+        // the forced token wins over the penalty, then the penalty applies
+        assert_eq!(s.sample(&logits, &[]).unwrap(), 1);
+        assert_eq!(s.sample(&logits, &[1]).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_logprobs() {
+        let logits = Tensor::new(&[2f32.ln() * 2., 0., 2f32.ln(), 0.], &Device::Cpu).unwrap();
+        let (logprob, top) = logprobs(&logits, 3, 2).unwrap();
+        assert!((logprob - 0.125f32.ln()).abs() < 1e-6);
+        assert_eq!(top.iter().map(|(t, _)| *t).collect::<Vec<_>>(), [0, 2]);
+        assert!((top[0].1 - 0.5f32.ln()).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,158 @@
+/*
+Log-likelihood of given answers, e.g. to rank the choices of a multiple-choice question or
+candidate captions. The prompt is prefilled once, each continuation then gets the logits of all
+its positions from `LLaVA::forward_all`.
+*/
+use anyhow::{bail, Error as E, Result};
+use candle_core::{DType, Tensor, D};
+use image::DynamicImage;
+
+use crate::pipeline::LlavaPipeline;
+
+#[derive(Debug, Clone)]
+pub struct ContinuationScore {
+    pub tokens: Vec<u32>,
+    /// The log probability of each token given the prompt and the tokens before it.
+    pub token_logprobs: Vec<f32>,
+    /// The sum of `token_logprobs`.
+    pub log_likelihood: f64,
+    /// Every token was the most likely one, greedy decoding would have produced the continuation.
+    pub is_greedy: bool,
+}
+
+impl LlavaPipeline {
+    /// How likely the model is to answer `prompt` with `continuation`.
+    pub fn score(
+        &self,
+        prompt: &str,
+        images: &[DynamicImage],
+        continuation: &str,
+    ) -> Result<ContinuationScore> {
+        let mut scores = self.score_many(prompt, images, &[continuation])?;
+        Ok(scores.remove(0))
+    }
+
+    /// `score` for several continuations of the same prompt, the images are encoded once. A
+    /// continuation is tokenized on its own, the way the answer is generated after the prompt.
+    pub fn score_many(
+        &self,
+        prompt: &str,
+        images: &[DynamicImage],
+        continuations: &[&str],
+    ) -> Result<Vec<ContinuationScore>> {
+        let conv = self.prompt_conversation(prompt, images.len())?;
+        let prompt_embeds = self.conversation_embeds(&conv, images)?;
+        let (_, prompt_len, _) = prompt_embeds.dims3()?;
+        let continuations = continuations
+            .iter()
+            .map(|continuation| {
+                let tokens = self
+                    .tokenizer
+                    .encode(*continuation, false)
+                    .map_err(E::msg)?
+                    .get_ids()
+                    .to_vec();
+                if tokens.is_empty() {
+                    bail!("the continuation {continuation:?} has no tokens")
+                }
+                Ok(tokens)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // each continuation copies the prompt cache, with room for the longest one only
+        let longest = continuations.iter().map(Vec::len).max().unwrap_or(0);
+        let mut cache = self.cache.with_max_seq_len(prompt_len + longest)?;
+        // the logits of the last prompt position predict the first token of every continuation
+        let prompt_logits = if cache.use_kv_cache {
+            Some(self.llava.forward(&prompt_embeds, 0, &mut cache)?)
+        } else {
+            None
+        };
+
+        let mut scores = Vec::with_capacity(continuations.len());
+        for tokens in continuations {
+            let n = tokens.len();
+            let embeds = self
+                .llava
+                .language_model
+                .embed(&Tensor::new(tokens.as_slice(), self.device())?)?
+                .unsqueeze(0)?;
+            // (n, vocab_size), row i predicts tokens[i]
+            let logits = match &prompt_logits {
+                Some(prompt_logits) if n == 1 => prompt_logits.clone(),
+                Some(prompt_logits) => {
+                    let mut cache = cache.clone();
+                    let rest = self.llava.forward_all(
+                        &embeds.narrow(1, 0, n - 1)?,
+                        prompt_len,
+                        &mut cache,
+                    )?;
+                    Tensor::cat(&[prompt_logits, &rest.squeeze(0)?], 0)?
+                }
+                None => {
+                    let input = Tensor::cat(&[&prompt_embeds, &embeds], 1)?;
+                    let mut cache = cache.clone();
+                    self.llava
+                        .forward_all(&input, 0, &mut cache)?
+                        .squeeze(0)?
+                        .narrow(0, prompt_len - 1, n)?
+                }
+            };
+            let log_probs: Vec<Vec<f32>> =
+                candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?.to_vec2()?;
+            let mut token_logprobs = Vec::with_capacity(n);
+            let mut is_greedy = true;
+            for (token, log_probs) in tokens.iter().zip(&log_probs) {
+                let logprob = log_probs[*token as usize];
+                is_greedy &= log_probs.iter().all(|l| *l <= logprob);
+                token_logprobs.push(logprob);
+            }
+            scores.push(ContinuationScore {
+                log_likelihood: token_logprobs.iter().map(|l| *l as f64).sum(),
+                tokens,
+                token_logprobs,
+                is_greedy,
+            });
+        }
+        Ok(scores)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline::tests::{tiny_image, tiny_pipeline};
+    use crate::GenerationOptions;
+
+    #[test]
+    fn test_score_matches_generation() {
+        let mut pipeline = tiny_pipeline(true);
+        let images = [tiny_image()];
+        let prompt = "what color is the cat?";
+        let output = pipeline
+            .generate(
+                prompt,
+                &images,
+                &GenerationOptions {
+                    temperature: 0.,
+                    max_new_tokens: 4,
+                    logprobs: Some(0),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let continuation = pipeline.tokenizer.decode(&output.tokens, true).unwrap();
+        let with_cache = pipeline.score(prompt, &images, &continuation).unwrap();
+        assert_eq!(with_cache.tokens, output.tokens);
+        assert!(with_cache.is_greedy);
+        for (a, b) in with_cache.token_logprobs.iter().zip(&output.logprobs) {
+            assert!((a - b.logprob).abs() < 1e-4);
+        }
+
+        pipeline.cache = pipeline.llava.language_model.create_cache(false).unwrap();
+        let scores = pipeline
+            .score_many(prompt, &images, &[&continuation, "dog dog"])
+            .unwrap();
+        assert!((scores[0].log_likelihood - with_cache.log_likelihood).abs() < 1e-4);
+        assert_eq!(scores[1].token_logprobs.len(), 2);
+        assert!(pipeline.score(prompt, &images, "").is_err());
+    }
+}
@@ -0,0 +1,853 @@
+/*
+Pure rust conversion of a SentencePiece `tokenizer.model` (plus `tokenizer_config.json` and
+`special_tokens_map.json`) to a `tokenizers::Tokenizer`, following `LlamaConverter` in
+https://github.com/huggingface/transformers/blob/main/src/transformers/convert_slow_tokenizer.py
+*/
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error as E, Result};
+use serde_json::Value;
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip;
+use tokenizers::models::bpe::BPE;
+use tokenizers::normalizers::{Prepend, Replace, Sequence as NormalizerSequence};
+use tokenizers::pre_tokenizers::metaspace::{Metaspace, PrependScheme};
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{AddedToken, DecoderWrapper, NormalizerWrapper, Tokenizer};
+
+use crate::model_source::ModelSource;
+use crate::utils::StableHasher;
+
+const SPIECE_UNDERLINE: &str = "▁";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceType {
+    Normal,
+    Unknown,
+    Control,
+    UserDefined,
+    Unused,
+    Byte,
+}
+
+impl PieceType {
+    fn from_proto(value: u64) -> Self {
+        match value {
+            2 => PieceType::Unknown,
+            3 => PieceType::Control,
+            4 => PieceType::UserDefined,
+            5 => PieceType::Unused,
+            6 => PieceType::Byte,
+            _ => PieceType::Normal,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SentencePiece {
+    pub piece: String,
+    pub score: f32,
+    pub piece_type: PieceType,
+}
+
+/// The parts of sentencepiece's `ModelProto` needed for the conversion.
+#[derive(Debug, Clone)]
+pub struct SentencePieceModel {
+    pub pieces: Vec<SentencePiece>,
+    /// 1: unigram, 2: bpe, 3: word, 4: char
+    pub model_type: u64,
+    pub byte_fallback: bool,
+    pub unk_id: usize,
+    pub add_dummy_prefix: bool,
+}
+
+struct ProtoReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let Some(byte) = self.data.get(self.pos) else {
+                bail!("truncated varint in tokenizer.model")
+            };
+            self.pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("varint too long in tokenizer.model")
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.pos + n > self.data.len() {
+            bail!("truncated field in tokenizer.model")
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u64, ProtoValue<'a>)>> {
+        if self.pos >= self.data.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                ProtoValue::Fixed64
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                ProtoValue::Bytes(self.take(len)?)
+            }
+            5 => ProtoValue::Fixed32(u32::from_le_bytes(self.take(4)?.try_into()?)),
+            wire_type => bail!("unsupported protobuf wire type {wire_type} in tokenizer.model"),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+impl SentencePieceModel {
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut model = SentencePieceModel {
+            pieces: Vec::new(),
+            model_type: 1,
+            byte_fallback: false,
+            unk_id: 0,
+            add_dummy_prefix: true,
+        };
+        let mut reader = ProtoReader::new(data);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, ProtoValue::Bytes(bytes)) => model.pieces.push(Self::parse_piece(bytes)?),
+                (2, ProtoValue::Bytes(bytes)) => {
+                    let mut trainer_spec = ProtoReader::new(bytes);
+                    while let Some((field, value)) = trainer_spec.next_field()? {
+                        match (field, value) {
+                            (3, ProtoValue::Varint(v)) => model.model_type = v,
+                            (35, ProtoValue::Varint(v)) => model.byte_fallback = v != 0,
+                            (40, ProtoValue::Varint(v)) => model.unk_id = v as usize,
+                            _ => {}
+                        }
+                    }
+                }
+                (3, ProtoValue::Bytes(bytes)) => {
+                    let mut normalizer_spec = ProtoReader::new(bytes);
+                    while let Some((field, value)) = normalizer_spec.next_field()? {
+                        if let (3, ProtoValue::Varint(v)) = (field, value) {
+                            model.add_dummy_prefix = v != 0
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if model.pieces.is_empty() {
+            bail!("no pieces in tokenizer.model")
+        }
+        Ok(model)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    fn parse_piece(data: &[u8]) -> Result<SentencePiece> {
+        let mut piece = SentencePiece {
+            piece: String::new(),
+            score: 0.0,
+            piece_type: PieceType::Normal,
+        };
+        let mut reader = ProtoReader::new(data);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, ProtoValue::Bytes(bytes)) => piece.piece = String::from_utf8(bytes.to_vec())?,
+                (2, ProtoValue::Fixed32(bits)) => piece.score = f32::from_bits(bits),
+                (3, ProtoValue::Varint(v)) => piece.piece_type = PieceType::from_proto(v),
+                _ => {}
+            }
+        }
+        Ok(piece)
+    }
+
+    /// Same as `SentencePieceExtractor.extract` in transformers: every split of a piece into two
+    /// pieces of the vocab is a merge, ordered by the score of the merged piece.
+    fn vocab_and_merges(&self) -> (HashMap<String, u32>, Vec<(String, String)>) {
+        let vocab: HashMap<String, u32> = self
+            .pieces
+            .iter()
+            .enumerate()
+            .map(|(id, p)| (p.piece.clone(), id as u32))
+            .collect();
+        let mut merges = Vec::new();
+        for piece in self.pieces.iter() {
+            let mut local = Vec::new();
+            for (index, _) in piece.piece.char_indices().skip(1) {
+                let (left, right) = piece.piece.split_at(index);
+                if let (Some(left_id), Some(right_id)) = (vocab.get(left), vocab.get(right)) {
+                    local.push((left.to_string(), right.to_string(), *left_id, *right_id));
+                }
+            }
+            local.sort_by_key(|(_, _, left_id, right_id)| (*left_id, *right_id));
+            merges.extend(local.into_iter().map(|(l, r, _, _)| (l, r, piece.score)));
+        }
+        // stable sort, so merges with the same score keep the order of the vocab
+        merges.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let merges = merges.into_iter().map(|(l, r, _)| (l, r)).collect();
+        (vocab, merges)
+    }
+}
+
+/// `tokenizer_config.json` and `special_tokens_map.json`, both optional.
+#[derive(Debug, Clone, Default)]
+pub struct SpecialTokensConfig {
+    pub tokenizer_config: Option<Value>,
+    pub special_tokens_map: Option<Value>,
+    /// content of `added_tokens.json`, used by older checkpoints
+    pub added_tokens: Option<Value>,
+}
+
+impl SpecialTokensConfig {
+    fn tokenizer_config_bool(&self, key: &str, default: bool) -> bool {
+        self.tokenizer_config
+            .as_ref()
+            .and_then(|c| c.get(key))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(default)
+    }
+
+    /// special tokens can either be a plain string or an `AddedToken` dict
+    fn token_content(value: &Value) -> Option<String> {
+        match value {
+            Value::String(s) => Some(s.clone()),
+            Value::Object(map) => map.get("content")?.as_str().map(|s| s.to_string()),
+            _ => None,
+        }
+    }
+
+    fn special_token_contents(&self) -> Vec<String> {
+        let mut contents = Vec::new();
+        for config in [&self.special_tokens_map, &self.tokenizer_config]
+            .into_iter()
+            .flatten()
+        {
+            for key in ["bos_token", "eos_token", "unk_token", "pad_token"] {
+                if let Some(content) = config.get(key).and_then(Self::token_content) {
+                    contents.push(content);
+                }
+            }
+            if let Some(Value::Array(tokens)) = config.get("additional_special_tokens") {
+                contents.extend(tokens.iter().filter_map(Self::token_content));
+            }
+        }
+        contents
+    }
+
+    /// Added tokens with their ids, sorted by id.
+    fn added_tokens(&self) -> Vec<(u32, AddedToken)> {
+        let special_contents = self.special_token_contents();
+        let mut added = HashMap::new();
+        if let Some(Value::Object(decoder)) = self
+            .tokenizer_config
+            .as_ref()
+            .and_then(|c| c.get("added_tokens_decoder"))
+        {
+            for (id, token) in decoder.iter() {
+                let (Ok(id), Some(content)) = (id.parse::<u32>(), Self::token_content(token))
+                else {
+                    continue;
+                };
+                let flag = |key: &str, default: bool| {
+                    token.get(key).and_then(|v| v.as_bool()).unwrap_or(default)
+                };
+                let special = flag("special", special_contents.contains(&content));
+                let added_token = AddedToken::from(content, special)
+                    .single_word(flag("single_word", false))
+                    .lstrip(flag("lstrip", false))
+                    .rstrip(flag("rstrip", false))
+                    .normalized(flag("normalized", !special));
+                added.insert(id, added_token);
+            }
+        }
+        if let Some(Value::Object(tokens)) = &self.added_tokens {
+            for (content, id) in tokens.iter() {
+                let Some(id) = id.as_u64() else { continue };
+                let special = special_contents.contains(content);
+                added
+                    .entry(id as u32)
+                    .or_insert_with(|| AddedToken::from(content.clone(), special));
+            }
+        }
+        let mut added = added.into_iter().collect::<Vec<_>>();
+        added.sort_by_key(|(id, _)| *id);
+        added
+    }
+}
+
+/// Build a fast tokenizer equivalent to `LlamaTokenizerFast` from a BPE sentencepiece model.
+pub fn convert_llama_tokenizer(
+    model: &SentencePieceModel,
+    config: &SpecialTokensConfig,
+) -> Result<Tokenizer> {
+    if model.model_type != 2 {
+        bail!(
+            "only BPE sentencepiece models can be converted, got model type {}",
+            model.model_type
+        )
+    }
+    let (vocab, merges) = model.vocab_and_merges();
+    let unk_token = model
+        .pieces
+        .get(model.unk_id)
+        .with_context(|| format!("unk id {} is not a piece of the model", model.unk_id))?
+        .piece
+        .clone();
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .unk_token(unk_token)
+        .fuse_unk(true)
+        .byte_fallback(true)
+        .build()
+        .map_err(E::msg)?;
+    let mut tokenizer = Tokenizer::new(bpe);
+
+    let add_prefix_space = config.tokenizer_config_bool("add_prefix_space", model.add_dummy_prefix);
+    if config.tokenizer_config_bool("legacy", true) {
+        let mut normalizers: Vec<NormalizerWrapper> = Vec::new();
+        if add_prefix_space {
+            normalizers.push(Prepend::new(SPIECE_UNDERLINE.to_string()).into());
+        }
+        normalizers.push(Replace::new(" ", SPIECE_UNDERLINE).map_err(E::msg)?.into());
+        tokenizer.with_normalizer(NormalizerSequence::new(normalizers));
+    } else {
+        let prepend_scheme = if add_prefix_space {
+            PrependScheme::First
+        } else {
+            PrependScheme::Never
+        };
+        tokenizer.with_pre_tokenizer(Metaspace::new('▁', prepend_scheme, false));
+    }
+
+    let mut decoders: Vec<DecoderWrapper> = vec![
+        Replace::new(SPIECE_UNDERLINE, " ").map_err(E::msg)?.into(),
+        ByteFallback::new().into(),
+        Fuse::new().into(),
+    ];
+    if add_prefix_space {
+        decoders.push(Strip::new(' ', 1, 0).into());
+    }
+    tokenizer.with_decoder(DecoderSequence::new(decoders));
+
+    // <unk>, <s>, </s>
+    let control_tokens = model.pieces[..3.min(model.pieces.len())]
+        .iter()
+        .map(|p| AddedToken::from(p.piece.clone(), true).normalized(false))
+        .collect::<Vec<_>>();
+    tokenizer.add_special_tokens(&control_tokens);
+
+    // user defined pieces are never split, as in LlamaConverter
+    let special_contents = config.special_token_contents();
+    let user_defined = model
+        .pieces
+        .iter()
+        .filter(|p| p.piece_type == PieceType::UserDefined)
+        .map(|p| {
+            AddedToken::from(p.piece.clone(), special_contents.contains(&p.piece)).normalized(false)
+        })
+        .collect::<Vec<_>>();
+    tokenizer.add_tokens(&user_defined);
+
+    for (id, added_token) in config.added_tokens() {
+        if (id as usize) < model.pieces.len() {
+            // already a piece of the model, e.g. <unk>
+            continue;
+        }
+        if id as usize != tokenizer.get_vocab_size(true) {
+            bail!(
+                "added token {} has id {id} but the vocab has {} tokens",
+                added_token.content,
+                tokenizer.get_vocab_size(true)
+            )
+        }
+        if added_token.special {
+            tokenizer.add_special_tokens(&[added_token]);
+        } else {
+            tokenizer.add_tokens(&[added_token]);
+        }
+    }
+
+    let add_bos = config.tokenizer_config_bool("add_bos_token", true);
+    let add_eos = config.tokenizer_config_bool("add_eos_token", false);
+    if add_bos || add_eos {
+        let bos = model
+            .pieces
+            .get(1)
+            .context("no bos piece in the model")?
+            .piece
+            .clone();
+        let eos = model
+            .pieces
+            .get(2)
+            .context("no eos piece in the model")?
+            .piece
+            .clone();
+        let mut single = Vec::new();
+        let mut pair = Vec::new();
+        if add_bos {
+            single.push(format!("{bos}:0"));
+            pair.push(format!("{bos}:0"));
+        }
+        single.push("$A:0".to_string());
+        pair.push("$A:0".to_string());
+        if add_eos {
+            single.push(format!("{eos}:0"));
+            pair.push(format!("{eos}:0"));
+        }
+        if add_bos {
+            pair.push(format!("{bos}:1"));
+        }
+        pair.push("$B:1".to_string());
+        if add_eos {
+            pair.push(format!("{eos}:1"));
+        }
+        let mut special_tokens = Vec::new();
+        if add_bos {
+            special_tokens.push((bos, 1));
+        }
+        if add_eos {
+            special_tokens.push((eos, 2));
+        }
+        let post_processor = TemplateProcessing::builder()
+            .try_single(single.join(" "))
+            .map_err(E::msg)?
+            .try_pair(pair.join(" "))
+            .map_err(E::msg)?
+            .special_tokens(special_tokens)
+            .build()
+            .map_err(E::msg)?;
+        tokenizer.with_post_processor(post_processor);
+    }
+    Ok(tokenizer)
+}
+
+fn read_json(path: Option<PathBuf>) -> Result<Option<Value>> {
+    match path {
+        Some(path) => Ok(Some(serde_json::from_slice(&std::fs::read(path)?)?)),
+        None => Ok(None),
+    }
+}
+
+/// The files besides `tokenizer.model` that the conversion reads, all optional.
+const CONFIG_FILES: [&str; 3] = [
+    "tokenizer_config.json",
+    "special_tokens_map.json",
+    "added_tokens.json",
+];
+
+/// A hash of `tokenizer.model` and of the `CONFIG_FILES`, missing ones included, that names the
+/// converted tokenizer so that editing any of them converts again.
+fn conversion_key(tokenizer_model: &[u8], configs: &[Option<Vec<u8>>]) -> String {
+    let mut hasher = StableHasher::default();
+    hasher.write_field(tokenizer_model);
+    for config in configs {
+        match config {
+            Some(config) => {
+                hasher.write(&[1]);
+                hasher.write_field(config);
+            }
+            None => hasher.write(&[0]),
+        }
+    }
+    hasher.finish_hex()
+}
+
+/// `$XDG_CACHE_HOME`, else `~/.cache`.
+fn cache_dir() -> PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Local checkpoints keep the converted tokenizer next to `tokenizer.model`. Hub snapshots are
+/// never written to, theirs goes to `<cache dir>/candle-llava/<repo>/<revision>/`, the revision
+/// being the name of the snapshot directory.
+fn converted_tokenizer_path(source: &ModelSource, tokenizer_model: &Path, key: &str) -> PathBuf {
+    let filename = format!("tokenizer-{key}.json");
+    match source {
+        ModelSource::Hub(repo_id) => {
+            let revision = tokenizer_model
+                .parent()
+                .and_then(|snapshot| snapshot.file_name())
+                .map(|revision| revision.to_string_lossy().to_string())
+                .unwrap_or_else(|| "main".to_string());
+            cache_dir()
+                .join("candle-llava")
+                .join(repo_id)
+                .join(revision)
+                .join(filename)
+        }
+        _ => tokenizer_model.with_file_name(filename),
+    }
+}
+
+/// Load the tokenizer of a liuhaotian checkpoint, converting `tokenizer.model` on first use.
+/// The conversion is cached as `tokenizer-<key>.json`, see `conversion_key` and
+/// `converted_tokenizer_path`.
+pub fn load_or_convert_tokenizer(source: &ModelSource) -> Result<Tokenizer> {
+    let tokenizer_model = source.get("tokenizer.model")?;
+    let model_bytes = std::fs::read(&tokenizer_model)
+        .with_context(|| format!("cannot read {}", tokenizer_model.display()))?;
+    let configs = CONFIG_FILES
+        .iter()
+        .map(|filename| -> Result<Option<Vec<u8>>> {
+            match source.get_optional(filename)? {
+                Some(path) => Ok(Some(std::fs::read(path)?)),
+                None => Ok(None),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let cached = converted_tokenizer_path(
+        source,
+        &tokenizer_model,
+        &conversion_key(&model_bytes, &configs),
+    );
+    if cached.is_file() {
+        match Tokenizer::from_file(&cached) {
+            Ok(tokenizer) => return Ok(tokenizer),
+            Err(e) => eprintln!("converting again, cannot read {}: {e}", cached.display()),
+        }
+    }
+    eprintln!("converting {} to tokenizer.json", tokenizer_model.display());
+    let model = SentencePieceModel::from_bytes(&model_bytes)?;
+    let json = |config: &Option<Vec<u8>>| -> Result<Option<Value>> {
+        match config {
+            Some(config) => Ok(Some(serde_json::from_slice(config)?)),
+            None => Ok(None),
+        }
+    };
+    let config = SpecialTokensConfig {
+        tokenizer_config: json(&configs[0])?,
+        special_tokens_map: json(&configs[1])?,
+        added_tokens: json(&configs[2])?,
+    };
+    let tokenizer = convert_llama_tokenizer(&model, &config)?;
+    if let Some(dir) = cached.parent() {
+        // a failure shows up when saving
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if let Err(e) = tokenizer.save(&cached, false) {
+        eprintln!("cannot cache tokenizer to {}: {e}", cached.display());
+    }
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // minimal protobuf writer, enough to build a tiny tokenizer.model fixture
+    fn varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn bytes_field(field: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint((field << 3) | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn varint_field(field: u64, v: u64, out: &mut Vec<u8>) {
+        varint(field << 3, out);
+        varint(v, out);
+    }
+
+    fn piece(piece: &str, score: f32, piece_type: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        bytes_field(1, piece.as_bytes(), &mut out);
+        varint((2 << 3) | 5, &mut out);
+        out.extend_from_slice(&score.to_bits().to_le_bytes());
+        varint_field(3, piece_type, &mut out);
+        out
+    }
+
+    /// <unk>, <s>, </s>, 256 byte pieces, then a handful of normal pieces.
+    fn tiny_model() -> Vec<u8> {
+        let mut pieces = vec![
+            piece("<unk>", 0.0, 2),
+            piece("<s>", 0.0, 3),
+            piece("</s>", 0.0, 3),
+        ];
+        for b in 0..=255u8 {
+            pieces.push(piece(&format!("<0x{b:02X}>"), 0.0, 6));
+        }
+        // ids 259..
+        for (i, p) in ["▁", "a", "b", "c", "▁a", "ab", "▁ab", "▁c", "ca"]
+            .iter()
+            .enumerate()
+        {
+            pieces.push(piece(p, -(i as f32), 1));
+        }
+        let mut out = Vec::new();
+        for p in pieces.iter() {
+            bytes_field(1, p, &mut out);
+        }
+        let mut trainer_spec = Vec::new();
+        varint_field(3, 2, &mut trainer_spec);
+        varint_field(35, 1, &mut trainer_spec);
+        bytes_field(2, &trainer_spec, &mut out);
+        out
+    }
+
+    fn tiny_config(legacy: bool) -> SpecialTokensConfig {
+        SpecialTokensConfig {
+            tokenizer_config: Some(serde_json::json!({
+                "add_bos_token": true,
+                "add_eos_token": false,
+                "legacy": legacy,
+                "added_tokens_decoder": {
+                    "0": {"content": "<unk>", "special": true},
+                    "268": {"content": "<im_start>", "special": true, "normalized": false},
+                    "269": {"content": "<im_end>", "special": true, "normalized": false}
+                }
+            })),
+            special_tokens_map: Some(serde_json::json!({
+                "bos_token": {"content": "<s>"},
+                "eos_token": "</s>",
+                "unk_token": "<unk>",
+                "pad_token": "<unk>"
+            })),
+            added_tokens: None,
+        }
+    }
+
+    fn ids(tokenizer: &Tokenizer, text: &str) -> Vec<u32> {
+        tokenizer.encode(text, true).unwrap().get_ids().to_vec()
+    }
+
+    #[test]
+    fn test_parse_model() {
+        let model = SentencePieceModel::from_bytes(&tiny_model()).unwrap();
+        assert_eq!(model.pieces.len(), 268);
+        assert_eq!(model.model_type, 2);
+        assert!(model.byte_fallback);
+        assert!(model.add_dummy_prefix);
+        assert_eq!(model.pieces[1].piece_type, PieceType::Control);
+        assert_eq!(model.pieces[3].piece, "<0x00>");
+        assert_eq!(model.pieces[263].piece, "▁a");
+        assert_eq!(model.pieces[263].score, -4.0);
+    }
+
+    #[test]
+    fn test_convert_legacy() {
+        let model = SentencePieceModel::from_bytes(&tiny_model()).unwrap();
+        let tokenizer = convert_llama_tokenizer(&model, &tiny_config(true)).unwrap();
+        assert_eq!(tokenizer.get_vocab_size(true), 270);
+        // <s> ▁ab ▁c
+        assert_eq!(ids(&tokenizer, "ab c"), vec![1, 265, 266]);
+        // <s> ▁a c ab, "ab" is merged before "ca"
+        assert_eq!(ids(&tokenizer, "acab"), vec![1, 263, 262, 264]);
+        // é is not in the vocab, it falls back to its utf-8 bytes 0xC3 0xA9
+        assert_eq!(ids(&tokenizer, "é"), vec![1, 259, 3 + 0xC3, 3 + 0xA9]);
+        // added tokens are never split, and legacy mode prepends ▁ after them
+        assert_eq!(
+            ids(&tokenizer, "<im_start>a<im_end>"),
+            vec![1, 268, 263, 269]
+        );
+        assert_eq!(
+            tokenizer
+                .decode(&[1, 265, 266, 3 + 0xC3, 3 + 0xA9], true)
+                .unwrap(),
+            "ab cé"
+        );
+    }
+
+    #[test]
+    fn test_convert_user_defined_pieces() {
+        let mut data = tiny_model();
+        // id 268
+        bytes_field(1, &piece("<sep>", 0.0, 4), &mut data);
+        let model = SentencePieceModel::from_bytes(&data).unwrap();
+        let tokenizer = convert_llama_tokenizer(&model, &SpecialTokensConfig::default()).unwrap();
+        // <s> ▁a <sep> ▁ b
+        assert_eq!(ids(&tokenizer, "a<sep>b"), vec![1, 263, 268, 259, 261]);
+        assert_eq!(tokenizer.decode(&[263, 268], true).unwrap(), "a<sep>");
+    }
+
+    #[test]
+    fn test_convert_missing_pieces() {
+        let mut data = Vec::new();
+        bytes_field(1, &piece("<unk>", 0.0, 2), &mut data);
+        let mut trainer_spec = Vec::new();
+        varint_field(3, 2, &mut trainer_spec);
+        varint_field(40, 5, &mut trainer_spec);
+        bytes_field(2, &trainer_spec, &mut data);
+        let model = SentencePieceModel::from_bytes(&data).unwrap();
+        // an out of range unk id or no <s> is an error, not a panic
+        assert!(convert_llama_tokenizer(&model, &tiny_config(true)).is_err());
+        let model = SentencePieceModel { unk_id: 0, ..model };
+        assert!(convert_llama_tokenizer(&model, &tiny_config(true)).is_err());
+    }
+
+    #[test]
+    fn test_local_conversion_is_cached() {
+        let dir = std::env::temp_dir().join(format!("candle-llava-{}-spm", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tokenizer.model"), tiny_model()).unwrap();
+        let cached_files = || {
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .filter(|name| name.starts_with("tokenizer-") && name.ends_with(".json"))
+                .count()
+        };
+        let source = ModelSource::Local(dir.clone());
+        let converted = load_or_convert_tokenizer(&source).unwrap();
+        assert_eq!(cached_files(), 1);
+        let cached = load_or_convert_tokenizer(&source).unwrap();
+        assert_eq!(cached_files(), 1);
+        // an edited config is converted again
+        std::fs::write(
+            dir.join("tokenizer_config.json"),
+            r#"{"add_bos_token": false}"#,
+        )
+        .unwrap();
+        let edited = load_or_convert_tokenizer(&source).unwrap();
+        let num_cached = cached_files();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(num_cached, 2);
+        assert_eq!(ids(&cached, "ab c"), ids(&converted, "ab c"));
+        assert_eq!(ids(&edited, "ab c"), vec![265, 266]);
+    }
+
+    #[test]
+    fn test_converted_tokenizer_path() {
+        let source = ModelSource::Hub("liuhaotian/llava-v1.6-vicuna-7b".to_string());
+        let snapshot =
+            Path::new("/hf/hub/models--liuhaotian--llava-v1.6-vicuna-7b/snapshots/0123abcd");
+        let path = converted_tokenizer_path(&source, &snapshot.join("tokenizer.model"), "key");
+        assert!(
+            path.ends_with(
+                "candle-llava/liuhaotian/llava-v1.6-vicuna-7b/0123abcd/tokenizer-key.json"
+            ),
+            "{path:?}"
+        );
+        assert!(!path.starts_with(snapshot));
+        let source = ModelSource::Local(PathBuf::from("/models/llava"));
+        assert_eq!(
+            converted_tokenizer_path(&source, Path::new("/models/llava/tokenizer.model"), "key"),
+            PathBuf::from("/models/llava/tokenizer-key.json")
+        );
+    }
+
+    /// Converts the `tokenizer.model` of `dir` with its configs and checks the ids and the
+    /// decoded text of `texts` against the `tokenizer.json` next to it.
+    fn assert_matches_reference(dir: &Path, texts: &[&str]) -> Tokenizer {
+        let json = |name: &str| {
+            let path = dir.join(name);
+            read_json(path.is_file().then_some(path)).unwrap()
+        };
+        let config = SpecialTokensConfig {
+            tokenizer_config: json("tokenizer_config.json"),
+            special_tokens_map: json("special_tokens_map.json"),
+            added_tokens: json("added_tokens.json"),
+        };
+        let model = SentencePieceModel::from_file(dir.join("tokenizer.model")).unwrap();
+        let mut converted = convert_llama_tokenizer(&model, &config).unwrap();
+        let mut reference = Tokenizer::from_file(dir.join("tokenizer.json")).unwrap();
+        // added by LLaVA when mm_use_im_start_end is set
+        for tokenizer in [&mut converted, &mut reference] {
+            tokenizer.add_special_tokens(&[
+                AddedToken::from("<im_start>", true),
+                AddedToken::from("<im_end>", true),
+            ]);
+        }
+        for text in texts {
+            let expected = ids(&reference, text);
+            assert_eq!(ids(&converted, text), expected, "{text:?}");
+            assert_eq!(
+                converted.decode(&expected, false).unwrap(),
+                reference.decode(&expected, false).unwrap()
+            );
+        }
+        converted
+    }
+
+    // tests/fixtures/spm holds a small BPE model with byte fallback and the configs of a llava
+    // v1.6 checkpoint, with its tokenizer.json and the ids of a few strings. regenerate.py writes
+    // both with transformers' LlamaTokenizerFast.
+    #[test]
+    fn test_convert_matches_fixture() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/spm");
+        let expected = read_json(Some(dir.join("expected_ids.json")))
+            .unwrap()
+            .unwrap();
+        let expected = expected.as_object().unwrap();
+        let texts = expected
+            .keys()
+            .map(|text| text.as_str())
+            .collect::<Vec<_>>();
+        assert!(texts.len() > 5);
+        let converted = assert_matches_reference(&dir, &texts);
+        for (text, expected_ids) in expected {
+            let expected_ids = serde_json::from_value::<Vec<u32>>(expected_ids.clone()).unwrap();
+            assert_eq!(ids(&converted, text), expected_ids, "{text:?}");
+        }
+    }
+
+    // The same against a real checkpoint, e.g. a local copy of llava-hf/llava-1.5-7b-hf, which
+    // ships both files.
+    #[test]
+    #[ignore = "needs CANDLE_LLAVA_SPM_DIR with tokenizer.model and tokenizer.json"]
+    fn test_convert_matches_transformers() {
+        let dir = PathBuf::from(std::env::var("CANDLE_LLAVA_SPM_DIR").unwrap());
+        assert_matches_reference(
+            &dir,
+            &[
+                "Hello world",
+                " leading space",
+                "   several leading spaces",
+                "é, 中文 and 🦙 fall back to bytes",
+                "USER: <im_start><image><im_end>\nWhat is shown? ASSISTANT:",
+                "<im_start> a<im_end>b",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_convert_non_legacy() {
+        let model = SentencePieceModel::from_bytes(&tiny_model()).unwrap();
+        let tokenizer = convert_llama_tokenizer(&model, &tiny_config(false)).unwrap();
+        assert_eq!(ids(&tokenizer, "ab c"), vec![1, 265, 266]);
+        // the dummy prefix is only added to the very first split
+        assert_eq!(
+            ids(&tokenizer, "<im_start>a<im_end>"),
+            vec![1, 268, 260, 269]
+        );
+    }
+}
@@ -0,0 +1,659 @@
+/*
+An OpenAI compatible chat completion server, see https://platform.openai.com/docs/api-reference/chat
+Requests are answered one at a time by the same LlavaPipeline.
+*/
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::Engine;
+use image::DynamicImage;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+
+use crate::constants::IMAGE_PLACEHOLDER;
+use crate::conversation::Conversation;
+use crate::grammar::Grammar;
+use crate::logits_processor::LogitBias;
+use crate::pipeline::{
+    insert_image_tokens, GenerationOptions, GenerationOutput, LlavaPipeline, TokenLogprob,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct ChatCompletionRequest {
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// Token ids, as strings, to a bias added to their logits.
+    pub logit_bias: Option<HashMap<String, f32>>,
+    // not in the OpenAI api, the names follow vLLM
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub repetition_penalty: Option<f32>,
+    pub guided_regex: Option<String>,
+    pub guided_grammar: Option<String>,
+    pub response_format: Option<ResponseFormat>,
+    pub max_tokens: Option<usize>,
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub logprobs: bool,
+    /// The number of alternatives given with each token, needs `logprobs`.
+    pub top_logprobs: Option<usize>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchema },
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JsonSchema {
+    pub schema: Value,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: MessageContent,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ImageUrl {
+    Object { url: String },
+    Url(String),
+}
+
+impl ImageUrl {
+    fn url(&self) -> &str {
+        match self {
+            ImageUrl::Object { url } | ImageUrl::Url(url) => url,
+        }
+    }
+}
+
+struct ServerState {
+    pipeline: Mutex<LlavaPipeline>,
+    model_id: String,
+    options: GenerationOptions,
+}
+
+/// `data:image/...;base64,` uris and local paths, with or without `file://`.
+pub fn load_image_url(url: &str) -> Result<DynamicImage> {
+    if let Some(data) = url.strip_prefix("data:") {
+        let (_, payload) = data
+            .split_once(";base64,")
+            .context("only base64 data uris are supported")?;
+        let bytes = base64::engine::general_purpose::STANDARD.decode(payload)?;
+        Ok(image::load_from_memory(&bytes)?)
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        bail!("remote images are not supported, send a data uri or a local path")
+    } else {
+        let path = url.strip_prefix("file://").unwrap_or(url);
+        image::open(path).with_context(|| format!("cannot open image {path}"))
+    }
+}
+
+/// The conversation to answer and its images in the order of their image tokens.
+/// An image part becomes an `<image-placeholder>` line at its place in the message.
+pub fn build_conversation(
+    pipeline: &LlavaPipeline,
+    messages: &[ChatMessage],
+) -> Result<(Conversation, Vec<DynamicImage>)> {
+    let mut conv = pipeline.conversation()?;
+    let mut images = Vec::new();
+    for message in messages {
+        let (text, message_images) = match &message.content {
+            MessageContent::Text(text) => (text.clone(), Vec::new()),
+            MessageContent::Parts(parts) => {
+                let mut lines = Vec::new();
+                let mut message_images = Vec::new();
+                for part in parts {
+                    match part {
+                        ContentPart::Text { text } => lines.push(text.clone()),
+                        ContentPart::ImageUrl { image_url } => {
+                            message_images.push(load_image_url(image_url.url())?);
+                            lines.push(IMAGE_PLACEHOLDER.to_string());
+                        }
+                    }
+                }
+                (lines.join("\n"), message_images)
+            }
+        };
+        match message.role.as_str() {
+            "system" if message_images.is_empty() => conv.system = text,
+            "user" => {
+                let text = insert_image_tokens(
+                    &text,
+                    message_images.len(),
+                    pipeline.llava_config.mm_use_im_start_end,
+                )?;
+                conv.append_user_message(Some(&text));
+                images.extend(message_images);
+            }
+            "assistant" if message_images.is_empty() => conv.append_assistant_message(Some(&text)),
+            "system" | "assistant" => bail!("only user messages can have images"),
+            role => bail!("unsupported role {role}"),
+        }
+    }
+    match conv.messages.last() {
+        Some((role, _)) if *role == conv.roles[0] => {}
+        _ => bail!("the last message has to come from the user"),
+    }
+    conv.append_assistant_message(None);
+    Ok((conv, images))
+}
+
+fn error_response(status: StatusCode, error: anyhow::Error) -> Response {
+    let error_type = if status.is_client_error() {
+        "invalid_request_error"
+    } else {
+        "server_error"
+    };
+    let body = json!({"error": {"message": format!("{error:#}"), "type": error_type}});
+    (status, Json(body)).into_response()
+}
+
+/// The grammar the answer has to match, at most one of the ways to give it.
+fn request_grammar(request: &ChatCompletionRequest) -> Result<Option<Grammar>> {
+    let mut grammars = Vec::new();
+    if let Some(regex) = &request.guided_regex {
+        grammars.push(Grammar::from_regex(regex)?);
+    }
+    if let Some(grammar) = &request.guided_grammar {
+        grammars.push(Grammar::parse(grammar)?);
+    }
+    match &request.response_format {
+        Some(ResponseFormat::JsonObject) => {
+            grammars.push(Grammar::from_json_schema(&json!({"type": "object"}))?)
+        }
+        Some(ResponseFormat::JsonSchema { json_schema }) => {
+            grammars.push(Grammar::from_json_schema(&json_schema.schema)?)
+        }
+        Some(ResponseFormat::Text) | None => {}
+    }
+    if grammars.len() > 1 {
+        bail!("guided_regex, guided_grammar and response_format cannot be combined")
+    }
+    Ok(grammars.pop())
+}
+
+fn parse_logit_bias(logit_bias: &HashMap<String, f32>) -> Result<LogitBias> {
+    let mut biases = HashMap::new();
+    for (token, bias) in logit_bias {
+        let token = token
+            .parse()
+            .with_context(|| format!("logit_bias keys are token ids, got {token}"))?;
+        biases.insert(token, *bias);
+    }
+    Ok(LogitBias(biases))
+}
+
+fn logprobs_json(logprobs: &[TokenLogprob]) -> Value {
+    let content: Vec<Value> = logprobs
+        .iter()
+        .map(|logprob| {
+            let top_logprobs: Vec<Value> = logprob
+                .top_logprobs
+                .iter()
+                .map(|top| {
+                    json!({"token": top.text, "logprob": top.logprob, "bytes": top.text.as_bytes()})
+                })
+                .collect();
+            json!({
+                "token": logprob.text,
+                "logprob": logprob.logprob,
+                "bytes": logprob.text.as_bytes(),
+                "top_logprobs": top_logprobs,
+            })
+        })
+        .collect();
+    json!({ "content": content })
+}
+
+fn finish_reason(
+    output: &GenerationOutput,
+    options: &GenerationOptions,
+    max_seq_len: usize,
+) -> &'static str {
+    // the eos token is not part of `tokens`, the last token of a full context is
+    if output.tokens.len() >= options.max_new_tokens
+        || output.prompt_len + output.tokens.len() > max_seq_len
+    {
+        "length"
+    } else {
+        "stop"
+    }
+}
+
+async fn models(State(state): State<Arc<ServerState>>) -> Json<Value> {
+    Json(json!({
+        "object": "list",
+        "data": [{"id": state.model_id, "object": "model", "created": 0, "owned_by": "candle-llava"}],
+    }))
+}
+
+async fn chat_completions(
+    State(state): State<Arc<ServerState>>,
+    request: Result<Json<ChatCompletionRequest>, JsonRejection>,
+) -> Response {
+    let Json(request) = match request {
+        Ok(request) => request,
+        Err(rejection) => {
+            return error_response(StatusCode::BAD_REQUEST, anyhow!(rejection.body_text()))
+        }
+    };
+    let defaults = &state.options;
+    let mut logits_processors = defaults.logits_processors.clone();
+    if let Some(logit_bias) = &request.logit_bias {
+        match parse_logit_bias(logit_bias) {
+            Ok(logit_bias) => logits_processors.push(Arc::new(logit_bias)),
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+        }
+    }
+    let grammar = match request_grammar(&request) {
+        Ok(grammar) => grammar,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    let logprobs = match (request.logprobs, request.top_logprobs) {
+        (true, top_logprobs) => Some(top_logprobs.unwrap_or(0)),
+        (false, None) => None,
+        (false, Some(_)) => {
+            let e = anyhow!("top_logprobs needs logprobs to be true");
+            return error_response(StatusCode::BAD_REQUEST, e);
+        }
+    };
+    let mut options = GenerationOptions {
+        temperature: request.temperature.unwrap_or(defaults.temperature),
+        top_k: request.top_k.or(defaults.top_k),
+        top_p: request.top_p.or(defaults.top_p),
+        min_p: request.min_p.or(defaults.min_p),
+        repeat_penalty: request
+            .repetition_penalty
+            .unwrap_or(defaults.repeat_penalty),
+        repeat_last_n: defaults.repeat_last_n,
+        presence_penalty: request
+            .presence_penalty
+            .unwrap_or(defaults.presence_penalty),
+        frequency_penalty: request
+            .frequency_penalty
+            .unwrap_or(defaults.frequency_penalty),
+        logits_processors,
+        max_new_tokens: request.max_tokens.unwrap_or(defaults.max_new_tokens),
+        seed: request.seed.unwrap_or(defaults.seed),
+        logprobs,
+    };
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let id = format!("chatcmpl-{}", created.as_nanos());
+    let created = created.as_secs();
+
+    let messages = request.messages;
+    let prepare_state = state.clone();
+    let prepared = tokio::task::spawn_blocking(move || {
+        let pipeline = prepare_state
+            .pipeline
+            .lock()
+            .map_err(|_| anyhow!("the pipeline is poisoned"))?;
+        let constraint = match grammar {
+            Some(grammar) => Some(pipeline.grammar_constraint(grammar)?),
+            None => None,
+        };
+        let (conv, images) = build_conversation(&pipeline, &messages)?;
+        Ok::<_, anyhow::Error>((conv, images, constraint))
+    })
+    .await;
+    let (conv, images, constraint) = match prepared {
+        Ok(Ok(prepared)) => prepared,
+        Ok(Err(e)) => return error_response(StatusCode::BAD_REQUEST, e),
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e.into()),
+    };
+    if let Some(constraint) = constraint {
+        options.logits_processors.push(constraint);
+    }
+
+    if request.stream {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        tokio::task::spawn_blocking(move || {
+            let send = |delta: Value, logprobs: Value, finish_reason: Option<&str>| {
+                let choice = json!({
+                    "index": 0,
+                    "delta": delta,
+                    "logprobs": logprobs,
+                    "finish_reason": finish_reason,
+                });
+                let chunk = json!({
+                    "id": id,
+                    "object": "chat.completion.chunk",
+                    "created": created,
+                    "model": state.model_id,
+                    "choices": [choice],
+                });
+                tx.send(Event::default().data(chunk.to_string()))
+                    .map_err(|_| anyhow!("the client is gone"))
+            };
+            let result = (|| {
+                let mut pipeline = state
+                    .pipeline
+                    .lock()
+                    .map_err(|_| anyhow!("the pipeline is poisoned"))?;
+                send(
+                    json!({"role": "assistant", "content": ""}),
+                    Value::Null,
+                    None,
+                )?;
+                let output = pipeline.generate_conversation(&conv, &images, &options, |t| {
+                    send(json!({"content": t}), Value::Null, None)
+                })?;
+                // the text is streamed as it is decoded, the logprobs of all tokens come last
+                let logprobs = match options.logprobs {
+                    Some(_) => logprobs_json(&output.logprobs),
+                    None => Value::Null,
+                };
+                let max_seq_len = pipeline.llava_config.max_seq_len();
+                let finish_reason = finish_reason(&output, &options, max_seq_len);
+                send(json!({}), logprobs, Some(finish_reason))
+            })();
+            if let Err(e) = result {
+                let error = json!({"error": {"message": format!("{e:#}"), "type": "server_error"}});
+                let _ = tx.send(Event::default().data(error.to_string()));
+            }
+            let _ = tx.send(Event::default().data("[DONE]"));
+        });
+        let stream = UnboundedReceiverStream::new(rx).map(Ok::<_, Infallible>);
+        return Sse::new(stream).into_response();
+    }
+
+    let generation_state = state.clone();
+    let generated = tokio::task::spawn_blocking(move || {
+        let mut pipeline = generation_state
+            .pipeline
+            .lock()
+            .map_err(|_| anyhow!("the pipeline is poisoned"))?;
+        let output = pipeline.generate_conversation(&conv, &images, &options, |_| Ok(()))?;
+        let max_seq_len = pipeline.llava_config.max_seq_len();
+        Ok::<_, anyhow::Error>((output, options, max_seq_len))
+    })
+    .await;
+    let (output, options, max_seq_len) = match generated {
+        Ok(Ok(generated)) => generated,
+        Ok(Err(e)) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e.into()),
+    };
+    Json(json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": state.model_id,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": output.text.trim()},
+            "logprobs": options.logprobs.map(|_| logprobs_json(&output.logprobs)),
+            "finish_reason": finish_reason(&output, &options, max_seq_len),
+        }],
+        "usage": {
+            "prompt_tokens": output.prompt_len,
+            "completion_tokens": output.tokens.len(),
+            "total_tokens": output.prompt_len + output.tokens.len(),
+            "prompt_tokens_details": {"cached_tokens": output.cached_len},
+        },
+    }))
+    .into_response()
+}
+
+/// `options` are the defaults for the fields a request leaves out.
+pub fn router(pipeline: LlavaPipeline, model_id: &str, options: GenerationOptions) -> Router {
+    let state = Arc::new(ServerState {
+        pipeline: Mutex::new(pipeline),
+        model_id: model_id.to_string(),
+        options,
+    });
+    Router::new()
+        .route("/v1/models", get(models))
+        .route("/v1/chat/completions", post(chat_completions))
+        .with_state(state)
+}
+
+pub async fn serve(
+    pipeline: LlavaPipeline,
+    model_id: &str,
+    options: GenerationOptions,
+    addr: &str,
+) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    eprintln!("serving {model_id} on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(pipeline, model_id, options)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::tests::{tiny_image, tiny_pipeline};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    fn start_server(runtime: &tokio::runtime::Runtime) -> SocketAddr {
+        let options = GenerationOptions {
+            temperature: 0.,
+            max_new_tokens: 6,
+            ..Default::default()
+        };
+        let app = router(tiny_pipeline(true), "tiny-llava", options);
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        runtime.spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    /// A bare HTTP/1.1 client, returns the status and the de-chunked body.
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, mut body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        if !head.to_lowercase().contains("transfer-encoding: chunked") {
+            return (status, body.to_string());
+        }
+        let mut dechunked = String::new();
+        while let Some((size, rest)) = body.split_once("\r\n") {
+            let size = usize::from_str_radix(size, 16).unwrap();
+            dechunked.push_str(&rest[..size]);
+            body = &rest[size + 2..];
+        }
+        (status, dechunked)
+    }
+
+    fn image_data_uri() -> String {
+        let mut png = std::io::Cursor::new(Vec::new());
+        tiny_image()
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let payload = base64::engine::general_purpose::STANDARD.encode(png.into_inner());
+        format!("data:image/png;base64,{payload}")
+    }
+
+    fn chat_request(stream: bool) -> String {
+        json!({
+            "model": "tiny-llava",
+            "stream": stream,
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "image_url", "image_url": {"url": image_data_uri()}},
+                    {"type": "text", "text": "what color is the cat?"},
+                ]},
+            ],
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_models() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let addr = start_server(&runtime);
+        let (status, body) = request(addr, "GET", "/v1/models", "");
+        assert_eq!(status, 200);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["data"][0]["id"], "tiny-llava");
+    }
+
+    #[test]
+    fn test_chat_completions() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let addr = start_server(&runtime);
+        let (status, body) = request(addr, "POST", "/v1/chat/completions", &chat_request(false));
+        assert_eq!(status, 200, "{body}");
+        let completion: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(completion["object"], "chat.completion");
+        assert_eq!(completion["choices"][0]["message"]["role"], "assistant");
+        let content = completion["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let (status, body) = request(addr, "POST", "/v1/chat/completions", &chat_request(true));
+        assert_eq!(status, 200);
+        let events = body
+            .split("\n\n")
+            .filter_map(|event| event.strip_prefix("data: "))
+            .collect::<Vec<_>>();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+        let mut streamed = String::new();
+        let mut finish_reason = Value::Null;
+        for event in &events[..events.len() - 1] {
+            let chunk: Value = serde_json::from_str(event).unwrap();
+            assert_eq!(chunk["object"], "chat.completion.chunk");
+            let choice = &chunk["choices"][0];
+            if let Some(t) = choice["delta"]["content"].as_str() {
+                streamed.push_str(t);
+            }
+            if !choice["finish_reason"].is_null() {
+                finish_reason = choice["finish_reason"].clone();
+            }
+        }
+        assert_eq!(streamed.trim(), content);
+        assert_eq!(finish_reason, completion["choices"][0]["finish_reason"]);
+    }
+
+    #[test]
+    fn test_chat_completions_with_logprobs() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let addr = start_server(&runtime);
+        let with_logprobs = |stream| {
+            let mut body: Value = serde_json::from_str(&chat_request(stream)).unwrap();
+            body["logprobs"] = json!(true);
+            body["top_logprobs"] = json!(2);
+            body.to_string()
+        };
+        let (status, body) = request(addr, "POST", "/v1/chat/completions", &with_logprobs(false));
+        assert_eq!(status, 200, "{body}");
+        let completion: Value = serde_json::from_str(&body).unwrap();
+        let content = completion["choices"][0]["logprobs"]["content"].clone();
+        let content = content.as_array().unwrap();
+        assert_eq!(content.len(), completion["usage"]["completion_tokens"]);
+        for logprob in content {
+            assert!(logprob["logprob"].as_f64().unwrap() <= 0.);
+            assert_eq!(logprob["top_logprobs"].as_array().unwrap().len(), 2);
+        }
+
+        let (status, body) = request(addr, "POST", "/v1/chat/completions", &with_logprobs(true));
+        assert_eq!(status, 200);
+        let last_chunk = body
+            .split("\n\n")
+            .filter_map(|event| event.strip_prefix("data: "))
+            .filter_map(|event| serde_json::from_str::<Value>(event).ok())
+            .last()
+            .unwrap();
+        assert_eq!(
+            last_chunk["choices"][0]["logprobs"]["content"],
+            Value::Array(content.clone())
+        );
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let addr = start_server(&runtime);
+        let too_many_placeholders = json!({"messages": [
+            {"role": "user", "content": "<image> and <image>"},
+        ]});
+        let remote_image = json!({"messages": [
+            {"role": "user", "content": [{"type": "image_url", "image_url": "https://example.com/cat.png"}]},
+        ]});
+        let last_from_assistant = json!({"messages": [
+            {"role": "user", "content": "hi"},
+            {"role": "assistant", "content": "hello"},
+        ]});
+        let two_constraints = json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "guided_regex": "a+",
+            "response_format": {"type": "json_object"},
+        });
+        let bad_regex = json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "guided_regex": "(a",
+        });
+        let top_logprobs_alone = json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "top_logprobs": 2,
+        });
+        for body in [
+            too_many_placeholders,
+            remote_image,
+            last_from_assistant,
+            two_constraints,
+            bad_regex,
+            top_logprobs_alone,
+        ] {
+            let (status, body) = request(addr, "POST", "/v1/chat/completions", &body.to_string());
+            assert_eq!(status, 400);
+            let body: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["type"], "invalid_request_error");
+        }
+        let (status, _) = request(addr, "POST", "/v1/chat/completions", "{");
+        assert_eq!(status, 400);
+    }
+}
@@ -188,6 +188,67 @@ pub fn get_model_name_from_path(model_path: &str) -> String {
     }
 }
 
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is the same on every platform and rust
+/// version, so it can name files and be stored in them.
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl StableHasher {
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Writes the length first, so that consecutive fields cannot run into each other.
+    pub fn write_field(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+
+    /// The hash as 16 hex digits.
+    pub fn finish_hex(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+/// How many bytes of each tensor go into `weights_fingerprint`.
+pub const FINGERPRINT_SAMPLE_BYTES: usize = 64;
+
+/// What `weights_fingerprint` reads of a tensor of the checkpoint.
+pub struct TensorSample {
+    pub name: String,
+    pub shape: Vec<usize>,
+    /// As stored in the checkpoint, e.g. "BF16" or "Q4K".
+    pub dtype: String,
+    /// The first `FINGERPRINT_SAMPLE_BYTES` bytes of the stored data, or all of it when shorter.
+    pub data: Vec<u8>,
+}
+
+/// A hash of the names, shapes and dtypes of all the tensors of a checkpoint and of the first
+/// bytes of each. It tells two fine-tunes of the same architecture apart without reading their
+/// weights.
+pub fn weights_fingerprint(mut tensors: Vec<TensorSample>) -> String {
+    tensors.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut hasher = StableHasher::default();
+    for tensor in tensors.iter() {
+        hasher.write_field(tensor.name.as_bytes());
+        hasher.write(&(tensor.shape.len() as u64).to_le_bytes());
+        for dim in tensor.shape.iter() {
+            hasher.write(&(*dim as u64).to_le_bytes());
+        }
+        hasher.write_field(tensor.dtype.as_bytes());
+        hasher.write_field(&tensor.data[..tensor.data.len().min(FINGERPRINT_SAMPLE_BYTES)]);
+    }
+    hasher.finish_hex()
+}
+
 fn duplicate_vec<T>(vec: &[T], n: usize) -> Vec<T>
 where
     T: Clone,
@@ -257,10 +318,30 @@ pub fn tokenizer_image_token(
 
 #[cfg(test)]
 mod tests {
-    use crate::{clip_image_processor::CLIPImageProcessor, utils::process_anyres_image};
+    use crate::{
+        clip_image_processor::CLIPImageProcessor,
+        utils::{process_anyres_image, StableHasher},
+    };
 
     const CLIP_ID: &str = "openai/clip-vit-large-patch14-336";
 
+    #[test]
+    fn test_stable_hasher() {
+        let hash = |fields: &[&str]| {
+            let mut hasher = StableHasher::default();
+            for field in fields {
+                hasher.write_field(field.as_bytes());
+            }
+            hasher.finish_hex()
+        };
+        let mut hasher = StableHasher::default();
+        hasher.write(b"a");
+        // the FNV-1a test vector
+        assert_eq!(hasher.finish_hex(), "af63dc4c8601ec8c");
+        assert_eq!(hash(&["ab", "c"]), hash(&["ab", "c"]));
+        assert_ne!(hash(&["ab", "c"]), hash(&["a", "bc"]));
+    }
+
     #[test]
     fn test_process_anyres_image() {
         let image = image::open("images/llava_logo.png").unwrap();
@@ -0,0 +1,121 @@
+/*
+Runs the `batch` command of the binary on a tiny random checkpoint bundled as gguf, stdout has to
+be one json answer per line and nothing else.
+*/
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use candle_core::quantized::GgmlDType;
+use candle_core::{DType, Device};
+use candle_llava::bundle::{quantize_tensor, write_bundle, QuantizeOptions};
+use candle_llava::clip_image_processor::CLIPImageProcessor;
+use candle_llava::config::LLaVAConfig;
+use candle_llava::model::LLaVA;
+use candle_llava::pipeline::ModelConfigs;
+use candle_llava::quantized::Quantization;
+use candle_nn::{VarBuilder, VarMap};
+use common::{
+    tiny_clip_vision_config, tiny_image, tiny_image_processor, tiny_llava_config, tiny_tokenizer,
+};
+
+/// A llava-v1 shaped checkpoint with random weights, a 28x28 clip and a 2 layer llama.
+fn write_tiny_bundle(path: &Path) {
+    let llava_config: LLaVAConfig = serde_json::from_value(tiny_llava_config()).unwrap();
+    let clip_vision_config = tiny_clip_vision_config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    LLaVA::load(
+        vb,
+        &llava_config,
+        Some(clip_vision_config.clone()),
+        &Quantization::default(),
+    )
+    .unwrap();
+    let options = QuantizeOptions {
+        dtype: GgmlDType::F32,
+        vision_dtype: GgmlDType::F32,
+    };
+    let tensors = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| (name.clone(), quantize_tensor(name, var, &options).unwrap()))
+        .collect::<Vec<_>>();
+    let configs = ModelConfigs {
+        name: "tiny-llava-v1.6".to_string(),
+        llava_config,
+        clip_vision_config: Some(clip_vision_config),
+        tokenizer: tiny_tokenizer(),
+        image_processor: serde_json::from_value::<CLIPImageProcessor>(tiny_image_processor())
+            .unwrap(),
+        generation_config: None,
+    };
+    write_bundle(path, &configs, &tensors).unwrap();
+}
+
+fn tmp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("candle-llava-batch-{}-{name}", std::process::id()))
+}
+
+#[test]
+fn test_batch_prints_json_lines() {
+    let bundle = tmp_path("model.gguf");
+    write_tiny_bundle(&bundle);
+    let image = tmp_path("cat.png");
+    tiny_image().save(&image).unwrap();
+    let requests = [
+        serde_json::json!({"prompt": "is this a cat?"}),
+        serde_json::json!({"prompt": "what color is this cat?"}),
+        // two placeholders for one image, answered with an error
+        serde_json::json!({"prompt": "<image> and <image>"}),
+        serde_json::json!({"prompt": "is this a dog?", "images": []}),
+    ];
+    let file = tmp_path("requests.jsonl");
+    let lines = requests.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+    std::fs::write(&file, lines.join("\n")).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_candle-llava"))
+        .arg("--model-path")
+        .arg(&bundle)
+        .arg("--image-file")
+        .arg(&image)
+        .args([
+            "--device",
+            "cpu",
+            "--temperature",
+            "0",
+            "--max-new-tokens",
+            "4",
+        ])
+        .arg("batch")
+        .arg(&file)
+        .output()
+        .unwrap();
+    for path in [&bundle, &image, &file] {
+        std::fs::remove_file(path).unwrap();
+    }
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let answers = stdout
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<serde_json::Value>(line)
+                .unwrap_or_else(|e| panic!("{line:?} is not json: {e}"))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(answers.len(), requests.len());
+    for (answer, request) in answers.iter().zip(&requests) {
+        assert_eq!(answer["prompt"], request["prompt"]);
+    }
+    assert!(answers[0]["text"].is_string());
+    assert!(answers[2]["error"].is_string());
+    // the system prompt and the image are shared with the first prompt
+    assert!(answers[1]["cached_tokens"].as_u64().unwrap() > 0);
+}
@@ -0,0 +1,107 @@
+/*
+The tiny llava-v1 shaped model shared by the unit tests and the integration tests: a word level
+tokenizer, a 28x28 clip (4 patches) and a 2 layer llama. The unit tests include this file from
+lib.rs, so it only uses the dependencies and not the crate itself.
+*/
+use std::collections::HashMap;
+
+use candle_transformers::models::clip::text_model::Activation;
+use candle_transformers::models::clip::vision_model::ClipVisionConfig;
+use image::DynamicImage;
+use serde_json::Value;
+use tokenizers::models::wordlevel::WordLevel;
+use tokenizers::pre_tokenizers::whitespace::Whitespace;
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::Tokenizer;
+
+pub const TINY_VOCAB_SIZE: usize = 32;
+
+pub fn tiny_tokenizer() -> Tokenizer {
+    let mut vocab: HashMap<String, u32> = ["<unk>", "<s>", "</s>"]
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.to_string(), i as u32))
+        .collect();
+    let words = "USER ASSISTANT : . , ? is this a cat dog what color the picture show";
+    for word in words.split(' ') {
+        vocab.insert(word.to_string(), vocab.len() as u32);
+    }
+    for i in vocab.len()..TINY_VOCAB_SIZE {
+        vocab.insert(format!("w{i}"), i as u32);
+    }
+    let model = WordLevel::builder()
+        .vocab(vocab)
+        .unk_token("<unk>".to_string())
+        .build()
+        .unwrap();
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Whitespace {});
+    tokenizer.with_post_processor(
+        TemplateProcessing::builder()
+            .try_single("<s> $A")
+            .unwrap()
+            .special_tokens(vec![("<s>", 1)])
+            .build()
+            .unwrap(),
+    );
+    tokenizer
+}
+
+/// The `config.json` of the original checkpoints, for `LLaVAConfig`.
+pub fn tiny_llava_config() -> Value {
+    serde_json::json!({
+        "_name_or_path": "tiny-llava-v1.6",
+        "architectures": ["LlavaLlamaForCausalLM"],
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+        "hidden_size": 32,
+        "image_aspect_ratio": "square",
+        "image_crop_resolution": 28,
+        "image_grid_pinpoints": [[28, 28]],
+        "image_split_resolution": 28,
+        "intermediate_size": 64,
+        "max_position_embeddings": 4096,
+        "mm_hidden_size": 16,
+        "mm_projector_type": "mlp2x_gelu",
+        "mm_use_im_start_end": false,
+        "mm_vision_select_feature": "patch",
+        "mm_vision_select_layer": -2,
+        "mm_vision_tower": "tiny-clip",
+        "model_type": "llava_llama",
+        "num_attention_heads": 4,
+        "num_hidden_layers": 2,
+        "num_key_value_heads": 2,
+        "pad_token_id": 0,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "tokenizer_model_max_length": 4096,
+        "torch_dtype": "float32",
+        "use_cache": true,
+        "vocab_size": TINY_VOCAB_SIZE,
+    })
+}
+
+/// The `preprocessor_config.json`, for `CLIPImageProcessor`.
+pub fn tiny_image_processor() -> Value {
+    serde_json::json!({"size": 28, "crop_size": 28})
+}
+
+pub fn tiny_clip_vision_config() -> ClipVisionConfig {
+    ClipVisionConfig {
+        embed_dim: 16,
+        activation: Activation::QuickGelu,
+        intermediate_size: 32,
+        num_hidden_layers: 2,
+        num_attention_heads: 2,
+        projection_dim: 16,
+        num_channels: 3,
+        image_size: 28,
+        patch_size: 14,
+    }
+}
+
+pub fn tiny_image() -> DynamicImage {
+    DynamicImage::ImageRgb8(image::RgbImage::from_fn(40, 30, |x, y| {
+        image::Rgb([(x * 6) as u8, (y * 8) as u8, 128])
+    }))
+}
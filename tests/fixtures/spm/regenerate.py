@@ -0,0 +1,21 @@
+"""Rewrite tokenizer.json and expected_ids.json with transformers, from tokenizer.model and the
+configs of this directory. The texts are the keys of the current expected_ids.json.
+
+    pip install transformers sentencepiece protobuf
+    python tests/fixtures/spm/regenerate.py
+"""
+import json
+from pathlib import Path
+
+from transformers import LlamaTokenizerFast
+
+fixture_dir = Path(__file__).parent
+tokenizer = LlamaTokenizerFast.from_pretrained(fixture_dir, from_slow=True)
+tokenizer.backend_tokenizer.save(str(fixture_dir / "tokenizer.json"))
+
+# added by LLaVA when mm_use_im_start_end is set, as in the test
+tokenizer.add_special_tokens({"additional_special_tokens": ["<im_start>", "<im_end>"]})
+expected_path = fixture_dir / "expected_ids.json"
+texts = list(json.loads(expected_path.read_text()))
+expected = {text: tokenizer(text)["input_ids"] for text in texts}
+expected_path.write_text(json.dumps(expected, ensure_ascii=False, indent=2) + "\n")